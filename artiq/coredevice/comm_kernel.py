@@ -229,7 +229,8 @@ class CommKernel:
 
         finished_cleanly = self._read_bool()
         if not finished_cleanly:
-            logger.warning("Previous kernel did not cleanly finish")
+            logger.warning("Previous kernel did not cleanly finish, or the core "
+                           "device panicked (see `artiq_coremgmt log crash`)")
 
     def load(self, kernel_library):
         self._write_header(Request.LoadKernel)
@@ -1,7 +1,11 @@
 from enum import Enum
+from collections import namedtuple
 import logging
 import socket
 import struct
+import binascii
+import hashlib
+import hmac
 
 from artiq.coredevice.comm import initialize_connection
 
@@ -10,11 +14,16 @@ logger = logging.getLogger(__name__)
 
 
 class Request(Enum):
+    GetChallenge = 20
+    Authenticate = 21
+
     GetLog = 1
     ClearLog = 2
     PullLog = 7
     SetLogFilter = 3
     SetUartLogFilter = 6
+    SetLogTargetFilter = 16
+    GetLastCrash = 17
 
     ConfigRead = 12
     ConfigWrite = 13
@@ -24,27 +33,80 @@ class Request(Enum):
     StartProfiler = 9
     StopProfiler = 10
     GetProfile = 11
+    GetProfilePprof = 27
 
     Hotswap = 4
+    FlashFirmware = 18
+    GetFirmwareSlots = 19
     Reboot = 5
 
+    GetNetworkStats = 22
+    StartCapture = 23
+    StopCapture = 24
+    GetCapture = 25
+
+    GetHeapStats = 26
     DebugAllocator = 8
 
+    GetThreads = 28
+
 
 class Reply(Enum):
     Success = 1
     Error = 6
-    Unavailable = 4
 
     LogContent = 2
+    LogRecord = 8
+    LastCrash = 9
 
     ConfigData = 7
 
     Profile = 5
+    ProfilePprof = 15
+
+    FirmwareSlots = 10
+
+    Challenge = 11
+
+    NetworkStats = 12
+    Capture = 13
+
+    HeapStats = 14
+
+    Threads = 16
 
     RebootImminent = 3
 
 
+class ErrorCode(Enum):
+    Other = 0
+    Unavailable = 1
+    UnknownRequest = 2
+    InvalidArgument = 3
+    Unauthorized = 4
+    AuthenticationFailed = 5
+    OutOfMemory = 6
+
+    ConfigAlreadyLocked = 16
+    ConfigSpaceExhausted = 17
+    ConfigCorrupted = 18
+    ConfigUtf8 = 19
+    ConfigRejected = 20
+
+    InvalidFirmware = 32
+    UnauthenticatedFirmware = 33
+    FlashWriteFailed = 34
+
+
+class MgmtError(Exception):
+    """Raised when the core device reports an error in reply to a
+    management request."""
+    def __init__(self, code, message):
+        Exception.__init__(self, message)
+        self.code = code
+        self.message = message
+
+
 class LogLevel(Enum):
     OFF = 0
     ERROR = 1
@@ -54,16 +116,57 @@ class LogLevel(Enum):
     TRACE = 5
 
 
+LogRecord = namedtuple("LogRecord", "seq timestamp level target message")
+NetworkStats = namedtuple("NetworkStats",
+                          "mac_errors frames rejected_connections poll_errors "
+                          "sockets services neighbors")
+HeapStats = namedtuple("HeapStats",
+                       "busy idle meta largest_idle blocks peak_busy")
+Profile = namedtuple("Profile", "hits edges kernel_cpu")
+ThreadInfo = namedtuple("ThreadInfo",
+                        "name stack_size stack_used state detail")
+
+
+def make_firmware_image(firmware, signing_key=None):
+    """Packages a runtime binary for hotswapping: an 8-byte header with the
+    big-endian length and CRC32 of the firmware, the firmware, and, if
+    ``signing_key`` (a 32-byte Ed25519 seed) is given, a signature of both.
+
+    ``firmware`` may also be an image that already has a header, such as
+    ``runtime.fbi``."""
+    if len(firmware) >= 8:
+        length, crc = struct.unpack(">LL", firmware[:8])
+        if (length == len(firmware) - 8 and
+                crc == binascii.crc32(firmware[8:]) & 0xffffffff):
+            firmware = firmware[8:]
+    image = struct.pack(">LL", len(firmware),
+                        binascii.crc32(firmware) & 0xffffffff) + firmware
+    if signing_key is not None:
+        try:
+            from nacl.signing import SigningKey
+        except ImportError:
+            raise ImportError("signing firmware images requires PyNaCl "
+                              "(pip install artiq[signing])")
+        image += SigningKey(signing_key).sign(image).signature
+    return image
+
+
 class CommMgmt:
-    def __init__(self, host, port=1380):
+    """If ``secret`` is given, the connection is authenticated with it as
+    soon as it is opened; this is required for any request that changes the
+    state of a core device whose ``mgmt_secret`` configuration key is set."""
+    def __init__(self, host, port=1380, secret=None):
         self.host = host
         self.port = port
+        self.secret = secret
 
     def open(self, **kwargs):
         if hasattr(self, "socket"):
             return
         self.socket = initialize_connection(self.host, self.port, **kwargs)
         self.socket.sendall(b"ARTIQ management\n")
+        if self.secret is not None:
+            self.authenticate(self.secret)
 
     def close(self):
         if not hasattr(self, "socket"):
@@ -89,6 +192,9 @@ class CommMgmt:
     def _write_int32(self, value):
         self._write(struct.pack(">l", value))
 
+    def _write_int64(self, value):
+        self._write(struct.pack(">q", value))
+
     def _write_bytes(self, value):
         self._write_int32(len(value))
         self._write(value)
@@ -109,17 +215,36 @@ class CommMgmt:
         ty = Reply(*struct.unpack("B", self._read(1)))
         logger.debug("receiving message: type=%r", ty)
 
+        if ty == Reply.Error:
+            code = self._read_int32()
+            try:
+                code = ErrorCode(code)
+            except ValueError:
+                pass
+            message = self._read_string()
+            logger.debug("device error: code=%r message=%r", code, message)
+            raise MgmtError(code, message)
+
         return ty
 
     def _read_expect(self, ty):
-        if self._read_header() != ty:
+        actual = self._read_header()
+        if actual != ty:
             raise IOError("Incorrect reply from device: {} (expected {})".
-                          format(self._read_type, ty))
+                          format(actual, ty))
+
+    def _read_int16(self):
+        (value, ) = struct.unpack(">H", self._read(2))
+        return value
 
     def _read_int32(self):
         (value, ) = struct.unpack(">l", self._read(4))
         return value
 
+    def _read_int64(self):
+        (value, ) = struct.unpack(">q", self._read(8))
+        return value
+
     def _read_bytes(self):
         return self._read(self._read_int32())
 
@@ -128,6 +253,17 @@ class CommMgmt:
 
     # External API
 
+    def authenticate(self, secret):
+        """Proves knowledge of ``secret`` by returning the HMAC-SHA256 of
+        a challenge sent by the core device. The core device closes the
+        connection if authentication fails."""
+        self._write_header(Request.GetChallenge)
+        self._read_expect(Reply.Challenge)
+        challenge = self._read_bytes()
+        self._write_header(Request.Authenticate)
+        self._write_bytes(hmac.new(secret, challenge, hashlib.sha256).digest())
+        self._read_expect(Reply.Success)
+
     def get_log(self):
         self._write_header(Request.GetLog)
         self._read_expect(Reply.LogContent)
@@ -137,9 +273,29 @@ class CommMgmt:
         self._write_header(Request.ClearLog)
         self._read_expect(Reply.Success)
 
-    def pull_log(self):
+    def pull_log(self, from_seq=0):
+        """Yields :class:`LogRecord` tuples as they are logged, starting at
+        sequence number ``from_seq``. A record with a sequence number higher
+        than expected means that the intervening records were lost; a lower
+        one means that the core device has rebooted."""
         self._write_header(Request.PullLog)
-        self._read_expect(Reply.LogContent)
+        self._write_int64(from_seq)
+        while True:
+            self._read_expect(Reply.LogRecord)
+            seq = self._read_int64()
+            timestamp = self._read_int64()
+            level = LogLevel(self._read(1)[0])
+            target = self._read_string()
+            message = self._read_string()
+            yield LogRecord(seq, timestamp, level, target, message)
+
+    def get_last_crash(self):
+        """Returns the report written by the core device when it last
+        panicked: location, message, backtrace and the end of the log.
+        Raises :class:`MgmtError` if no crash has been recorded since
+        power-up."""
+        self._write_header(Request.GetLastCrash)
+        self._read_expect(Reply.LastCrash)
         return self._read_string()
 
     def set_log_level(self, level):
@@ -158,6 +314,15 @@ class CommMgmt:
         self._write_int8(getattr(LogLevel, level).value)
         self._read_expect(Reply.Success)
 
+    def set_log_target_filter(self, spec):
+        """Overrides the log level of individual firmware modules, e.g.
+        ``runtime::rtio_mgt=debug,runtime::session=warn``. The overrides
+        apply to both the log buffer and the UART, and replace any previous
+        ones; an empty string removes them all."""
+        self._write_header(Request.SetLogTargetFilter)
+        self._write_string(spec)
+        self._read_expect(Reply.Success)
+
     def config_read(self, key):
         self._write_header(Request.ConfigRead)
         self._write_string(key)
@@ -168,12 +333,7 @@ class CommMgmt:
         self._write_header(Request.ConfigWrite)
         self._write_string(key)
         self._write_bytes(value)
-        ty = self._read_header()
-        if ty == Reply.Error:
-            raise IOError("Flash storage is full")
-        elif ty != Reply.Success:
-            raise IOError("Incorrect reply from device: {} (expected {})".
-                          format(ty, Reply.Success))
+        self._read_expect(Reply.Success)
 
     def config_remove(self, key):
         self._write_header(Request.ConfigRemove)
@@ -184,15 +344,16 @@ class CommMgmt:
         self._write_header(Request.ConfigErase)
         self._read_expect(Reply.Success)
 
-    def start_profiler(self, interval, edges_size, hits_size):
+    def start_profiler(self, interval, hits_size, edges_size, kernel_cpu=False):
+        """Starts sampling the program counter every ``interval`` microseconds.
+        If ``edges_size`` is nonzero, caller to callee edges are recorded as well.
+        If ``kernel_cpu`` is true, the kernel CPU is sampled instead of the comms
+        CPU, and addresses are relative to the start of the kernel."""
         self._write_header(Request.StartProfiler)
         self._write_int32(interval)
-        self._write_int32(edges_size)
         self._write_int32(hits_size)
-        self._read_expect(Reply.Success)
-
-    def stop_profiler(self):
-        self._write_header(Request.StopProfiler)
+        self._write_int32(edges_size)
+        self._write_int8(kernel_cpu)
         self._read_expect(Reply.Success)
 
     def stop_profiler(self):
@@ -202,6 +363,7 @@ class CommMgmt:
     def get_profile(self):
         self._write_header(Request.GetProfile)
         self._read_expect(Reply.Profile)
+        kernel_cpu = bool(self._read(1)[0])
 
         hits = {}
         for _ in range(self._read_int32()):
@@ -216,16 +378,156 @@ class CommMgmt:
             count = self._read_int32()
             edges[(caller, callee)] = count
 
-        return hits, edges
-
-    def hotswap(self, firmware):
+        return Profile(hits, edges, kernel_cpu)
+
+    def get_profile_pprof(self):
+        """Returns the profile as an uncompressed pprof protobuf, which
+        embeds the software identifier of the runtime. Addresses are not
+        symbolized; pass the firmware or kernel ELF file to ``pprof``."""
+        self._write_header(Request.GetProfilePprof)
+        self._read_expect(Reply.ProfilePprof)
+        return self._read_bytes()
+
+    def hotswap(self, firmware, signing_key=None):
+        """Replaces the running firmware. See :func:`make_firmware_image` for
+        the accepted formats; if the ``hotswap_pubkey`` configuration key is
+        set on the core device, ``signing_key`` must be the matching private
+        key."""
         self._write_header(Request.Hotswap)
-        self._write_bytes(firmware)
+        self._write_bytes(make_firmware_image(firmware, signing_key))
         self._read_expect(Reply.RebootImminent)
 
+    def flash_firmware(self, firmware, signing_key=None):
+        """Writes firmware to the flash slot that is not running and
+        marks it to be tried on the next reboot. If it fails to start, the
+        bootloader falls back to the previous firmware. Accepts the same
+        formats as :meth:`hotswap`."""
+        self._write_header(Request.FlashFirmware)
+        self._write_bytes(make_firmware_image(firmware, signing_key))
+        self._read_expect(Reply.Success)
+
+    def get_firmware_slots(self):
+        """Returns the flash slots (``"A"`` or ``"B"``) that the running
+        firmware was booted from, that last booted successfully, and that
+        will be tried on the next reboot (or ``None``)."""
+        self._write_header(Request.GetFirmwareSlots)
+        self._read_expect(Reply.FirmwareSlots)
+        running, active, pending = self._read(3)
+        names = {0: "A", 1: "B"}
+        return names[running], names[active], names.get(pending)
+
+    def get_network_stats(self):
+        """Returns a :class:`NetworkStats` tuple:
+
+        * ``mac_errors``: receive errors counted by the Ethernet MAC, as a
+          dictionary with the keys ``rx_preamble_errors``, ``rx_crc_errors``
+          and ``rx_dropped``;
+        * ``frames``: frames and bytes received and sent, as a dictionary
+          with the keys ``rx_frames``, ``rx_bytes``, ``tx_frames`` and
+          ``tx_bytes``;
+        * ``rejected_connections``: connections from hosts not in
+          ``allowed_hosts``;
+        * ``poll_errors``: network stack errors, by kind;
+        * ``sockets``: ``(local, remote, state)`` of every TCP socket;
+        * ``services``: ``{port: (rx_bytes, tx_bytes)}`` of TCP traffic;
+        * ``neighbors``: ``(ip, mac, age in ms)`` of hosts learned from ARP.
+
+        All counters start at zero when the core device boots."""
+        self._write_header(Request.GetNetworkStats)
+        self._read_expect(Reply.NetworkStats)
+
+        mac_errors = dict(zip(["rx_preamble_errors", "rx_crc_errors", "rx_dropped"],
+                              [self._read_int32() & 0xffffffff for _ in range(3)]))
+        frames = dict(zip(["rx_frames", "rx_bytes", "tx_frames", "tx_bytes"],
+                          [self._read_int64() for _ in range(4)]))
+        rejected_connections = self._read_int32()
+        poll_errors = {}
+        for _ in range(self._read_int32()):
+            kind = self._read_string()
+            poll_errors[kind] = self._read_int32()
+        sockets = []
+        for _ in range(self._read_int32()):
+            sockets.append((self._read_string(), self._read_string(),
+                            self._read_string()))
+        services = {}
+        for _ in range(self._read_int32()):
+            port = self._read_int16()
+            services[port] = (self._read_int64(), self._read_int64())
+        neighbors = []
+        for _ in range(self._read_int32()):
+            neighbors.append((self._read_string(), self._read_string(),
+                              self._read_int64()))
+        return NetworkStats(mac_errors, frames, rejected_connections,
+                            poll_errors, sockets, services, neighbors)
+
+    def start_capture(self, buffer_size=256*1024, snap_len=1514):
+        """Starts capturing Ethernet frames sent and received by the core
+        device into a ring buffer of ``buffer_size`` bytes, keeping at most
+        ``snap_len`` bytes of each frame. Any previous capture is discarded."""
+        self._write_header(Request.StartCapture)
+        self._write_int32(buffer_size)
+        self._write_int32(snap_len)
+        self._read_expect(Reply.Success)
+
+    def stop_capture(self):
+        self._write_header(Request.StopCapture)
+        self._read_expect(Reply.Success)
+
+    def get_capture(self):
+        """Returns the captured frames in libpcap format, which can be
+        opened with e.g. Wireshark. Timestamps count from the startup of
+        the core device, as if it had started on 1970-01-01."""
+        self._write_header(Request.GetCapture)
+        self._read_expect(Reply.Capture)
+        return self._read_bytes()
+
     def reboot(self):
         self._write_header(Request.Reboot)
         self._read_expect(Reply.RebootImminent)
 
+    def get_heap_stats(self):
+        """Returns a :class:`HeapStats` tuple describing the runtime heap,
+        with all sizes in bytes:
+
+        * ``busy``, ``idle``, ``meta``: total size of allocated blocks, free
+          blocks and block headers;
+        * ``largest_idle``: size of the largest allocation that can succeed;
+        * ``blocks``: number of blocks, allocated or free;
+        * ``peak_busy``: the largest value of ``busy`` since startup."""
+        self._write_header(Request.GetHeapStats)
+        self._read_expect(Reply.HeapStats)
+        return HeapStats(*[self._read_int32() & 0xffffffff for _ in range(6)])
+
+    def get_threads(self):
+        """Returns a list of :class:`ThreadInfo` tuples, one for every
+        thread of the runtime:
+
+        * ``name``;
+        * ``stack_size``, ``stack_used``: size of the stack and the most of
+          it the thread has ever used, in bytes;
+        * ``state``: ``"running"`` for the thread answering the request,
+          ``"ready"``, or what the thread is waiting for: ``"event"``,
+          ``"timeout"`` or ``"socket"``;
+        * ``detail``: the remaining time in ms if ``state`` is ``"timeout"``,
+          the ``(local, remote)`` endpoints if it is ``"socket"``, or else
+          ``None``."""
+        self._write_header(Request.GetThreads)
+        self._read_expect(Reply.Threads)
+
+        threads = []
+        for _ in range(self._read_int32()):
+            name = self._read_string()
+            stack_size = self._read_int32()
+            stack_used = self._read_int32()
+            state = self._read_string()
+            if state == "timeout":
+                detail = self._read_int64()
+            elif state == "socket":
+                detail = (self._read_string(), self._read_string())
+            else:
+                detail = None
+            threads.append(ThreadInfo(name, stack_size, stack_used, state, detail))
+        return threads
+
     def debug_allocator(self):
         self._write_header(Request.DebugAllocator)
@@ -0,0 +1,56 @@
+"""Finds the core devices on the local network segment, which answer a
+broadcast UDP request with their identifiers and addresses."""
+
+import socket
+import time
+
+
+PORT = 1384
+REQUEST = b"ARTIQ discovery\n"
+REPLY = b"ARTIQ core device\n"
+
+
+def parse_reply(data):
+    """Returns a dictionary with the ``software_ident``, ``gateware_ident``
+    and ``mac`` of the core device that sent ``data``, and the list of its
+    addresses in ``ip``; or ``None`` if ``data`` is not a discovery reply."""
+    if not data.startswith(REPLY):
+        return None
+    device = {"ip": []}
+    for line in data[len(REPLY):].decode("utf-8", "replace").splitlines():
+        key, sep, value = line.partition(": ")
+        if not sep:
+            continue
+        if key == "ip":
+            device["ip"].append(value)
+        else:
+            device[key] = value
+    return device
+
+
+def discover(timeout=1.0, address="255.255.255.255", port=PORT):
+    """Broadcasts a discovery request and returns a list of
+    ``(sender, device)`` pairs, where ``device`` is as returned by
+    :func:`parse_reply`, for all replies received within ``timeout``
+    seconds."""
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    try:
+        sock.setsockopt(socket.SOL_SOCKET, socket.SO_BROADCAST, 1)
+        sock.sendto(REQUEST, (address, port))
+        devices = []
+        deadline = time.monotonic() + timeout
+        while True:
+            remaining = deadline - time.monotonic()
+            if remaining <= 0:
+                break
+            sock.settimeout(remaining)
+            try:
+                data, sender = sock.recvfrom(4096)
+            except socket.timeout:
+                break
+            device = parse_reply(data)
+            if device is not None:
+                devices.append((sender[0], device))
+        return devices
+    finally:
+        sock.close()
@@ -9,10 +9,11 @@ extern crate board_misoc;
 
 use core::{ptr, slice};
 use crc::crc32;
-use byteorder::{ByteOrder, BigEndian};
-use board_misoc::{ident, cache, sdram, boot, mem as board_mem};
+use board_misoc::{ident, cache, sdram, boot, boot_slot, clock, config, mem as board_mem};
 #[cfg(has_ethmac)]
-use board_misoc::{clock, config, ethmac};
+use board_misoc::ethmac;
+#[cfg(has_uart)]
+use board_misoc::uart_shell;
 use board_misoc::uart_console::Console;
 
 fn check_integrity() -> bool {
@@ -110,42 +111,154 @@ fn startup() -> bool {
     true
 }
 
-fn flash_boot() {
-    const FIRMWARE: *mut u8 = board_mem::FLASH_BOOT_ADDRESS as *mut u8;
+fn boot_from_slot(slot: boot_slot::Slot) {
     const MAIN_RAM: *mut u8 = board_mem::MAIN_RAM_BASE as *mut u8;
 
-    println!("Booting from flash...");
+    println!("Booting from flash slot {}...", slot);
 
-    let header = unsafe { slice::from_raw_parts(FIRMWARE, 8) };
-    let length = BigEndian::read_u32(&header[0..]) as usize;
-    let expected_crc = BigEndian::read_u32(&header[4..]);
+    let firmware_in_flash = match boot_slot::firmware(slot) {
+        Ok(firmware) => firmware,
+        Err(err) => {
+            println!("Cannot boot from slot {}: {}", slot, err);
+            return
+        }
+    };
+    let expected_crc = crc32::checksum_ieee(firmware_in_flash);
 
-    if length == 0xffffffff {
-        println!("No firmware present");
-        return
-    } else if length > 4 * 1024 * 1024 {
-        println!("Firmware too large (is it corrupted?)");
-        return
+    let firmware_in_sdram = unsafe { slice::from_raw_parts_mut(MAIN_RAM, firmware_in_flash.len()) };
+    firmware_in_sdram.copy_from_slice(firmware_in_flash);
+
+    let actual_crc_sdram = crc32::checksum_ieee(firmware_in_sdram);
+    if actual_crc_sdram == expected_crc {
+        println!("Starting firmware.");
+        unsafe { boot::jump(MAIN_RAM as usize) }
+    } else {
+        println!("Firmware CRC failed in SDRAM (actual {:08x}, expected {:08x})",
+                 actual_crc_sdram, expected_crc);
     }
+}
 
-    let firmware_in_flash = unsafe { slice::from_raw_parts(FIRMWARE.offset(8), length) };
-    let actual_crc_flash = crc32::checksum_ieee(firmware_in_flash);
+// Boots from `slot` on trial; it becomes the active slot once the firmware
+// confirms that it has started up.
+fn trial_boot(slot: boot_slot::Slot) {
+    match boot_slot::begin_trial(slot) {
+        Ok(()) => {
+            boot_from_slot(slot);
+            let _ = boot_slot::abandon_trial();
+        }
+        Err(err) => println!("Cannot start trial: {}", err)
+    }
+}
 
-    if actual_crc_flash == expected_crc {
-        let firmware_in_sdram = unsafe { slice::from_raw_parts_mut(MAIN_RAM, length) };
-        firmware_in_sdram.copy_from_slice(firmware_in_flash);
+fn flash_boot() {
+    let failed = boot_slot::trial();
+    if let Some(slot) = failed {
+        println!("Firmware in slot {} did not confirm a successful boot, falling back", slot);
+        if let Err(err) = boot_slot::abandon_trial() {
+            println!("Cannot record failed trial: {}", err);
+        }
+    }
+
+    let pending = boot_slot::pending();
+    if let Some(slot) = pending {
+        println!("Trying new firmware in slot {}", slot);
+        trial_boot(slot);
+    }
 
-        let actual_crc_sdram = crc32::checksum_ieee(firmware_in_sdram);
-        if actual_crc_sdram == expected_crc {
-            println!("Starting firmware.");
-            unsafe { boot::jump(MAIN_RAM as usize) }
-        } else {
-            println!("Firmware CRC failed in SDRAM (actual {:08x}, expected {:08x})",
-                     actual_crc_sdram, expected_crc);
+    let slot = boot_slot::active();
+    println!("Active firmware slot is {}", slot);
+    boot_from_slot(slot);
+
+    // The active slot is damaged; the other one may still hold good firmware,
+    // unless it has just failed, on this boot or the previous one.
+    let other = slot.other();
+    if pending != Some(other) && failed != Some(other) {
+        match boot_slot::firmware(other) {
+            Ok(_) => {
+                println!("Falling back to slot {}", other);
+                trial_boot(other);
+            }
+            Err(err) => println!("Cannot fall back to slot {}: {}", other, err)
+        }
+    }
+}
+
+// Leave the console and boot if no command is entered for this long, so that
+// a chattering UART cannot keep the board in the bootloader.
+#[cfg(has_uart)]
+const CONSOLE_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+#[cfg(has_uart)]
+fn console_requested() -> bool {
+    println!("Press 'e' within 1 second to enter the console...");
+    let t = clock::get_ms();
+    while clock::get_ms() < t + 1000 {
+        if uart_shell::read_byte() == Some(b'e') {
+            return true
         }
-    } else {
-        println!("Firmware CRC failed in flash (actual {:08x}, expected {:08x})",
-                 actual_crc_flash, expected_crc);
+    }
+    false
+}
+
+#[cfg(has_uart)]
+fn console() {
+    const HELP: &'static str = "\
+boot                     leave the console and boot the firmware
+reboot                   restart the bootloader
+netinfo                  print the configured MAC and IP addresses";
+
+    println!("Type `help` for a list of commands, or `boot` to continue booting.");
+    print!("bootloader> ");
+
+    let mut editor = uart_shell::LineEditor::new();
+    let mut last_command_ms = clock::get_ms();
+    loop {
+        let byte = match uart_shell::read_byte() {
+            Some(byte) => byte,
+            None => {
+                if clock::get_ms() > last_command_ms + CONSOLE_IDLE_TIMEOUT_MS {
+                    println!("");
+                    println!("No command for {} seconds, continuing to boot.",
+                             CONSOLE_IDLE_TIMEOUT_MS / 1000);
+                    return
+                }
+                continue
+            }
+        };
+        let line = match editor.feed(byte) {
+            Some(line) => line,
+            None => continue
+        };
+
+        let (command, args) = uart_shell::split_word(line);
+        if command != "" {
+            last_command_ms = clock::get_ms();
+        }
+        match command {
+            "" => (),
+            "help" => {
+                println!("{}", HELP);
+                println!("{}", uart_shell::CONFIG_HELP);
+            }
+            // The bootloader has no configuration schema; `mgmt_secret` is the
+            // only secret key.
+            "config" => uart_shell::config_command(args, |key| key == "mgmt_secret",
+                                                   |_, _| true),
+            "boot" => return,
+            "reboot" => unsafe { boot::reset() },
+            "netinfo" => {
+                config::read_str("mac", |r| match r {
+                    Ok(mac) if mac != "" => println!("mac: {}", mac),
+                    _ => println!("mac: (default)")
+                });
+                config::read_str("ip", |r| match r {
+                    Ok(ip) if ip != "" => println!("ip:  {}", ip),
+                    _ => println!("ip:  (default)")
+                });
+            }
+            _ => println!("unknown command {:?}; type `help` for a list of commands", command)
+        }
+        print!("bootloader> ");
     }
 }
 
@@ -160,7 +273,8 @@ fn network_boot() {
         _ => EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01])
     };
 
-    let ip_addr = match config::read_str("ip", |r| r.map(|s| s.parse())) {
+    // Only the address of `address/prefix_len` is used; there is no routing.
+    let ip_addr = match config::read_str("ip", |r| r.map(|s| s.split('/').next().unwrap().parse())) {
         Ok(Ok(addr)) => addr,
         _ => IpAddress::v4(192, 168, 1, 50)
     };
@@ -212,6 +326,12 @@ pub extern fn main() -> i32 {
 
     if startup() {
         println!("");
+        #[cfg(has_uart)]
+        {
+            if console_requested() {
+                console()
+            }
+        }
         flash_boot();
         #[cfg(has_ethmac)]
         network_boot();
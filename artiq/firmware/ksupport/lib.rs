@@ -4,6 +4,7 @@
 
 extern crate libc;
 extern crate unwind;
+extern crate unwind_backtrace;
 extern crate cslice;
 
 extern crate eh;
@@ -98,6 +99,7 @@ mod eh_artiq;
 mod api;
 mod rtio;
 mod nrt_bus;
+mod profiler;
 
 static mut NOW: u64 = 0;
 static mut LIBRARY: Option<Library<'static>> = None;
@@ -487,6 +489,14 @@ pub unsafe fn main() {
                                           kernel_proto::KERNELCPU_LAST_ADDRESS -
                                           kernel_proto::KERNELCPU_PAYLOAD_ADDRESS);
 
+    // The comms CPU asks for the kernel to be profiled before loading it.
+    while mailbox::receive() == 0 {}
+    if let ProfileStart { period, edges, samples, len } =
+            *(mailbox::receive() as *const Message) {
+        profiler::start(period, edges, samples, len);
+        mailbox::acknowledge();
+    }
+
     let library = recv!(&LoadRequest(library) => {
         match Library::load(library, image, &api::resolve) {
             Err(error) => {
@@ -526,6 +536,10 @@ pub unsafe fn main() {
 #[no_mangle]
 #[unwind(allowed)]
 pub extern fn exception(vect: u32, _regs: *const u32, pc: u32, ea: u32) {
+    if vect == board_misoc::irq::Exception::Tick as u32 {
+        return profiler::sample(pc as usize)
+    }
+
     panic!("exception {:?} at PC 0x{:x}, EA 0x{:x}", vect, pc, ea)
 }
 
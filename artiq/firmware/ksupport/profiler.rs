@@ -0,0 +1,56 @@
+use core::ptr;
+use unwind_backtrace::backtrace_limit;
+use board_misoc::spr::*;
+use kernel_proto::PROFILE_SAMPLE_WORDS;
+
+static mut SAMPLES: *mut usize = 0 as *mut usize;
+static mut LEN: usize = 0;
+static mut EDGES: bool = false;
+static mut COUNT: usize = 0;
+
+pub unsafe fn start(period: u32, edges: bool, samples: *mut usize, len: usize) {
+    if len == 0 { return }
+
+    SAMPLES = samples;
+    LEN = len;
+    EDGES = edges;
+    COUNT = 0;
+    ptr::write_volatile(SAMPLES, 0);
+
+    mtspr(SPR_TTCR, 0);
+    mtspr(SPR_TTMR, SPR_TTMR_RT | SPR_TTMR_IE | (period & SPR_TTMR_TP));
+    mtspr(SPR_SR, mfspr(SPR_SR) | SPR_SR_TEE);
+}
+
+// Skip frames: ::profiler::sample, ::exception, exception vector.
+const SKIP_FRAMES: usize = 3;
+
+#[inline(always)] // make the top of backtrace predictable
+fn caller(exn_pc: usize) -> usize {
+    let mut caller = 0;
+    let mut frame = 0;
+    let _ = backtrace_limit(SKIP_FRAMES + 2, |pc| {
+        // The frame below the interrupted one has a return address, which points
+        // after the delay slot of the call instruction.
+        if frame == SKIP_FRAMES + 1 && pc != exn_pc {
+            caller = pc - 2 * 4
+        }
+        frame += 1;
+    });
+    caller
+}
+
+#[inline(never)] // see above
+pub fn sample(pc: usize) {
+    unsafe {
+        let caller = if EDGES { caller(pc) } else { 0 };
+
+        let sample = SAMPLES.offset((1 + (COUNT % LEN) * PROFILE_SAMPLE_WORDS) as isize);
+        ptr::write_volatile(sample, pc);
+        ptr::write_volatile(sample.offset(1), caller);
+        COUNT += 1;
+        ptr::write_volatile(SAMPLES, COUNT);
+
+        mtspr(SPR_TTMR, mfspr(SPR_TTMR) & !SPR_TTMR_IP);
+    }
+}
@@ -0,0 +1,112 @@
+// In debug mode, every allocation is followed by a red zone filled with CANARY,
+// and the last two words of its block hold the address it was allocated from and
+// its size. Freed memory is filled with POISON, except for the metadata of free
+// blocks, which include the address the memory was last allocated from.
+
+use core::ptr;
+use super::{Block, ListAlloc, FREE_META_SIZE, WORD_SIZE, MAGIC_FREE, MAGIC_BUSY,
+            next_of, payload_of};
+
+const REDZONE_SIZE: usize = 2 * WORD_SIZE;
+pub const TRAILER_SIZE: usize = REDZONE_SIZE + 2 * WORD_SIZE;
+
+const CANARY: u8 = 0xfd;
+const POISON: u8 = 0xdd;
+
+// The address the allocator entry point will return to, i.e. the allocation site.
+// Only valid when inlined into an entry point that is itself never inlined, before
+// that makes any call: r9 is the link register, and is overwritten by every call.
+#[cfg(target_arch = "or1k")]
+#[inline(always)]
+pub fn return_address() -> usize {
+    let value: usize;
+    unsafe { asm!("l.ori $0, r9, 0" : "=r"(value) : : : "volatile") }
+    value
+}
+
+#[cfg(not(target_arch = "or1k"))]
+#[inline(always)]
+pub fn return_address() -> usize { 0 }
+
+unsafe fn trailer(block: *mut Block) -> *mut [usize; 2] {
+    (next_of(block) as usize - 2 * WORD_SIZE) as *mut [usize; 2]
+}
+
+unsafe fn fill(begin: usize, end: usize, value: u8) {
+    if end > begin {
+        ptr::write_bytes(begin as *mut u8, value, end - begin)
+    }
+}
+
+unsafe fn find_mismatch(begin: usize, end: usize, value: u8) -> Option<usize> {
+    (begin..end).find(|&addr| *(addr as *const u8) != value)
+}
+
+pub unsafe fn on_alloc(block: *mut Block, size: usize, site: usize) {
+    *trailer(block) = [site, size];
+    fill(payload_of(block) as usize + size, trailer(block) as usize, CANARY)
+}
+
+pub unsafe fn site_of(block: *mut Block) -> usize {
+    (*trailer(block))[0]
+}
+
+pub unsafe fn check_busy(block: *mut Block) {
+    let [site, size] = *trailer(block);
+    let payload = payload_of(block) as usize;
+    if size > trailer(block) as usize - payload {
+        panic!("heap corruption detected at {:p}: allocation size overwritten", block)
+    }
+    if let Some(addr) = find_mismatch(payload + size, trailer(block) as usize, CANARY) {
+        panic!("heap overrun detected at {:#x}, past the allocation at {:#x} of {} bytes \
+                made from {:#x}", addr, payload, size, site)
+    }
+}
+
+pub unsafe fn check_invalid_free(block: *mut Block) {
+    if (*block).magic == MAGIC_FREE {
+        panic!("double free detected at {:p}, of memory allocated from {:#x}",
+               payload_of(block), free_site(block))
+    }
+}
+
+pub unsafe fn free_site(block: *mut Block) -> usize {
+    *((block as usize + FREE_META_SIZE - WORD_SIZE) as *const usize)
+}
+
+pub unsafe fn set_free_site(block: *mut Block, site: usize) {
+    *((block as usize + FREE_META_SIZE - WORD_SIZE) as *mut usize) = site
+}
+
+pub unsafe fn poison(begin: usize, end: usize) {
+    fill(begin, end, POISON)
+}
+
+// Checks that memory of the free block `block`, which was last allocated from
+// `site`, has not been written to after it was freed.
+pub unsafe fn check_poison(begin: usize, end: usize, block: *mut Block, site: usize) {
+    if let Some(addr) = find_mismatch(begin, end, POISON) {
+        panic!("use after free detected at {:#x}, in memory freed at {:p} that was \
+                allocated from {:#x}", addr, block, site)
+    }
+}
+
+impl ListAlloc {
+    /// Verifies the red zones of all allocations and the poison of all free
+    /// memory, and panics if any of them were overwritten.
+    pub fn check(&self) {
+        unsafe {
+            self.walk(|block| {
+                match (*block).magic {
+                    MAGIC_BUSY => check_busy(block),
+                    MAGIC_FREE => check_poison(block as usize + FREE_META_SIZE,
+                                               next_of(block) as usize - WORD_SIZE,
+                                               block, free_site(block)),
+                    _ => panic!("heap corruption detected at {:p}", block)
+                }
+                true
+            })
+        }
+    }
+}
+
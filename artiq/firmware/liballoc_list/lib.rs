@@ -1,136 +1,529 @@
 #![no_std]
+#![cfg_attr(all(feature = "debug", target_arch = "or1k"), feature(asm))]
 
-use core::{ptr, mem, fmt};
+use core::{ptr, mem, cmp, fmt};
 use core::alloc::{GlobalAlloc, Layout};
 
-// The minimum alignment guaranteed by the architecture.
-const MIN_ALIGN: usize = 4;
+#[cfg(feature = "debug")]
+mod debug;
+
+#[cfg(not(feature = "debug"))]
+mod debug {
+    use super::Block;
+
+    pub const TRAILER_SIZE: usize = 0;
+
+    #[inline(always)]
+    pub fn return_address() -> usize { 0 }
+
+    #[inline(always)]
+    pub unsafe fn on_alloc(_block: *mut Block, _size: usize, _site: usize) {}
+    #[inline(always)]
+    pub unsafe fn check_busy(_block: *mut Block) {}
+    #[inline(always)]
+    pub unsafe fn check_invalid_free(_block: *mut Block) {}
+    #[inline(always)]
+    pub unsafe fn site_of(_block: *mut Block) -> usize { 0 }
+    #[inline(always)]
+    pub unsafe fn set_free_site(_block: *mut Block, _site: usize) {}
+    #[inline(always)]
+    pub unsafe fn free_site(_block: *mut Block) -> usize { 0 }
+    #[inline(always)]
+    pub unsafe fn poison(_begin: usize, _end: usize) {}
+    #[inline(always)]
+    pub unsafe fn check_poison(_begin: usize, _end: usize, _block: *mut Block, _site: usize) {}
+}
 
 const MAGIC_FREE: usize = 0xDEADDEAD;
 const MAGIC_BUSY: usize = 0xFEEDFEED;
 
+const WORD_SIZE: usize = mem::size_of::<usize>();
+
+// Blocks start at, and their sizes are multiples of, the granule; this is also
+// the alignment of every allocation made without a stricter requirement.
+const GRANULE: usize = 2 * WORD_SIZE;
+
+// A block starts with a header, made of the `magic` and `size` fields of `Block`;
+// its payload follows. A free block instead stores its free list links and a word
+// used in debug mode in place of the payload, and its size in its last word, so that
+// the next block can find it.
+const HEADER_SIZE: usize = 2 * WORD_SIZE;
+const FREE_META_SIZE: usize = HEADER_SIZE + 3 * WORD_SIZE;
+const MIN_BLOCK_SIZE: usize = (FREE_META_SIZE + WORD_SIZE + GRANULE - 1) / GRANULE * GRANULE;
+
+// Set in `Block::size` when the block before this one is free.
+const PREV_FREE: usize = 1;
+const SIZE_MASK: usize = !(GRANULE - 1);
+
+// Free blocks are kept in one list per power of two of their size.
+const NUM_BINS: usize = 8 * WORD_SIZE;
+
 #[derive(Debug)]
-struct Header {
+struct Block {
     magic: usize,
     size:  usize,
-    next:  *mut Header
+    prev:  *mut Block,
+    next:  *mut Block
+}
+
+// A contiguous range of memory, which starts with this header and ends with
+// an empty busy block that stops coalescing.
+struct Region {
+    next:  *mut Region
 }
 
+struct State {
+    bins:     [*mut Block; NUM_BINS],
+    // Bit `n` is set if `bins[n]` is not empty.
+    nonempty: usize,
+    regions:  *mut Region,
+    busy:     usize,
+    peak:     usize
+}
+
+/// A segregated fit allocator. Free blocks are coalesced with their neighbors
+/// as soon as they are released, and any power of two alignment is supported.
 pub struct ListAlloc {
-    root:  *mut Header
+    state: *mut State
 }
 
-pub const EMPTY: ListAlloc = ListAlloc { root: 0 as *mut Header };
+pub const EMPTY: ListAlloc = ListAlloc { state: 0 as *mut State };
+
+/// A summary of the heap. All sizes are in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Total size of allocated blocks.
+    pub busy:         usize,
+    /// Total size of free blocks.
+    pub idle:         usize,
+    /// Total size of block headers.
+    pub meta:         usize,
+    /// Size of the largest allocation that can currently succeed.
+    pub largest_idle: usize,
+    /// Number of blocks, allocated or free.
+    pub blocks:       usize,
+    /// The largest value `busy` has had since startup.
+    pub peak_busy:    usize,
+}
+
+fn round_up(value: usize, align: usize) -> Option<usize> {
+    value.checked_add(align - 1).map(|value| value & !(align - 1))
+}
+
+fn bin_index(size: usize) -> usize {
+    NUM_BINS - 1 - size.leading_zeros() as usize
+}
+
+// The size of the block that holds `size` bytes of payload.
+fn block_size(size: usize) -> Option<usize> {
+    cmp::max(size, 1).checked_add(debug::TRAILER_SIZE)
+        .and_then(|size| round_up(size, GRANULE))
+        .and_then(|size| size.checked_add(HEADER_SIZE))
+        .map(|size| cmp::max(size, MIN_BLOCK_SIZE))
+}
+
+unsafe fn size_of(block: *mut Block) -> usize {
+    (*block).size & SIZE_MASK
+}
+
+unsafe fn next_of(block: *mut Block) -> *mut Block {
+    (block as usize + size_of(block)) as *mut Block
+}
+
+unsafe fn payload_of(block: *mut Block) -> *mut u8 {
+    (block as usize + HEADER_SIZE) as *mut u8
+}
+
+unsafe fn block_of(payload: *mut u8) -> *mut Block {
+    let block = (payload as usize - HEADER_SIZE) as *mut Block;
+    if (*block).magic != MAGIC_BUSY {
+        debug::check_invalid_free(block);
+        panic!("heap corruption detected at {:p}", block)
+    }
+    block
+}
+
+unsafe fn set_size(block: *mut Block, size: usize) {
+    (*block).size = size | ((*block).size & PREV_FREE)
+}
+
+unsafe fn set_prev_free(block: *mut Block, prev_free: bool) {
+    if prev_free {
+        (*block).size |= PREV_FREE
+    } else {
+        (*block).size &= !PREV_FREE
+    }
+}
+
+impl State {
+    unsafe fn insert(&mut self, block: *mut Block) {
+        let size = size_of(block);
+        (*block).magic = MAGIC_FREE;
+        *((block as usize + size - WORD_SIZE) as *mut usize) = size;
+        set_prev_free(next_of(block), true);
+
+        let index = bin_index(size);
+        (*block).prev = ptr::null_mut();
+        (*block).next = self.bins[index];
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = block
+        }
+        self.bins[index] = block;
+        self.nonempty |= 1 << index;
+    }
+
+    unsafe fn remove(&mut self, block: *mut Block) {
+        if (*block).magic != MAGIC_FREE {
+            panic!("heap corruption detected at {:p}", block)
+        }
+        (*block).magic = MAGIC_BUSY;
+        set_prev_free(next_of(block), false);
+
+        let index = bin_index(size_of(block));
+        if (*block).prev.is_null() {
+            self.bins[index] = (*block).next
+        } else {
+            (*(*block).prev).next = (*block).next
+        }
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = (*block).prev
+        }
+        if self.bins[index].is_null() {
+            self.nonempty &= !(1 << index)
+        }
+    }
+
+    // Finds and removes a free block of at least `size` bytes.
+    unsafe fn find(&mut self, size: usize) -> *mut Block {
+        // Blocks in the bin of `size` may be smaller than it...
+        let index = bin_index(size);
+        let mut block = self.bins[index];
+        while !block.is_null() {
+            if size_of(block) >= size {
+                self.remove(block);
+                return block
+            }
+            block = (*block).next
+        }
+
+        // ... but not in the larger bins.
+        let larger = self.nonempty & (!0usize).checked_shl(index as u32 + 1).unwrap_or(0);
+        if larger == 0 {
+            return ptr::null_mut()
+        }
+        let block = self.bins[larger.trailing_zeros() as usize];
+        self.remove(block);
+        block
+    }
+
+    // Frees a block that is not in a free list, merging it with its neighbors,
+    // and returns the resulting free block. In debug mode, the payload of the block
+    // must have been poisoned already.
+    unsafe fn release(&mut self, mut block: *mut Block) -> *mut Block {
+        let next = next_of(block);
+        if (*next).magic == MAGIC_FREE {
+            self.remove(next);
+            (*next).magic = 0;
+            set_size(block, size_of(block) + size_of(next));
+            debug::poison(next as usize, next as usize + FREE_META_SIZE);
+        }
+
+        if (*block).size & PREV_FREE != 0 {
+            let prev_size = *((block as usize - WORD_SIZE) as *const usize);
+            let prev = (block as usize - prev_size) as *mut Block;
+            self.remove(prev);
+            set_size(prev, prev_size + size_of(block));
+            (*block).magic = 0;
+            debug::poison(block as usize - WORD_SIZE, block as usize + HEADER_SIZE);
+            block = prev;
+        } else {
+            debug::set_free_site(block, 0);
+        }
+
+        self.insert(block);
+        block
+    }
+
+    // Shrinks a busy block to `size` bytes, freeing the rest if it is large enough
+    // to form a block of its own. Unless the rest was carved out of a free block,
+    // it has to be poisoned.
+    unsafe fn trim(&mut self, block: *mut Block, size: usize, poison: bool) {
+        let rest = size_of(block) - size;
+        if rest >= MIN_BLOCK_SIZE {
+            set_size(block, size);
+            let tail = next_of(block);
+            if poison {
+                debug::poison(tail as usize, tail as usize + rest);
+            }
+            (*tail).magic = MAGIC_BUSY;
+            (*tail).size  = rest;
+            self.release(tail);
+        }
+    }
+
+    fn account(&mut self, freed: usize, allocated: usize) {
+        self.busy = self.busy - freed + allocated;
+        if self.busy > self.peak {
+            self.peak = self.busy
+        }
+    }
+}
 
 impl ListAlloc {
     pub unsafe fn add(&mut self, ptr: *mut u8, size: usize) {
-        let header_size = mem::size_of::<Header>();
-        if size < header_size * 2 { return }
+        let begin = match round_up(ptr as usize, GRANULE) {
+            Some(begin) => begin,
+            None => return
+        };
+        let end = (ptr as usize).saturating_add(size) & SIZE_MASK;
 
-        let curr = ptr as *mut Header;
-        (*curr).magic = MAGIC_FREE;
-        (*curr).size  = size - header_size;
-        (*curr).next  = self.root;
-        self.root = curr;
+        let state_size = if self.state.is_null() {
+            round_up(mem::size_of::<State>(), GRANULE).unwrap()
+        } else {
+            0
+        };
+        let first = begin + state_size + round_up(mem::size_of::<Region>(), GRANULE).unwrap();
+        if end < first || end - first < MIN_BLOCK_SIZE + HEADER_SIZE { return }
+
+        if self.state.is_null() {
+            self.state = begin as *mut State;
+            ptr::write(self.state, State {
+                bins:     [ptr::null_mut(); NUM_BINS],
+                nonempty: 0,
+                regions:  ptr::null_mut(),
+                busy:     0,
+                peak:     0
+            });
+        }
+        let state = &mut *self.state;
+
+        let region = (begin + state_size) as *mut Region;
+        (*region).next = state.regions;
+        state.regions = region;
+
+        let sentinel = (end - HEADER_SIZE) as *mut Block;
+        (*sentinel).magic = MAGIC_BUSY;
+        (*sentinel).size  = 0;
+
+        let block = first as *mut Block;
+        debug::poison(first, sentinel as usize);
+        (*block).magic = MAGIC_BUSY;
+        (*block).size  = sentinel as usize - first;
+        state.insert(block);
+        debug::set_free_site(block, 0);
     }
 
     pub unsafe fn add_range(&mut self, begin: *mut u8, end: *mut u8) {
         self.add(begin, end as usize - begin as usize)
     }
-}
 
-unsafe impl GlobalAlloc for ListAlloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if layout.align() > MIN_ALIGN {
-            panic!("cannot allocate with alignment {}", layout.align())
+    // Calls `f` with every block of every region, stopping early if `f` returns false.
+    unsafe fn walk<F: FnMut(*mut Block) -> bool>(&self, mut f: F) {
+        if self.state.is_null() { return }
+
+        let mut region = (*self.state).regions;
+        while !region.is_null() {
+            let mut block = (region as usize +
+                             round_up(mem::size_of::<Region>(), GRANULE).unwrap()) as *mut Block;
+            while size_of(block) != 0 {
+                if !f(block) { return }
+                block = next_of(block);
+            }
+            region = (*region).next;
         }
+    }
 
-        let header_size = mem::size_of::<Header>();
-        let size;
-        if layout.size() % header_size != 0 {
-            size = layout.size() + header_size - (layout.size() % header_size);
-        } else {
-            size = layout.size()
-        }
-
-        let mut curr = self.root;
-        while !curr.is_null() {
-            match (*curr).magic {
-                MAGIC_BUSY => (),
-                MAGIC_FREE => {
-                    let mut next = (*curr).next;
-                    while !next.is_null() && (*next).magic == MAGIC_FREE {
-                        // Join
-                        (*next).magic = 0;
-                        (*curr).size += (*next).size + header_size;
-                        (*curr).next  = (*next).next;
-                        next = (*curr).next;
-                    }
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            busy:         0,
+            idle:         0,
+            meta:         0,
+            largest_idle: 0,
+            blocks:       0,
+            peak_busy:    0,
+        };
+        if self.state.is_null() { return stats }
 
-                    if (*curr).size > size + header_size * 2 {
-                        // Split
-                        let offset = header_size + size;
-                        let next = (curr as *mut u8).offset(offset as isize) as *mut Header;
-                        (*next).magic = MAGIC_FREE;
-                        (*next).size  = (*curr).size - offset;
-                        (*next).next  = (*curr).next;
-                        (*curr).next  = next;
-                        (*curr).size  = size;
-                    }
+        unsafe {
+            stats.peak_busy = (*self.state).peak;
+            self.walk(|block| {
+                let size = size_of(block) - HEADER_SIZE;
+                stats.meta   += HEADER_SIZE;
+                stats.blocks += 1;
 
-                    if (*curr).size >= size {
-                        (*curr).magic = MAGIC_BUSY;
-                        return curr.offset(1) as *mut u8
+                match (*block).magic {
+                    MAGIC_FREE => {
+                        stats.idle += size;
+                        stats.largest_idle = cmp::max(stats.largest_idle, size);
                     }
-                },
-                _ => panic!("heap corruption detected at {:p}", curr)
+                    MAGIC_BUSY => stats.busy += size,
+                    _ => panic!("heap corruption detected at {:p}", block)
+                }
+                true
+            })
+        }
+
+        stats
+    }
+}
+
+impl ListAlloc {
+    unsafe fn allocate(&self, layout: Layout, site: usize) -> *mut u8 {
+        if self.state.is_null() { return ptr::null_mut() }
+        let state = &mut *self.state;
+
+        let size = match block_size(layout.size()) {
+            Some(size) => size,
+            None => return ptr::null_mut()
+        };
+        // An aligned payload may start further into a block; the part before it
+        // becomes a free block of its own.
+        let align = layout.align();
+        let search_size = if align > GRANULE {
+            match size.checked_add(align + MIN_BLOCK_SIZE) {
+                Some(search_size) => search_size,
+                None => return ptr::null_mut()
+            }
+        } else {
+            size
+        };
+
+        let mut block = state.find(search_size);
+        if block.is_null() { return ptr::null_mut() }
+        let (found_begin, found_end) = (block as usize, next_of(block) as usize);
+        let freed_site = debug::free_site(block);
+
+        if align > GRANULE {
+            let payload = payload_of(block) as usize;
+            let mut aligned = round_up(payload, align).unwrap();
+            while aligned != payload && aligned - payload < MIN_BLOCK_SIZE {
+                aligned += align
             }
 
-            curr = (*curr).next;
+            if aligned != payload {
+                let gap = aligned - payload;
+                let rest = (block as usize + gap) as *mut Block;
+                (*rest).magic = MAGIC_BUSY;
+                (*rest).size  = size_of(block) - gap;
+                set_size(block, gap);
+                state.insert(block);
+                block = rest;
+            }
         }
 
-        ptr::null_mut()
+        state.trim(block, size, false);
+        // Only the part of the free block that is handed out is checked here;
+        // the rest is checked when it is handed out, or by a heap walk.
+        debug::check_poison(cmp::max(payload_of(block) as usize, found_begin + FREE_META_SIZE),
+                            cmp::min(next_of(block) as usize, found_end - WORD_SIZE),
+                            block, freed_site);
+        debug::on_alloc(block, layout.size(), site);
+        state.account(0, size_of(block) - HEADER_SIZE);
+        payload_of(block)
+    }
+}
+
+// The entry points are never inlined, so that `debug::return_address` finds the
+// allocation site in r9 rather than a return address inside liballoc.
+unsafe impl GlobalAlloc for ListAlloc {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout, debug::return_address())
+    }
+
+    // Not the default implementation, which would record itself as the allocation site.
+    #[inline(never)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocate(layout, debug::return_address());
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size())
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let curr = (ptr as *mut Header).offset(-1);
-        if (*curr).magic != MAGIC_BUSY {
-            panic!("heap corruption detected at {:p}", curr)
+        let state = &mut *self.state;
+        let block = block_of(ptr);
+        debug::check_busy(block);
+        let site = debug::site_of(block);
+        debug::poison(payload_of(block) as usize, next_of(block) as usize);
+
+        state.account(size_of(block) - HEADER_SIZE, 0);
+        let block = state.release(block);
+        debug::set_free_site(block, site);
+    }
+
+    #[inline(never)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let site = debug::return_address();
+        let state = &mut *self.state;
+        let block = block_of(ptr);
+        debug::check_busy(block);
+        let old_size = size_of(block);
+
+        let size = match block_size(new_size) {
+            Some(size) => size,
+            None => return ptr::null_mut()
+        };
+
+        // Grow into the next block, if it is free and large enough...
+        let next = next_of(block);
+        if size > old_size && (*next).magic == MAGIC_FREE &&
+                old_size + size_of(next) >= size {
+            state.remove(next);
+            (*next).magic = 0;
+            set_size(block, old_size + size_of(next));
+        }
+
+        // ... and give back whatever is not needed.
+        if size_of(block) >= size {
+            state.trim(block, size, true);
+            debug::on_alloc(block, new_size, site);
+            state.account(old_size - HEADER_SIZE, size_of(block) - HEADER_SIZE);
+            return ptr
         }
-        (*curr).magic = MAGIC_FREE;
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.allocate(new_layout, site);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
 impl fmt::Display for ListAlloc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unsafe {
-            let mut total_busy = 0;
-            let mut total_idle = 0;
-            let mut total_meta = 0;
+        let mut total_busy = 0;
+        let mut total_idle = 0;
+        let mut total_meta = 0;
+        let mut result = Ok(());
 
-            let mut curr = self.root;
-            while !curr.is_null() {
-                total_meta += mem::size_of::<Header>();
+        unsafe {
+            self.walk(|block| {
+                total_meta += HEADER_SIZE;
 
-                let desc = match (*curr).magic {
-                    MAGIC_FREE => { total_idle += (*curr).size; "IDLE" },
-                    MAGIC_BUSY => { total_busy += (*curr).size; "BUSY" },
+                let size = size_of(block).wrapping_sub(HEADER_SIZE);
+                let desc = match (*block).magic {
+                    MAGIC_FREE => { total_idle += size; "IDLE" },
+                    MAGIC_BUSY => { total_busy += size; "BUSY" },
                     _ => "!!!!"
                 };
 
-                write!(f, "{} {:p} + {:#x} + {:#x} -> {:p}\n",
-                       desc, curr, mem::size_of::<Header>(), (*curr).size, (*curr).next)?;
-                match (*curr).magic {
-                    MAGIC_FREE | MAGIC_BUSY => (),
-                    _ => break
+                result = write!(f, "{} {:p} + {:#x} + {:#x}\n",
+                                desc, block, HEADER_SIZE, size);
+                match (*block).magic {
+                    MAGIC_FREE | MAGIC_BUSY => result.is_ok(),
+                    _ => false
                 }
-
-                curr = (*curr).next;
-            }
-
-            write!(f, " === busy: {:#x} idle: {:#x} meta: {:#x} total: {:#x}\n",
-                   total_busy, total_idle, total_meta,
-                   total_busy + total_idle + total_meta)
+            })
         }
+        result?;
+
+        let peak = if self.state.is_null() { 0 } else { unsafe { (*self.state).peak } };
+        write!(f, " === busy: {:#x} idle: {:#x} meta: {:#x} total: {:#x} peak busy: {:#x}\n",
+               total_busy, total_idle, total_meta,
+               total_busy + total_idle + total_meta, peak)
     }
 }
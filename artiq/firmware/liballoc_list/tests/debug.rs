@@ -0,0 +1,88 @@
+#![cfg(feature = "debug")]
+
+extern crate alloc_list;
+
+use std::alloc::{GlobalAlloc, Layout};
+use alloc_list::ListAlloc;
+
+fn with_heap<F: FnOnce(&ListAlloc)>(f: F) {
+    let mut memory = vec![0u64; 4096];
+    let mut heap = alloc_list::EMPTY;
+    unsafe { heap.add(memory.as_mut_ptr() as *mut u8, memory.len() * 8) }
+    f(&heap)
+}
+
+#[test]
+fn clean() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(13, 1).unwrap();
+        let ptr = heap.alloc(layout);
+        *ptr.add(12) = 1;
+        heap.check();
+        let ptr = heap.realloc(ptr, layout, 100);
+        *ptr.add(99) = 1;
+        heap.check();
+        heap.dealloc(ptr, Layout::from_size_align(100, 1).unwrap());
+        heap.check();
+    })
+}
+
+#[test]
+#[should_panic(expected = "heap overrun")]
+fn overrun_on_free() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(13, 1).unwrap();
+        let ptr = heap.alloc(layout);
+        *ptr.add(13) = 1;
+        heap.dealloc(ptr, layout);
+    })
+}
+
+#[test]
+#[should_panic(expected = "heap overrun")]
+fn overrun_on_check() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        *ptr.add(32) = 1;
+        heap.check();
+    })
+}
+
+#[test]
+#[should_panic(expected = "use after free")]
+fn use_after_free_on_check() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        let _guard = heap.alloc(layout);
+        heap.dealloc(ptr, layout);
+        *ptr.add(128) = 1;
+        heap.check();
+    })
+}
+
+#[test]
+#[should_panic(expected = "use after free")]
+fn use_after_free_on_alloc() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        let _guard = heap.alloc(layout);
+        heap.dealloc(ptr, layout);
+        *ptr.add(128) = 1;
+        heap.alloc(layout);
+    })
+}
+
+#[test]
+#[should_panic(expected = "double free")]
+fn double_free() {
+    with_heap(|heap| unsafe {
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        let _guard = heap.alloc(layout);
+        heap.dealloc(ptr, layout);
+        heap.dealloc(ptr, layout);
+    })
+}
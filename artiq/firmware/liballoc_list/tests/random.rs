@@ -0,0 +1,182 @@
+extern crate alloc_list;
+
+use std::alloc::{GlobalAlloc, Layout};
+use alloc_list::ListAlloc;
+
+const HEAP_SIZE: usize = 1 << 20;
+
+// xorshift64*, so that failures are reproducible from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+struct Allocation {
+    ptr:    *mut u8,
+    layout: Layout,
+    fill:   u8
+}
+
+impl Allocation {
+    unsafe fn check(&self) {
+        for offset in 0..self.layout.size() {
+            assert_eq!(*self.ptr.add(offset), self.fill,
+                       "allocation at {:p} was overwritten", self.ptr)
+        }
+    }
+
+    unsafe fn fill(&mut self, fill: u8) {
+        self.fill = fill;
+        for offset in 0..self.layout.size() {
+            *self.ptr.add(offset) = fill
+        }
+    }
+}
+
+fn random_layout(rng: &mut Rng) -> Layout {
+    let size = match rng.below(4) {
+        0 => rng.below(16),
+        1 => rng.below(256),
+        2 => rng.below(4096),
+        _ => rng.below(64 * 1024)
+    };
+    let align = 1 << rng.below(8);
+    Layout::from_size_align(size, align).unwrap()
+}
+
+fn check_heap(heap: &ListAlloc, heap_size: usize) {
+    let stats = heap.stats();
+    assert!(stats.busy + stats.idle + stats.meta <= heap_size);
+    assert!(stats.largest_idle <= stats.idle);
+    assert!(stats.busy <= stats.peak_busy);
+    #[cfg(feature = "debug")]
+    heap.check();
+}
+
+fn run(seed: u64, steps: usize) {
+    let mut memory = vec![0u64; HEAP_SIZE / 8];
+    let mut heap = alloc_list::EMPTY;
+    unsafe { heap.add(memory.as_mut_ptr() as *mut u8, HEAP_SIZE) }
+    let initial = heap.stats();
+    assert_eq!(initial.blocks, 1);
+
+    let mut rng = Rng(seed);
+    let mut allocations: Vec<Allocation> = Vec::new();
+    for step in 0..steps {
+        unsafe {
+            match rng.below(3) {
+                0 | 1 if allocations.len() < 200 => {
+                    let layout = random_layout(&mut rng);
+                    let ptr = heap.alloc(layout);
+                    if ptr.is_null() {
+                        // A request that fits into the largest free block must succeed.
+                        assert!(layout.size() + layout.align() + 128 > heap.stats().largest_idle);
+                        continue
+                    }
+                    assert_eq!(ptr as usize % layout.align(), 0);
+                    let mut allocation = Allocation { ptr, layout, fill: 0 };
+                    allocation.fill(step as u8);
+                    allocations.push(allocation);
+                }
+                0 if !allocations.is_empty() => {
+                    let index = rng.below(allocations.len());
+                    let allocation = &mut allocations[index];
+                    allocation.check();
+                    let new_size = rng.below(2 * allocation.layout.size() + 64);
+                    let ptr = heap.realloc(allocation.ptr, allocation.layout, new_size);
+                    if ptr.is_null() { continue }
+                    assert_eq!(ptr as usize % allocation.layout.align(), 0);
+                    let old_size = allocation.layout.size();
+                    for offset in 0..old_size.min(new_size) {
+                        assert_eq!(*ptr.add(offset), allocation.fill)
+                    }
+                    allocation.ptr = ptr;
+                    allocation.layout =
+                        Layout::from_size_align(new_size, allocation.layout.align()).unwrap();
+                    allocation.fill(step as u8);
+                }
+                _ if !allocations.is_empty() => {
+                    let index = rng.below(allocations.len());
+                    let allocation = allocations.swap_remove(index);
+                    allocation.check();
+                    heap.dealloc(allocation.ptr, allocation.layout);
+                }
+                _ => ()
+            }
+        }
+
+        if step % 64 == 0 {
+            check_heap(&heap, HEAP_SIZE);
+        }
+    }
+
+    for allocation in allocations.drain(..) {
+        unsafe {
+            allocation.check();
+            heap.dealloc(allocation.ptr, allocation.layout);
+        }
+    }
+
+    // Everything that was freed has been coalesced again.
+    let stats = heap.stats();
+    assert_eq!(stats.busy, 0);
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.idle, initial.idle);
+    assert_eq!(stats.largest_idle, initial.idle);
+}
+
+#[test]
+fn random_traces() {
+    for seed in 1..33 {
+        run(seed, 5000)
+    }
+}
+
+#[test]
+fn large_alignment() {
+    let mut memory = vec![0u64; HEAP_SIZE / 8];
+    let mut heap = alloc_list::EMPTY;
+    unsafe { heap.add(memory.as_mut_ptr() as *mut u8, HEAP_SIZE) }
+
+    let layout = Layout::from_size_align(100_000, 4096).unwrap();
+    let ptrs: Vec<*mut u8> = (0..8).map(|_| unsafe { heap.alloc(layout) }).collect();
+    for &ptr in ptrs.iter() {
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % 4096, 0);
+    }
+    for &ptr in ptrs.iter() {
+        unsafe { heap.dealloc(ptr, layout) }
+    }
+    assert_eq!(heap.stats().blocks, 1);
+}
+
+#[test]
+fn exhaustion() {
+    let mut memory = vec![0u64; 4096 / 8];
+    let mut heap = alloc_list::EMPTY;
+    unsafe { heap.add(memory.as_mut_ptr() as *mut u8, 4096) }
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(layout) };
+        if ptr.is_null() { break }
+        ptrs.push(ptr);
+    }
+    assert!(ptrs.len() > 24);
+    assert!(heap.stats().largest_idle < 64);
+    for ptr in ptrs {
+        unsafe { heap.dealloc(ptr, layout) }
+    }
+    assert_eq!(heap.stats().blocks, 1);
+}
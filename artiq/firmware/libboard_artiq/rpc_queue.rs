@@ -6,7 +6,8 @@ const SEND_MAILBOX: *mut usize = (mem::MAILBOX_BASE + 4) as *mut usize;
 const RECV_MAILBOX: *mut usize = (mem::MAILBOX_BASE + 8) as *mut usize;
 
 const QUEUE_BEGIN: usize = 0x40400000;
-const QUEUE_END:   usize = 0x407fff80;
+// The space above QUEUE_END is reserved for the runtime crash log.
+const QUEUE_END:   usize = 0x407f0000;
 const QUEUE_CHUNK: usize = 0x1000;
 
 pub unsafe fn init() {
@@ -0,0 +1,231 @@
+use core::{cmp, fmt, slice};
+use byteorder::{ByteOrder, BigEndian};
+use crc::crc32;
+use {boot, cache, config, csr, mem, spiflash};
+
+// The firmware may be stored in either of two slots in SPI flash. Slot A is at
+// FLASH_BOOT_ADDRESS, where artiq_flash writes it; slot B immediately follows.
+// Both have to fit below FIRMWARE_FLASH_END, which the gateware sets to where
+// the next item in the flash map of the board (see artiq_flash) begins. Where
+// there is no room for slot B, only slot A is used and FlashFirmware is refused.
+//
+// Which slot is booted is recorded in the configuration:
+//  * `boot_slot` is the slot that last booted successfully (A if absent);
+//  * `boot_pending` is a slot to try on the next boot;
+//  * `boot_trial` is set by the bootloader while it tries the pending slot, and
+//    removed by the firmware once it has started up. If it is still present on
+//    the next boot, the trial failed and the bootloader falls back to `boot_slot`.
+
+// length: u32, crc32: u32
+const HEADER_SIZE: usize = 8;
+
+// Room for the header and the largest firmware that can be booted, in whole sectors.
+const SLOT_SIZE: usize = (HEADER_SIZE + boot::MAX_FIRMWARE_SIZE + spiflash::SECTOR_SIZE - 1) &
+                         !(spiflash::SECTOR_SIZE - 1);
+
+const FIRMWARE_END: usize = csr::CONFIG_FIRMWARE_FLASH_END as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A
+        }
+    }
+
+    pub fn address(self) -> usize {
+        match self {
+            Slot::A => mem::FLASH_BOOT_ADDRESS,
+            Slot::B => mem::FLASH_BOOT_ADDRESS + SLOT_SIZE
+        }
+    }
+
+    /// The space available for the header and firmware in this slot, which may
+    /// be less than SLOT_SIZE for slot A, and is zero if the slot does not exist.
+    pub fn size(self) -> usize {
+        match self {
+            Slot::A => cmp::min(SLOT_SIZE, FIRMWARE_END.saturating_sub(self.address())),
+            Slot::B if self.address() + SLOT_SIZE <= FIRMWARE_END => SLOT_SIZE,
+            Slot::B => 0
+        }
+    }
+
+    /// Whether the flash map of the board has room for this slot.
+    pub fn exists(self) -> bool {
+        self.size() > HEADER_SIZE
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Slot> {
+        match name {
+            "a" => Some(Slot::A),
+            "b" => Some(Slot::B),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Slot::A => write!(f, "A"),
+            Slot::B => write!(f, "B")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoRoom { slot: Slot },
+    Empty,
+    TooLarge { size: usize, max: usize },
+    CrcMismatch { expected: u32, actual: u32 },
+    Config(config::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoRoom { slot } =>
+                write!(f, "the flash of this board has no room for slot {}", slot),
+            Error::Empty =>
+                write!(f, "no firmware present"),
+            Error::TooLarge { size, max } =>
+                write!(f, "firmware of {} bytes does not fit into {} bytes \
+                           (is it corrupted?)", size, max),
+            Error::CrcMismatch { expected, actual } =>
+                write!(f, "firmware CRC failed (actual {:08x}, expected {:08x})",
+                       actual, expected),
+            Error::Config(err) =>
+                write!(f, "configuration error: {}", err)
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(value: config::Error) -> Error {
+        Error::Config(value)
+    }
+}
+
+/// Returns the firmware stored in `slot` after checking its length and CRC.
+pub fn firmware(slot: Slot) -> Result<&'static [u8], Error> {
+    if !slot.exists() {
+        return Err(Error::NoRoom { slot })
+    }
+
+    let max = slot.size() - HEADER_SIZE;
+    let header = unsafe { slice::from_raw_parts(slot.address() as *const u8, HEADER_SIZE) };
+    let length = BigEndian::read_u32(&header[0..]) as usize;
+    let expected_crc = BigEndian::read_u32(&header[4..]);
+
+    if length == 0xffffffff {
+        return Err(Error::Empty)
+    } else if length > max {
+        return Err(Error::TooLarge { size: length, max })
+    }
+
+    let firmware = unsafe {
+        slice::from_raw_parts((slot.address() + HEADER_SIZE) as *const u8, length)
+    };
+    let actual_crc = crc32::checksum_ieee(firmware);
+    if actual_crc != expected_crc {
+        return Err(Error::CrcMismatch { expected: expected_crc, actual: actual_crc })
+    }
+    Ok(firmware)
+}
+
+/// Writes a firmware image (header and firmware, as in .fbi files) into `slot`
+/// and checks it. `relinquish` is called after each sector, since writing
+/// a few megabytes takes a while.
+pub fn write<F: FnMut()>(slot: Slot, image: &[u8], mut relinquish: F) -> Result<(), Error> {
+    if !slot.exists() {
+        return Err(Error::NoRoom { slot })
+    }
+    if image.len() > slot.size() {
+        return Err(Error::TooLarge { size: image.len() - HEADER_SIZE,
+                                     max:  slot.size() - HEADER_SIZE })
+    }
+
+    // Only erase what the new firmware needs; the rest of the slot is not read.
+    let mut offset = 0;
+    while offset < image.len() {
+        let end = cmp::min(offset + spiflash::SECTOR_SIZE, image.len());
+        unsafe {
+            spiflash::erase_sector(slot.address() + offset);
+            spiflash::write(slot.address() + offset, &image[offset..end]);
+        }
+        offset += spiflash::SECTOR_SIZE;
+        relinquish();
+    }
+    cache::flush_l2_cache();
+
+    firmware(slot).map(|_| ())
+}
+
+fn read_slot(key: &str) -> Option<Slot> {
+    config::read_str(key, |result| result.ok().and_then(Slot::from_name))
+}
+
+/// The slot that last booted successfully.
+pub fn active() -> Slot {
+    read_slot("boot_slot").unwrap_or(Slot::A)
+}
+
+/// The slot to try on the next boot, if any.
+pub fn pending() -> Option<Slot> {
+    read_slot("boot_pending")
+}
+
+/// The slot being tried on this boot, if it has not been confirmed yet.
+pub fn trial() -> Option<Slot> {
+    read_slot("boot_trial")
+}
+
+/// The slot that the running firmware was booted from.
+pub fn running() -> Slot {
+    trial().unwrap_or_else(active)
+}
+
+pub fn set_pending(slot: Slot) -> Result<(), Error> {
+    config::write("boot_pending", slot.name().as_bytes())?;
+    Ok(())
+}
+
+/// Called by the bootloader before booting the pending slot.
+pub fn begin_trial(slot: Slot) -> Result<(), Error> {
+    config::write("boot_trial", slot.name().as_bytes())?;
+    config::remove("boot_pending")?;
+    Ok(())
+}
+
+/// Called by the bootloader when the trial of a slot has failed.
+pub fn abandon_trial() -> Result<(), Error> {
+    config::remove("boot_trial")?;
+    Ok(())
+}
+
+/// Called by the firmware once it has started up; makes the slot being tried,
+/// if any, the active one and returns it.
+pub fn confirm() -> Result<Option<Slot>, Error> {
+    match trial() {
+        Some(slot) => {
+            config::write("boot_slot", slot.name().as_bytes())?;
+            config::remove("boot_trial")?;
+            Ok(Some(slot))
+        }
+        None => Ok(None)
+    }
+}
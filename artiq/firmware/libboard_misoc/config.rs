@@ -58,6 +58,43 @@ impl<'a> fmt::Write for FmtWrapper<'a> {
     }
 }
 
+/// Keys that record the state of the firmware rather than configure it, and
+/// that `erase` therefore keeps: which flash slot to boot, and the counter that
+/// keeps authentication challenges unique across reboots.
+pub const PRESERVED_KEYS: &'static [&'static str] =
+    &["boot_slot", "boot_pending", "boot_trial", "mgmt_epoch"];
+
+const MAX_SUBSCRIBERS: usize = 16;
+
+static mut SUBSCRIBERS: [Option<(&'static str, fn(&str))>; MAX_SUBSCRIBERS] =
+    [None; MAX_SUBSCRIBERS];
+
+/// Registers `handler` to be called with the key name after `key` is
+/// written, removed, or the whole configuration is erased.
+///
+/// Handlers are called synchronously from whichever context changed the
+/// configuration, after the change has been committed to flash.
+pub fn subscribe(key: &'static str, handler: fn(&str)) {
+    unsafe {
+        for subscriber in SUBSCRIBERS.iter_mut() {
+            if subscriber.is_none() {
+                *subscriber = Some((key, handler));
+                return
+            }
+        }
+    }
+    panic!("too many configuration subscribers")
+}
+
+fn notify(key: Option<&str>) {
+    let subscribers = unsafe { SUBSCRIBERS };
+    for &(subscribed_key, handler) in subscribers.iter().filter_map(|s| s.as_ref()) {
+        if key.map(|key| key == subscribed_key).unwrap_or(true) {
+            handler(subscribed_key)
+        }
+    }
+}
+
 #[cfg(has_spiflash)]
 mod imp {
     use core::str;
@@ -196,7 +233,9 @@ mod imp {
         Ok(offset)
     }
 
-    fn compact() -> Result<(), Error> {
+    // Rewrites the sector with the latest value of every key for which `keep`
+    // returns true.
+    fn compact_with<F: Fn(&[u8]) -> bool>(keep: F) -> Result<(), Error> {
         let lock = Lock::take()?;
         let data = lock.data();
 
@@ -207,6 +246,7 @@ mod imp {
         };
 
         unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
+        cache::flush_l2_cache();
 
         // This is worst-case quadratic, but we're limited by a small SPI flash sector size,
         // so it does not really matter.
@@ -214,8 +254,8 @@ mod imp {
         let mut iter = Iter::new(old_data);
         'iter: while let Some(result) = iter.next() {
             let (key, mut value) = result?;
-            if value.is_empty() {
-                // This is a removed entry, ignore it.
+            if value.is_empty() || !keep(key) {
+                // This is a removed entry, or one to drop; ignore it.
                 continue
             }
 
@@ -233,6 +273,10 @@ mod imp {
         Ok(())
     }
 
+    fn compact() -> Result<(), Error> {
+        compact_with(|_| true)
+    }
+
     fn append(key: &str, value: &[u8]) -> Result<(), Error> {
         let lock = Lock::take()?;
         let data = lock.data();
@@ -257,7 +301,10 @@ mod imp {
                 append(key, value)
             }
             res => res
-        }
+        }?;
+
+        super::notify(Some(key));
+        Ok(())
     }
 
     pub fn write_int(key: &str, value: u32) -> Result<(), Error> {
@@ -272,12 +319,18 @@ mod imp {
     }
 
     pub fn erase() -> Result<(), Error> {
-        let lock = Lock::take()?;
-        let data = lock.data();
-
-        unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
-        cache::flush_l2_cache();
+        let preserved = |key: &[u8]| {
+            super::PRESERVED_KEYS.iter().any(|preserved| preserved.as_bytes() == key)
+        };
+        // The sector is erased before the records are parsed; corrupted records
+        // are dropped along with every other key.
+        match compact_with(preserved) {
+            Ok(()) | Err(Error::Truncated { .. }) | Err(Error::InvalidSize { .. }) |
+                Err(Error::MissingSeparator { .. }) => (),
+            Err(err) => return Err(err)
+        }
 
+        super::notify(None);
         Ok(())
     }
 }
@@ -128,9 +128,9 @@ impl phy::TxToken for EthernetTxSlot {
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct EthernetStatistics {
-    rx_preamble_errors: u32,
-    rx_crc_errors:      u32,
-    rx_dropped:         u32,
+    pub rx_preamble_errors: u32,
+    pub rx_crc_errors:      u32,
+    pub rx_dropped:         u32,
 }
 
 impl EthernetStatistics {
@@ -2,6 +2,7 @@
 #![feature(asm, try_from)]
 
 extern crate byteorder;
+extern crate crc;
 #[cfg(feature = "log")]
 extern crate log;
 #[cfg(feature = "smoltcp")]
@@ -24,8 +25,12 @@ pub mod uart;
 #[cfg(has_spiflash)]
 pub mod spiflash;
 pub mod config;
+#[cfg(has_spiflash)]
+pub mod boot_slot;
 #[cfg(feature = "uart_console")]
 pub mod uart_console;
+#[cfg(all(feature = "uart_console", has_uart))]
+pub mod uart_shell;
 #[cfg(all(feature = "uart_console", feature = "log"))]
 pub mod uart_logger;
 #[cfg(all(has_ethmac, feature = "smoltcp"))]
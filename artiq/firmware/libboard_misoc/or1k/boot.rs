@@ -1,4 +1,9 @@
 use super::{irq, cache};
+use csr;
+
+/// The firmware is linked into a region of this size at the start of SDRAM,
+/// where the bootloader and `hotswap` copy it.
+pub const MAX_FIRMWARE_SIZE: usize = 4 * 1024 * 1024;
 
 pub unsafe fn reset() -> ! {
     irq::set_ie(false);
@@ -9,6 +14,11 @@ pub unsafe fn reset() -> ! {
     loop {}
 }
 
+/// Restarts from the CPU reset address, i.e. runs the bootloader again.
+pub unsafe fn reboot() -> ! {
+    jump(csr::CONFIG_CPU_RESET_ADDRESS as usize)
+}
+
 pub unsafe fn jump(addr: usize) -> ! {
     irq::set_ie(false);
     cache::flush_cpu_icache();
@@ -106,6 +106,10 @@ pub const SPR_PICMR:        u32 = SPRGROUP_PIC + 0;
 pub const SPR_PICPR:        u32 = SPRGROUP_PIC + 1;
 pub const SPR_PICSR:        u32 = SPRGROUP_PIC + 2;
 
+/* Tick Timer group */
+pub const SPR_TTMR:         u32 = SPRGROUP_TT + 0;
+pub const SPR_TTCR:         u32 = SPRGROUP_TT + 1;
+
 // [snip]
 
 /*
@@ -132,6 +136,15 @@ pub const SPR_SR_SUMRA:     u32 = 0x00010000;  /* Supervisor SPR read access */
 pub const SPR_SR_RES:       u32 = 0x0ffe0000;  /* Reserved */
 pub const SPR_SR_CID:       u32 = 0xf0000000;  /* Context ID */
 
+/*
+ * Bit definitions for the Tick Timer Mode Register
+ *
+ */
+pub const SPR_TTMR_TP:      u32 = 0x0fffffff;  /* Time Period */
+pub const SPR_TTMR_IP:      u32 = 0x10000000;  /* Interrupt Pending */
+pub const SPR_TTMR_IE:      u32 = 0x20000000;  /* Interrupt Enable */
+pub const SPR_TTMR_RT:      u32 = 0x40000000;  /* Restart tick */
+
 /*
  * Bit definitions for Data Cache Control register
  *
@@ -0,0 +1,131 @@
+use core::str;
+use csr;
+use config;
+
+const UART_EV_RX: u8 = 0x2;
+const MAX_LINE_LEN: usize = 256;
+
+/// Returns the next byte received on the UART, if any, without blocking.
+pub fn read_byte() -> Option<u8> {
+    unsafe {
+        if csr::uart::rxempty_read() != 0 {
+            None
+        } else {
+            let byte = csr::uart::rxtx_read();
+            csr::uart::ev_pending_write(UART_EV_RX);
+            Some(byte)
+        }
+    }
+}
+
+/// Accumulates UART input into a line, echoing it back and handling
+/// backspace and Ctrl-U; other control characters are ignored.
+pub struct LineEditor {
+    buf:      [u8; MAX_LINE_LEN],
+    len:      usize,
+    complete: bool
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor { buf: [0; MAX_LINE_LEN], len: 0, complete: false }
+    }
+
+    /// Feeds one byte of input; returns the line once Enter is pressed.
+    pub fn feed(&mut self, byte: u8) -> Option<&str> {
+        if self.complete {
+            self.len = 0;
+            self.complete = false;
+        }
+
+        match byte {
+            b'\r' | b'\n' => {
+                println!("");
+                self.complete = true;
+                // Only printable ASCII is ever stored.
+                return Some(str::from_utf8(&self.buf[..self.len]).unwrap())
+            }
+            0x08 | 0x7f => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            0x15 => {
+                while self.len > 0 {
+                    self.len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            0x20...0x7e if self.len < MAX_LINE_LEN => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                print!("{}", byte as char);
+            }
+            _ => ()
+        }
+        None
+    }
+}
+
+/// Splits off the first whitespace-separated word of `line`, returning it
+/// and the rest of the line with leading whitespace removed.
+pub fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim_left();
+    match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim_left()),
+        None => (line, "")
+    }
+}
+
+pub const CONFIG_HELP: &'static str = "\
+config read KEY          print the value of a configuration key
+config write KEY VALUE   set a configuration key
+config remove KEY        remove a configuration key
+config erase             remove all configuration keys";
+
+/// Runs `config read|write|remove|erase` with the given arguments. Keys for
+/// which `is_secret` returns true are never printed. `validate` is consulted
+/// before writing a value, and with an empty value before removing a key; it
+/// should explain why a change is rejected, if it is.
+pub fn config_command<S, V>(args: &str, is_secret: S, validate: V)
+        where S: Fn(&str) -> bool, V: FnOnce(&str, &[u8]) -> bool {
+    let (action, args) = split_word(args);
+    let (key, value) = split_word(args);
+    let result = match (action, key, value) {
+        ("read", key, "") if key != "" && is_secret(key) => {
+            println!("config key {:?} is write-only", key);
+            return
+        }
+        ("read", key, "") if key != "" => {
+            config::read(key, |result| {
+                result.map(|value| {
+                    match str::from_utf8(value) {
+                        Ok(value) => println!("{}", value),
+                        Err(_) => println!("({} bytes of binary data)", value.len())
+                    }
+                })
+            })
+        }
+        ("write", key, value) if key != "" && value != "" => {
+            if !validate(key, value.as_bytes()) {
+                return
+            }
+            config::write(key, value.as_bytes())
+        }
+        ("remove", key, "") if key != "" => {
+            if !validate(key, &[]) {
+                return
+            }
+            config::remove(key)
+        }
+        ("erase", "", "") => config::erase(),
+        _ => {
+            println!("{}", CONFIG_HELP);
+            return
+        }
+    };
+    if let Err(err) = result {
+        println!("configuration error: {}", err)
+    }
+}
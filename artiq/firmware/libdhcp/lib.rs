@@ -0,0 +1,437 @@
+#![no_std]
+
+extern crate byteorder;
+#[macro_use]
+extern crate log;
+extern crate smoltcp;
+
+use core::{cmp, fmt};
+use byteorder::{ByteOrder, NetworkEndian};
+use smoltcp::socket::UdpSocket;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr};
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+// Large enough for any reply that fits into an unfragmented Ethernet frame.
+pub const MAX_PACKET_SIZE: usize = 576;
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY:   u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE:   u32 = 0x63825363;
+
+// op, htype, hlen, hops, xid, secs, flags, ciaddr, yiaddr, siaddr, giaddr,
+// chaddr, sname, file, magic cookie
+const OPTIONS_OFFSET: usize = 240;
+// BOOTP relays may drop shorter packets.
+const MIN_PACKET_SIZE: usize = 300;
+
+const OPT_PAD:            u8 = 0;
+const OPT_SUBNET_MASK:    u8 = 1;
+const OPT_ROUTER:         u8 = 3;
+const OPT_REQUESTED_IP:   u8 = 50;
+const OPT_LEASE_TIME:     u8 = 51;
+const OPT_MESSAGE_TYPE:   u8 = 53;
+const OPT_SERVER_ID:      u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME:   u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID:      u8 = 61;
+const OPT_END:            u8 = 255;
+
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER:    u8 = 2;
+pub const DHCPREQUEST:  u8 = 3;
+pub const DHCPACK:      u8 = 5;
+pub const DHCPNAK:      u8 = 6;
+
+// Retransmissions back off exponentially from the first to the second value (RFC 2131 4.1).
+const RETRY_MIN_MS: u64 = 4_000;
+const RETRY_MAX_MS: u64 = 64_000;
+// While renewing, wait at least this long between requests.
+const RENEW_MIN_MS: u64 = 60_000;
+// Give up on an offer, and start over, after this many unanswered requests.
+const MAX_REQUESTS: u32 = 4;
+
+pub const INFINITE_LEASE: u32 = 0xffffffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address:   Ipv4Address,
+    pub netmask:   Option<Ipv4Address>,
+    pub router:    Option<Ipv4Address>,
+    pub server:    Ipv4Address,
+    // In seconds; INFINITE_LEASE if the lease does not expire.
+    pub duration:  u32,
+    // In seconds since the lease was obtained.
+    pub renewal:   u32,
+    pub rebinding: u32,
+}
+
+impl Lease {
+    /// The assigned address, with the prefix length given by the netmask;
+    /// without a netmask, every host is treated as on-link.
+    pub fn cidr(&self) -> Ipv4Cidr {
+        let prefix_len = match self.netmask {
+            Some(netmask) => (!NetworkEndian::read_u32(netmask.as_bytes())).leading_zeros(),
+            None => 0
+        };
+        Ipv4Cidr::new(self.address, prefix_len as u8)
+    }
+
+    // What the interface is configured with; the rest only matters to the client.
+    fn config(self) -> (Ipv4Address, Option<Ipv4Address>, Option<Ipv4Address>) {
+        (self.address, self.netmask, self.router)
+    }
+}
+
+impl fmt::Display for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        if let Some(netmask) = self.netmask {
+            write!(f, " netmask {}", netmask)?;
+        }
+        if let Some(router) = self.router {
+            write!(f, " router {}", router)?;
+        }
+        write!(f, " from {}", self.server)?;
+        if self.duration == INFINITE_LEASE {
+            write!(f, " (infinite lease)")
+        } else {
+            write!(f, " for {} s", self.duration)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A lease was obtained; the interface should use its address.
+    Configured(Lease),
+    /// The lease expired; the interface should no longer use its address.
+    Deconfigured
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Discovering,
+    Requesting { offer: Lease },
+    Bound      { lease: Lease, since_ms: u64 },
+    Renewing   { lease: Lease, since_ms: u64 },
+    Rebinding  { lease: Lease, since_ms: u64 },
+}
+
+pub struct Reply {
+    pub message_type: u8,
+    pub lease:        Lease
+}
+
+/// Parses a reply to the request with transaction ID `xid` sent from `hardware_addr`;
+/// returns `None` if `packet` is not such a reply, or is malformed.
+pub fn parse_reply(packet: &[u8], xid: u32, hardware_addr: EthernetAddress) -> Option<Reply> {
+    if packet.len() < OPTIONS_OFFSET ||
+            packet[0] != OP_BOOTREPLY ||
+            NetworkEndian::read_u32(&packet[4..]) != xid ||
+            &packet[28..34] != hardware_addr.as_bytes() ||
+            NetworkEndian::read_u32(&packet[236..]) != MAGIC_COOKIE {
+        return None
+    }
+
+    let mut message_type = None;
+    let mut lease = Lease {
+        address:   Ipv4Address::from_bytes(&packet[16..20]),
+        netmask:   None,
+        router:    None,
+        server:    Ipv4Address::from_bytes(&packet[20..24]),
+        duration:  INFINITE_LEASE,
+        renewal:   0,
+        rebinding: 0,
+    };
+
+    let mut options = &packet[OPTIONS_OFFSET..];
+    while let Some((&kind, rest)) = options.split_first() {
+        match kind {
+            OPT_PAD => { options = rest; continue }
+            OPT_END => break,
+            _ => ()
+        }
+        let (&length, rest) = rest.split_first()?;
+        if rest.len() < length as usize {
+            return None
+        }
+        let (data, rest) = rest.split_at(length as usize);
+        options = rest;
+
+        match (kind, data.len()) {
+            (OPT_MESSAGE_TYPE, 1) => message_type = Some(data[0]),
+            (OPT_SUBNET_MASK, 4) => lease.netmask = Some(Ipv4Address::from_bytes(data)),
+            (OPT_ROUTER, n) if n >= 4 => lease.router = Some(Ipv4Address::from_bytes(&data[..4])),
+            (OPT_SERVER_ID, 4) => lease.server = Ipv4Address::from_bytes(data),
+            (OPT_LEASE_TIME, 4) => lease.duration = NetworkEndian::read_u32(data),
+            (OPT_RENEWAL_TIME, 4) => lease.renewal = NetworkEndian::read_u32(data),
+            (OPT_REBINDING_TIME, 4) => lease.rebinding = NetworkEndian::read_u32(data),
+            _ => ()
+        }
+    }
+
+    if lease.duration != INFINITE_LEASE {
+        // Defaults from RFC 2131 4.4.5.
+        if lease.renewal == 0 || lease.renewal > lease.duration {
+            lease.renewal = lease.duration / 2
+        }
+        if lease.rebinding == 0 || lease.rebinding > lease.duration {
+            lease.rebinding = lease.duration / 8 * 7
+        }
+    }
+
+    Some(Reply { message_type: message_type?, lease })
+}
+
+pub struct Request<'a> {
+    pub message_type:  u8,
+    pub xid:           u32,
+    pub hardware_addr: EthernetAddress,
+    // Set while renewing or rebinding, when the client may use the address.
+    pub client_addr:   Option<Ipv4Address>,
+    // Set while requesting an offered address.
+    pub requested:     Option<&'a Lease>,
+}
+
+impl<'a> Request<'a> {
+    /// Writes the request into `buf`, which must be at least `MAX_PACKET_SIZE` long,
+    /// and returns its length.
+    pub fn emit(&self, buf: &mut [u8]) -> usize {
+        for byte in buf[..MIN_PACKET_SIZE].iter_mut() {
+            *byte = 0
+        }
+        buf[0] = OP_BOOTREQUEST;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6;
+        NetworkEndian::write_u32(&mut buf[4..], self.xid);
+        match self.client_addr {
+            Some(addr) => buf[12..16].copy_from_slice(addr.as_bytes()),
+            // We cannot receive unicast replies without an address.
+            None => NetworkEndian::write_u16(&mut buf[10..], FLAG_BROADCAST)
+        }
+        buf[28..34].copy_from_slice(self.hardware_addr.as_bytes());
+        NetworkEndian::write_u32(&mut buf[236..], MAGIC_COOKIE);
+
+        let mut offset = OPTIONS_OFFSET;
+        {
+            let mut option = |kind: u8, data: &[u8]| {
+                buf[offset] = kind;
+                buf[offset + 1] = data.len() as u8;
+                buf[offset + 2..offset + 2 + data.len()].copy_from_slice(data);
+                offset += 2 + data.len();
+            };
+            option(OPT_MESSAGE_TYPE, &[self.message_type]);
+            let mut client_id = [HTYPE_ETHERNET; 7];
+            client_id[1..].copy_from_slice(self.hardware_addr.as_bytes());
+            option(OPT_CLIENT_ID, &client_id);
+            if let Some(lease) = self.requested {
+                option(OPT_REQUESTED_IP, lease.address.as_bytes());
+                option(OPT_SERVER_ID, lease.server.as_bytes());
+            }
+            option(OPT_PARAMETER_LIST, &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_LEASE_TIME,
+                                         OPT_RENEWAL_TIME, OPT_REBINDING_TIME]);
+        }
+        buf[offset] = OPT_END;
+        cmp::max(offset + 1, MIN_PACKET_SIZE)
+    }
+}
+
+/// The UDP socket that `DhcpClient` communicates through.
+pub trait Socket {
+    /// Returns the next received datagram, if any.
+    fn recv(&mut self) -> Option<&[u8]>;
+
+    fn send(&mut self, packet: &[u8], destination: IpEndpoint) -> Result<(), ()>;
+}
+
+impl<'a, 'b> Socket for UdpSocket<'a, 'b> {
+    fn recv(&mut self) -> Option<&[u8]> {
+        UdpSocket::recv(self).ok().map(|(packet, _)| packet)
+    }
+
+    fn send(&mut self, packet: &[u8], destination: IpEndpoint) -> Result<(), ()> {
+        self.send_slice(packet, destination).map_err(|_| ())
+    }
+}
+
+/// A DHCPv4 client (RFC 2131). It does not do any networking by itself; it is
+/// polled with a UDP socket bound to `CLIENT_PORT`, and reports the address that
+/// the interface should use through `Event`s.
+pub struct DhcpClient {
+    hardware_addr: EthernetAddress,
+    state:         State,
+    xid:           u32,
+    retries:       u32,
+    next_send_ms:  u64,
+}
+
+impl DhcpClient {
+    pub fn new(hardware_addr: EthernetAddress, now_ms: u64) -> DhcpClient {
+        let mac = hardware_addr.as_bytes();
+        DhcpClient {
+            hardware_addr,
+            state:        State::Discovering,
+            // Only has to differ between clients, and between boots of a client.
+            xid:          NetworkEndian::read_u32(&mac[2..]) ^ (now_ms as u32),
+            retries:      0,
+            next_send_ms: now_ms,
+        }
+    }
+
+    /// The current lease, if any.
+    pub fn lease(&self) -> Option<Lease> {
+        match self.state {
+            State::Discovering | State::Requesting { .. } => None,
+            State::Bound { lease, .. } |
+            State::Renewing { lease, .. } |
+            State::Rebinding { lease, .. } => Some(lease)
+        }
+    }
+
+    fn transition(&mut self, state: State, now_ms: u64) {
+        self.state = state;
+        self.retries = 0;
+        self.next_send_ms = now_ms;
+    }
+
+    fn restart(&mut self, now_ms: u64) {
+        self.xid = self.xid.wrapping_add(1);
+        self.transition(State::Discovering, now_ms);
+    }
+
+    fn retry_delay(&self) -> u64 {
+        cmp::min(RETRY_MIN_MS << cmp::min(self.retries, 4), RETRY_MAX_MS)
+    }
+
+    pub fn poll<S: Socket>(&mut self, now_ms: u64, socket: &mut S) -> Option<Event> {
+        while let Some(packet) = socket.recv() {
+            let reply = match parse_reply(packet, self.xid, self.hardware_addr) {
+                Some(reply) => reply,
+                None => continue
+            };
+
+            match (self.state, reply.message_type) {
+                (State::Discovering, DHCPOFFER) => {
+                    debug!("offered {}", reply.lease);
+                    self.transition(State::Requesting { offer: reply.lease }, now_ms);
+                }
+                (State::Requesting { .. }, DHCPACK) |
+                (State::Renewing { .. }, DHCPACK) |
+                (State::Rebinding { .. }, DHCPACK) => {
+                    let previous = self.lease();
+                    info!("obtained IP address {}", reply.lease);
+                    self.transition(State::Bound { lease: reply.lease, since_ms: now_ms }, now_ms);
+                    // A renewal may also move us to a different router or subnet.
+                    if previous.map(Lease::config) != Some(reply.lease.config()) {
+                        return Some(Event::Configured(reply.lease))
+                    }
+                }
+                (State::Requesting { .. }, DHCPNAK) => {
+                    warn!("DHCP server declined our request; starting over");
+                    self.restart(now_ms);
+                }
+                (State::Renewing { .. }, DHCPNAK) |
+                (State::Rebinding { .. }, DHCPNAK) => {
+                    warn!("DHCP server revoked our lease; starting over");
+                    self.restart(now_ms);
+                    return Some(Event::Deconfigured)
+                }
+                _ => ()
+            }
+        }
+
+        // Move on through the lease timers (RFC 2131 4.4.5).
+        let state = self.state;
+        match state {
+            State::Bound { lease, since_ms } |
+            State::Renewing { lease, since_ms } |
+            State::Rebinding { lease, since_ms } if lease.duration != INFINITE_LEASE => {
+                let elapsed_s = (now_ms - since_ms) / 1000;
+                if elapsed_s >= lease.duration as u64 {
+                    warn!("DHCP lease of {} expired", lease.address);
+                    self.restart(now_ms);
+                    return Some(Event::Deconfigured)
+                }
+                match state {
+                    State::Bound { .. } if elapsed_s >= lease.renewal as u64 => {
+                        debug!("renewing DHCP lease of {}", lease.address);
+                        self.transition(State::Renewing { lease, since_ms }, now_ms)
+                    }
+                    State::Renewing { .. } if elapsed_s >= lease.rebinding as u64 => {
+                        warn!("DHCP server {} did not renew our lease; rebinding", lease.server);
+                        self.transition(State::Rebinding { lease, since_ms }, now_ms)
+                    }
+                    _ => ()
+                }
+            }
+            _ => ()
+        }
+
+        if now_ms < self.next_send_ms {
+            return None
+        }
+
+        let broadcast = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), SERVER_PORT);
+        let state = self.state;
+        let (request, destination) = match state {
+            State::Discovering => {
+                (Request { message_type: DHCPDISCOVER, xid: self.xid,
+                           hardware_addr: self.hardware_addr,
+                           client_addr: None, requested: None },
+                 broadcast)
+            }
+            State::Requesting { ref offer } => {
+                if self.retries == MAX_REQUESTS {
+                    debug!("no reply to DHCP request; starting over");
+                    self.restart(now_ms);
+                    return None
+                }
+                (Request { message_type: DHCPREQUEST, xid: self.xid,
+                           hardware_addr: self.hardware_addr,
+                           client_addr: None, requested: Some(offer) },
+                 broadcast)
+            }
+            State::Bound { .. } => return None,
+            State::Renewing { ref lease, .. } => {
+                (Request { message_type: DHCPREQUEST, xid: self.xid,
+                           hardware_addr: self.hardware_addr,
+                           client_addr: Some(lease.address), requested: None },
+                 IpEndpoint::new(IpAddress::Ipv4(lease.server), SERVER_PORT))
+            }
+            State::Rebinding { ref lease, .. } => {
+                (Request { message_type: DHCPREQUEST, xid: self.xid,
+                           hardware_addr: self.hardware_addr,
+                           client_addr: Some(lease.address), requested: None },
+                 broadcast)
+            }
+        };
+
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let size = request.emit(&mut packet);
+        match socket.send(&packet[..size], destination) {
+            Ok(()) => (),
+            // Try again on the next poll.
+            Err(_) => return None
+        }
+
+        self.next_send_ms = match state {
+            State::Renewing { lease, since_ms } | State::Rebinding { lease, since_ms } => {
+                // Halve the remaining time until the next deadline, down to RENEW_MIN_MS.
+                let deadline_s = match state {
+                    State::Renewing { .. } => lease.rebinding,
+                    _ => lease.duration
+                };
+                let deadline_ms = since_ms + deadline_s as u64 * 1000;
+                now_ms + cmp::max(deadline_ms.saturating_sub(now_ms) / 2, RENEW_MIN_MS)
+            }
+            _ => now_ms + self.retry_delay()
+        };
+        self.retries += 1;
+        None
+    }
+}
@@ -0,0 +1,359 @@
+extern crate byteorder;
+extern crate smoltcp;
+extern crate dhcp;
+
+use std::collections::VecDeque;
+use byteorder::{ByteOrder, NetworkEndian};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpEndpoint, Ipv4Address};
+use dhcp::*;
+
+const MAC: EthernetAddress = EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+const OTHER_MAC: EthernetAddress = EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+const ADDRESS: Ipv4Address = Ipv4Address([192, 168, 1, 70]);
+const NETMASK: Ipv4Address = Ipv4Address([255, 255, 255, 0]);
+const ROUTER: Ipv4Address = Ipv4Address([192, 168, 1, 1]);
+const SERVER: Ipv4Address = Ipv4Address([192, 168, 1, 2]);
+
+const XID: u32 = 0x12345678;
+
+// The lease offered in `ack()`.
+const LEASE_S: u32 = 3600;
+const RENEWAL_MS: u64 = 1_800_000;
+const REBINDING_MS: u64 = 3_150_000;
+
+#[derive(Default)]
+struct FakeSocket {
+    received: VecDeque<Vec<u8>>,
+    current:  Vec<u8>,
+    sent:     Vec<(Vec<u8>, IpEndpoint)>,
+}
+
+impl Socket for FakeSocket {
+    fn recv(&mut self) -> Option<&[u8]> {
+        self.current = self.received.pop_front()?;
+        Some(&self.current)
+    }
+
+    fn send(&mut self, packet: &[u8], destination: IpEndpoint) -> Result<(), ()> {
+        self.sent.push((packet.to_vec(), destination));
+        Ok(())
+    }
+}
+
+impl FakeSocket {
+    fn take_sent(&mut self) -> Vec<(Vec<u8>, IpEndpoint)> {
+        self.sent.drain(..).collect()
+    }
+}
+
+fn reply(message_type: u8, xid: u32, options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut packet = vec![0; 240];
+    packet[0] = 2; // BOOTREPLY
+    packet[1] = 1;
+    packet[2] = 6;
+    NetworkEndian::write_u32(&mut packet[4..], xid);
+    packet[16..20].copy_from_slice(ADDRESS.as_bytes());
+    packet[28..34].copy_from_slice(MAC.as_bytes());
+    NetworkEndian::write_u32(&mut packet[236..], 0x63825363);
+    packet.extend_from_slice(&[53, 1, message_type]);
+    for &(kind, data) in options {
+        packet.push(kind);
+        packet.push(data.len() as u8);
+        packet.extend_from_slice(data);
+    }
+    packet.push(255);
+    packet
+}
+
+fn ack(message_type: u8, xid: u32, router: Ipv4Address) -> Vec<u8> {
+    let mut duration = [0; 4];
+    NetworkEndian::write_u32(&mut duration, LEASE_S);
+    reply(message_type, xid, &[
+        (1, NETMASK.as_bytes()),
+        (3, router.as_bytes()),
+        (51, &duration),
+        (54, SERVER.as_bytes()),
+    ])
+}
+
+// (kind, data) of every option of a request
+fn options(packet: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut options = Vec::new();
+    let mut offset = 240;
+    while packet[offset] != 255 {
+        let length = packet[offset + 1] as usize;
+        options.push((packet[offset], packet[offset + 2..offset + 2 + length].to_vec()));
+        offset += 2 + length;
+    }
+    options
+}
+
+fn option(packet: &[u8], kind: u8) -> Option<Vec<u8>> {
+    options(packet).into_iter().find(|&(k, _)| k == kind).map(|(_, data)| data)
+}
+
+fn xid_of(packet: &[u8]) -> u32 {
+    NetworkEndian::read_u32(&packet[4..])
+}
+
+fn broadcast() -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), SERVER_PORT)
+}
+
+fn unicast() -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(SERVER), SERVER_PORT)
+}
+
+fn lease(router: Ipv4Address) -> Lease {
+    parse_reply(&ack(DHCPACK, XID, router), XID, MAC).unwrap().lease
+}
+
+// Goes through discover, offer, request and ack; the lease is obtained at 1 s.
+fn bind(client: &mut DhcpClient, socket: &mut FakeSocket) -> u32 {
+    assert_eq!(client.poll(0, socket), None);
+    let sent = socket.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(option(&sent[0].0, 53), Some(vec![DHCPDISCOVER]));
+    let xid = xid_of(&sent[0].0);
+
+    socket.received.push_back(ack(DHCPOFFER, xid, ROUTER));
+    assert_eq!(client.poll(500, socket), None);
+    let sent = socket.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, broadcast());
+    assert_eq!(option(&sent[0].0, 53), Some(vec![DHCPREQUEST]));
+    assert_eq!(option(&sent[0].0, 50), Some(ADDRESS.as_bytes().to_vec()));
+    assert_eq!(xid_of(&sent[0].0), xid);
+
+    socket.received.push_back(ack(DHCPACK, xid, ROUTER));
+    assert_eq!(client.poll(1000, socket), Some(Event::Configured(lease(ROUTER))));
+    assert_eq!(client.lease(), Some(lease(ROUTER)));
+    assert_eq!(socket.take_sent(), vec![]);
+    xid
+}
+
+#[test]
+fn parse_ack() {
+    let reply = parse_reply(&ack(DHCPACK, XID, ROUTER), XID, MAC).unwrap();
+    assert_eq!(reply.message_type, DHCPACK);
+    let lease = reply.lease;
+    assert_eq!(lease.address, ADDRESS);
+    assert_eq!(lease.netmask, Some(NETMASK));
+    assert_eq!(lease.router, Some(ROUTER));
+    assert_eq!(lease.server, SERVER);
+    assert_eq!(lease.duration, LEASE_S);
+    // RFC 2131 defaults
+    assert_eq!(lease.renewal, 1800);
+    assert_eq!(lease.rebinding, 3150);
+    assert_eq!(lease.cidr().prefix_len(), 24);
+}
+
+#[test]
+fn parse_timers() {
+    let packet = reply(DHCPACK, XID, &[
+        (51, &[0, 0, 0x0e, 0x10]),
+        (58, &[0, 0, 0x03, 0xe8]),
+        (59, &[0, 0, 0xff, 0xff]),
+    ]);
+    let lease = parse_reply(&packet, XID, MAC).unwrap().lease;
+    assert_eq!(lease.duration, 3600);
+    assert_eq!(lease.renewal, 1000);
+    // Longer than the lease itself.
+    assert_eq!(lease.rebinding, 3150);
+
+    let lease = parse_reply(&reply(DHCPACK, XID, &[]), XID, MAC).unwrap().lease;
+    assert_eq!(lease.duration, INFINITE_LEASE);
+    assert_eq!(lease.netmask, None);
+    assert_eq!(lease.router, None);
+    assert_eq!(lease.cidr().prefix_len(), 0);
+}
+
+#[test]
+fn parse_rejects() {
+    let packet = ack(DHCPACK, XID, ROUTER);
+    assert!(parse_reply(&packet, XID + 1, MAC).is_none());
+    assert!(parse_reply(&packet, XID, OTHER_MAC).is_none());
+    assert!(parse_reply(&packet[..239], XID, MAC).is_none());
+
+    let mut request = packet.clone();
+    request[0] = 1;
+    assert!(parse_reply(&request, XID, MAC).is_none());
+
+    let mut no_cookie = packet.clone();
+    no_cookie[236] = 0;
+    assert!(parse_reply(&no_cookie, XID, MAC).is_none());
+
+    // The last option runs past the end of the packet.
+    let mut truncated = packet.clone();
+    truncated.pop();
+    truncated.extend_from_slice(&[51, 4, 0]);
+    assert!(parse_reply(&truncated, XID, MAC).is_none());
+
+    let mut no_type = packet.clone();
+    no_type[240] = 0;
+    no_type[241] = 0;
+    no_type[242] = 0;
+    assert!(parse_reply(&no_type, XID, MAC).is_none());
+}
+
+#[test]
+fn emit_discover() {
+    let mut packet = [0xaa; MAX_PACKET_SIZE];
+    let size = Request {
+        message_type: DHCPDISCOVER, xid: XID, hardware_addr: MAC,
+        client_addr: None, requested: None
+    }.emit(&mut packet);
+    // Padded for BOOTP relays.
+    assert_eq!(size, 300);
+    let packet = &packet[..size];
+    assert_eq!(&packet[..4], &[1, 1, 6, 0]);
+    assert_eq!(xid_of(packet), XID);
+    // Broadcast flag, no client address.
+    assert_eq!(&packet[10..16], &[0x80, 0, 0, 0, 0, 0]);
+    assert_eq!(&packet[28..34], MAC.as_bytes());
+    assert_eq!(NetworkEndian::read_u32(&packet[236..]), 0x63825363);
+    assert_eq!(options(packet), vec![
+        (53, vec![DHCPDISCOVER]),
+        (61, vec![1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+        (55, vec![1, 3, 51, 58, 59]),
+    ]);
+}
+
+#[test]
+fn emit_request() {
+    let offer = lease(ROUTER);
+    let mut packet = [0; MAX_PACKET_SIZE];
+    let size = Request {
+        message_type: DHCPREQUEST, xid: XID, hardware_addr: MAC,
+        client_addr: None, requested: Some(&offer)
+    }.emit(&mut packet);
+    let packet = &packet[..size];
+    assert_eq!(&packet[10..16], &[0x80, 0, 0, 0, 0, 0]);
+    assert_eq!(option(packet, 53), Some(vec![DHCPREQUEST]));
+    assert_eq!(option(packet, 50), Some(ADDRESS.as_bytes().to_vec()));
+    assert_eq!(option(packet, 54), Some(SERVER.as_bytes().to_vec()));
+}
+
+#[test]
+fn emit_renewal() {
+    let mut packet = [0; MAX_PACKET_SIZE];
+    let size = Request {
+        message_type: DHCPREQUEST, xid: XID, hardware_addr: MAC,
+        client_addr: Some(ADDRESS), requested: None
+    }.emit(&mut packet);
+    let packet = &packet[..size];
+    assert_eq!(&packet[10..12], &[0, 0]);
+    assert_eq!(&packet[12..16], ADDRESS.as_bytes());
+    assert_eq!(option(packet, 50), None);
+    assert_eq!(option(packet, 54), None);
+}
+
+#[test]
+fn retransmit() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    assert_eq!(client.poll(0, &mut socket), None);
+    assert_eq!(socket.take_sent().len(), 1);
+    assert_eq!(client.poll(3999, &mut socket), None);
+    assert_eq!(socket.take_sent().len(), 0);
+    assert_eq!(client.poll(4000, &mut socket), None);
+    assert_eq!(socket.take_sent().len(), 1);
+    // Backs off.
+    assert_eq!(client.poll(11999, &mut socket), None);
+    assert_eq!(socket.take_sent().len(), 0);
+    assert_eq!(client.poll(12000, &mut socket), None);
+    assert_eq!(socket.take_sent().len(), 1);
+}
+
+#[test]
+fn ignores_other_clients() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    client.poll(0, &mut socket);
+    let xid = xid_of(&socket.take_sent()[0].0);
+
+    socket.received.push_back(ack(DHCPOFFER, xid + 1, ROUTER));
+    assert_eq!(client.poll(500, &mut socket), None);
+    assert_eq!(socket.take_sent(), vec![]);
+}
+
+#[test]
+fn renew() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    let xid = bind(&mut client, &mut socket);
+
+    assert_eq!(client.poll(1000 + RENEWAL_MS - 1, &mut socket), None);
+    assert_eq!(socket.take_sent(), vec![]);
+    assert_eq!(client.poll(1000 + RENEWAL_MS, &mut socket), None);
+    let sent = socket.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, unicast());
+    assert_eq!(&sent[0].0[12..16], ADDRESS.as_bytes());
+    assert_eq!(xid_of(&sent[0].0), xid);
+    assert_eq!(client.lease(), Some(lease(ROUTER)));
+
+    // The same lease again; nothing to reconfigure.
+    socket.received.push_back(ack(DHCPACK, xid, ROUTER));
+    assert_eq!(client.poll(1000 + RENEWAL_MS + 100, &mut socket), None);
+    assert_eq!(client.lease(), Some(lease(ROUTER)));
+    assert_eq!(client.poll(1000 + RENEWAL_MS + 200, &mut socket), None);
+    assert_eq!(socket.take_sent(), vec![]);
+}
+
+#[test]
+fn renew_with_new_router() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    let xid = bind(&mut client, &mut socket);
+
+    client.poll(1000 + RENEWAL_MS, &mut socket);
+    assert_eq!(socket.take_sent().len(), 1);
+    let router = Ipv4Address([192, 168, 1, 254]);
+    socket.received.push_back(ack(DHCPACK, xid, router));
+    assert_eq!(client.poll(1000 + RENEWAL_MS + 100, &mut socket),
+               Some(Event::Configured(lease(router))));
+    assert_eq!(client.lease(), Some(lease(router)));
+}
+
+#[test]
+fn renew_nak() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    let xid = bind(&mut client, &mut socket);
+
+    client.poll(1000 + RENEWAL_MS, &mut socket);
+    assert_eq!(socket.take_sent().len(), 1);
+    socket.received.push_back(reply(DHCPNAK, xid, &[]));
+    assert_eq!(client.poll(1000 + RENEWAL_MS + 100, &mut socket), Some(Event::Deconfigured));
+    assert_eq!(client.lease(), None);
+
+    // Starts over with a new transaction.
+    assert_eq!(client.poll(1000 + RENEWAL_MS + 200, &mut socket), None);
+    let sent = socket.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, broadcast());
+    assert_eq!(option(&sent[0].0, 53), Some(vec![DHCPDISCOVER]));
+    assert!(xid_of(&sent[0].0) != xid);
+}
+
+#[test]
+fn expire() {
+    let mut client = DhcpClient::new(MAC, 0);
+    let mut socket = FakeSocket::default();
+    bind(&mut client, &mut socket);
+
+    client.poll(1000 + RENEWAL_MS, &mut socket);
+    assert_eq!(socket.take_sent()[0].1, unicast());
+    // No reply from the server; ask any server instead.
+    assert_eq!(client.poll(1000 + REBINDING_MS, &mut socket), None);
+    let sent = socket.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, broadcast());
+    assert_eq!(&sent[0].0[12..16], ADDRESS.as_bytes());
+    assert_eq!(client.lease(), Some(lease(ROUTER)));
+
+    assert_eq!(client.poll(1000 + LEASE_S as u64 * 1000 - 1, &mut socket), None);
+    assert_eq!(client.poll(1000 + LEASE_S as u64 * 1000, &mut socket), Some(Event::Deconfigured));
+    assert_eq!(client.lease(), None);
+}
@@ -130,6 +130,27 @@ impl<'a> Write for &'a mut [u8] {
     }
 }
 
+/// A writer that discards all data and counts how much of it there was.
+#[derive(Debug, Default)]
+pub struct SizeCounter {
+    pub size: usize
+}
+
+impl Write for SizeCounter {
+    type WriteError = !;
+    type FlushError = !;
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::WriteError> {
+        self.size += buf.len();
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), Self::FlushError> {
+        Ok(())
+    }
+}
+
 #[cfg(feature = "alloc")]
 impl<'a> Write for alloc::Vec<u8> {
     type WriteError = !;
@@ -1,26 +1,416 @@
 #![no_std]
 
 extern crate log;
-extern crate log_buffer;
+#[cfg(feature = "board_misoc")]
 #[macro_use]
 extern crate board_misoc;
 
+use core::{cmp, str, fmt};
 use core::cell::{Cell, RefCell, RefMut};
 use core::fmt::Write;
-use log::{Log, LevelFilter};
-use log_buffer::LogBuffer;
-use board_misoc::clock;
+use log::{Log, Level, LevelFilter};
+
+pub mod syslog;
+
+// size: u16, level: u8, target length: u8, seq: u64, timestamp: u64
+const HEADER_SIZE: usize = 20;
+const MAX_RECORD_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord<'a> {
+    pub seq:       u64,
+    pub timestamp: u64,
+    pub level:     Level,
+    pub target:    &'a str,
+    pub message:   &'a str,
+}
+
+impl<'a> fmt::Display for LogRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:6}.{:06}s] {:>5}({}): {}",
+               self.timestamp / 1_000_000, self.timestamp % 1_000_000,
+               self.level, self.target, self.message)
+    }
+}
+
+// Without the board support crate, e.g. when running the tests on the host,
+// there is no clock and no UART, and records only go to the buffer.
+#[cfg(feature = "board_misoc")]
+fn uptime_us() -> u64 {
+    board_misoc::clock::get_us()
+}
+
+#[cfg(not(feature = "board_misoc"))]
+fn uptime_us() -> u64 {
+    0
+}
+
+#[cfg(feature = "board_misoc")]
+fn print_uart(timestamp: u64, record: &log::Record) {
+    println!("[{:6}.{:06}s] {:>5}({}): {}",
+             timestamp / 1_000_000, timestamp % 1_000_000,
+             record.level(), record.target(), record.args());
+}
+
+#[cfg(not(feature = "board_misoc"))]
+fn print_uart(_timestamp: u64, _record: &log::Record) {}
+
+fn level_to_u8(level: Level) -> u8 {
+    match level {
+        Level::Error => 1,
+        Level::Warn  => 2,
+        Level::Info  => 3,
+        Level::Debug => 4,
+        Level::Trace => 5,
+    }
+}
+
+fn level_from_u8(level: u8) -> Level {
+    match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn write_u16(data: &mut [u8], value: u16) {
+    data[0] = (value >> 8) as u8;
+    data[1] = value as u8;
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    data[..8].iter().fold(0, |acc, &byte| acc << 8 | byte as u64)
+}
+
+fn write_u64(data: &mut [u8], value: u64) {
+    for (index, byte) in data[..8].iter_mut().enumerate() {
+        *byte = (value >> (56 - 8 * index)) as u8
+    }
+}
+
+// Copies as much of `s` as fits, never splitting a UTF-8 sequence.
+struct TruncatingWriter<'a> {
+    buf:    &'a mut [u8],
+    offset: usize
+}
+
+impl<'a> fmt::Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut length = cmp::min(s.len(), self.buf.len() - self.offset);
+        while !s.is_char_boundary(length) {
+            length -= 1
+        }
+        self.buf[self.offset..self.offset + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.offset += length;
+        Ok(())
+    }
+}
+
+// Records are stored contiguously; a record that would not fit before the end
+// of the storage is placed at its beginning instead, and the remaining space
+// is marked with a zero size. The oldest records are evicted to make room.
+//
+// Every record carries its sequence number, since records that could not be
+// stored at all still consume one, and so the numbers may have gaps.
+pub struct LogRing {
+    storage:  &'static mut [u8],
+    head:     usize,
+    tail:     usize,
+    len:      usize,
+    next_seq: u64,
+}
+
+impl LogRing {
+    fn new(storage: &'static mut [u8]) -> LogRing {
+        LogRing { storage, head: 0, tail: 0, len: 0, next_seq: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+        self.len = 0;
+    }
+
+    // Accounts for `count` records that were logged but could not be stored.
+    fn skip(&mut self, count: u64) {
+        self.next_seq += count
+    }
+
+    fn is_wrap_point(&self, offset: usize) -> bool {
+        offset + HEADER_SIZE > self.storage.len() || read_u16(&self.storage[offset..]) == 0
+    }
+
+    fn evict(&mut self) {
+        if self.is_wrap_point(self.head) {
+            self.head = 0
+        }
+        self.head += read_u16(&self.storage[self.head..]) as usize;
+        self.len -= 1;
+        if !self.is_empty() && self.is_wrap_point(self.head) {
+            self.head = 0
+        }
+    }
+
+    fn reserve(&mut self, size: usize) {
+        loop {
+            if self.is_empty() {
+                self.head = 0;
+                self.tail = 0;
+            }
+
+            let free =
+                if self.is_empty() { self.storage.len() }
+                else if self.tail > self.head { self.storage.len() - self.tail }
+                else { self.head - self.tail };
+            if free >= size {
+                return
+            }
+
+            if !self.is_empty() && self.tail > self.head {
+                if self.tail + 2 <= self.storage.len() {
+                    write_u16(&mut self.storage[self.tail..], 0)
+                }
+                self.tail = 0
+            } else {
+                self.evict()
+            }
+        }
+    }
+
+    fn push(&mut self, timestamp: u64, level: Level, target: &str, args: fmt::Arguments) {
+        let max_size = cmp::min(MAX_RECORD_SIZE, self.storage.len() / 4);
+        if max_size <= HEADER_SIZE {
+            self.skip(1);
+            return
+        }
+        self.reserve(max_size);
+
+        let size = {
+            let record = &mut self.storage[self.tail..self.tail + max_size];
+
+            let mut target_len = cmp::min(target.len(), 255);
+            while !target.is_char_boundary(target_len) {
+                target_len -= 1
+            }
+            record[HEADER_SIZE..HEADER_SIZE + target_len]
+                .copy_from_slice(&target.as_bytes()[..target_len]);
+
+            let mut writer = TruncatingWriter {
+                buf:    &mut record[HEADER_SIZE + target_len..],
+                offset: 0
+            };
+            let _ = writer.write_fmt(args);
+            let size = HEADER_SIZE + target_len + writer.offset;
+
+            write_u16(&mut record[0..], size as u16);
+            record[2] = level_to_u8(level);
+            record[3] = target_len as u8;
+            write_u64(&mut record[4..], self.next_seq);
+            write_u64(&mut record[12..], timestamp);
+            size
+        };
+
+        self.tail += size;
+        self.len += 1;
+        self.next_seq += 1;
+    }
+
+    fn records(&self, from_seq: u64) -> Records {
+        let mut iter = Records { ring: self, offset: self.head, remaining: self.len };
+        while iter.peek_seq().map_or(false, |seq| seq < from_seq) {
+            iter.next();
+        }
+        iter
+    }
+}
+
+pub struct Records<'a> {
+    ring:      &'a LogRing,
+    offset:    usize,
+    remaining: usize
+}
+
+impl<'a> Records<'a> {
+    fn peek_seq(&mut self) -> Option<u64> {
+        if self.remaining == 0 {
+            return None
+        }
+        if self.ring.is_wrap_point(self.offset) {
+            self.offset = 0
+        }
+        Some(read_u64(&self.ring.storage[self.offset + 4..]))
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = LogRecord<'a>;
+
+    fn next(&mut self) -> Option<LogRecord<'a>> {
+        let seq = self.peek_seq()?;
+
+        let storage = &self.ring.storage[self.offset..];
+        let size       = read_u16(storage) as usize;
+        let target_len = storage[3] as usize;
+        let target  = &storage[HEADER_SIZE..HEADER_SIZE + target_len];
+        let message = &storage[HEADER_SIZE + target_len..size];
+        let record = LogRecord {
+            seq:       seq,
+            timestamp: read_u64(&storage[12..]),
+            level:     level_from_u8(storage[2]),
+            target:    str::from_utf8(target).unwrap_or("?"),
+            message:   str::from_utf8(message).unwrap_or("?"),
+        };
+
+        self.offset += size;
+        self.remaining -= 1;
+        Some(record)
+    }
+}
+
+// Per-target overrides of the log level, parsed from a specification such as
+// `runtime::rtio_mgt=debug,runtime::session=warn`. An override applies to its
+// target and to every module nested in it; the longest matching target wins.
+const MAX_FILTER_LEN: usize = 256;
+const MAX_FILTER_ENTRIES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    TooLong,
+    TooManyEntries,
+    MissingTarget,
+    MissingLevel,
+    UnknownLevel,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilterError::TooLong =>
+                write!(f, "filter is longer than {} bytes", MAX_FILTER_LEN),
+            FilterError::TooManyEntries =>
+                write!(f, "filter has more than {} entries", MAX_FILTER_ENTRIES),
+            FilterError::MissingTarget =>
+                write!(f, "filter entry has an empty target"),
+            FilterError::MissingLevel =>
+                write!(f, "filter entry is not of the form TARGET=LEVEL"),
+            FilterError::UnknownLevel =>
+                write!(f, "filter entry has an unknown log level"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct TargetFilter {
+    targets: [u8; MAX_FILTER_LEN],
+    // (offset into `targets`, length, level)
+    entries: [(usize, usize, LevelFilter); MAX_FILTER_ENTRIES],
+    count:   usize
+}
+
+impl TargetFilter {
+    pub fn empty() -> TargetFilter {
+        TargetFilter {
+            targets: [0; MAX_FILTER_LEN],
+            entries: [(0, 0, LevelFilter::Off); MAX_FILTER_ENTRIES],
+            count:   0
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<TargetFilter, FilterError> {
+        if spec.len() > MAX_FILTER_LEN {
+            return Err(FilterError::TooLong)
+        }
+
+        let mut filter = TargetFilter::empty();
+        let mut offset = 0;
+        for entry in spec.split(',').map(|entry| entry.trim()) {
+            if entry.is_empty() {
+                continue
+            }
+            if filter.count == MAX_FILTER_ENTRIES {
+                return Err(FilterError::TooManyEntries)
+            }
+
+            let mut parts = entry.splitn(2, '=');
+            let target = parts.next().unwrap().trim();
+            let level = parts.next().ok_or(FilterError::MissingLevel)?.trim();
+            if target.is_empty() {
+                return Err(FilterError::MissingTarget)
+            }
+            let level = level.parse::<LevelFilter>()
+                             .map_err(|_| FilterError::UnknownLevel)?;
+
+            filter.targets[offset..offset + target.len()].copy_from_slice(target.as_bytes());
+            filter.entries[filter.count] = (offset, target.len(), level);
+            filter.count += 1;
+            offset += target.len();
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn target(&self, index: usize) -> &[u8] {
+        let (offset, length, _) = self.entries[index];
+        &self.targets[offset..offset + length]
+    }
+
+    /// The override for `target`, if any.
+    pub fn level_for(&self, target: &str) -> Option<LevelFilter> {
+        let target = target.as_bytes();
+        let mut best: Option<(usize, LevelFilter)> = None;
+        for index in 0..self.count {
+            let prefix = self.target(index);
+            let matches =
+                target.starts_with(prefix) &&
+                (target.len() == prefix.len() || target[prefix.len()..].starts_with(b"::"));
+            if matches && best.map_or(true, |(length, _)| prefix.len() > length) {
+                best = Some((prefix.len(), self.entries[index].2))
+            }
+        }
+        best.map(|(_, level)| level)
+    }
+
+    /// The most verbose level of any override.
+    pub fn max_level(&self) -> LevelFilter {
+        self.entries[..self.count].iter()
+            .map(|&(_, _, level)| level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl fmt::Display for TargetFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for index in 0..self.count {
+            let target = str::from_utf8(self.target(index)).unwrap_or("?");
+            write!(f, "{}{}={}", if index == 0 { "" } else { "," },
+                   target, self.entries[index].2)?;
+        }
+        Ok(())
+    }
+}
 
 pub struct LogBufferRef<'a> {
-    buffer:        RefMut<'a, LogBuffer<&'static mut [u8]>>,
-    old_log_level: LevelFilter
+    buffer: RefMut<'a, LogRing>,
+    logger: &'a BufferLogger
 }
 
 impl<'a> LogBufferRef<'a> {
-    fn new(buffer: RefMut<'a, LogBuffer<&'static mut [u8]>>) -> LogBufferRef<'a> {
-        let old_log_level = log::max_level();
-        log::set_max_level(LevelFilter::Off);
-        LogBufferRef { buffer, old_log_level }
+    fn new(buffer: RefMut<'a, LogRing>, logger: &'a BufferLogger) -> LogBufferRef<'a> {
+        LogBufferRef { buffer, logger }
     }
 
     pub fn is_empty(&self) -> bool {
@@ -31,20 +421,37 @@ impl<'a> LogBufferRef<'a> {
         self.buffer.clear()
     }
 
-    pub fn extract(&mut self) -> &str {
-        self.buffer.extract()
+    /// Sequence number that the next logged record will receive.
+    pub fn next_seq(&self) -> u64 {
+        self.buffer.next_seq
+    }
+
+    /// Records still in the buffer, oldest first, starting at `from_seq`
+    /// or at the oldest record if that one has already been evicted.
+    /// Sequence numbers of records that were dropped are skipped.
+    pub fn records(&self, from_seq: u64) -> Records {
+        self.buffer.records(from_seq)
     }
 }
 
 impl<'a> Drop for LogBufferRef<'a> {
     fn drop(&mut self) {
-        log::set_max_level(self.old_log_level)
+        // Records logged while the buffer was held were dropped; make them
+        // show up as a gap in the sequence numbers.
+        self.buffer.skip(self.logger.dropped.replace(0))
     }
 }
 
+/// Keeps log records in a ring buffer, and also prints them on the UART.
+///
+/// While the buffer is held through `buffer()`, new records cannot be stored;
+/// they are dropped, but still consume a sequence number.
 pub struct BufferLogger {
-    buffer:      RefCell<LogBuffer<&'static mut [u8]>>,
-    uart_filter: Cell<LevelFilter>
+    buffer:        RefCell<LogRing>,
+    dropped:       Cell<u64>,
+    buffer_filter: Cell<LevelFilter>,
+    uart_filter:   Cell<LevelFilter>,
+    target_filter: RefCell<TargetFilter>
 }
 
 static mut LOGGER: *const BufferLogger = 0 as *const _;
@@ -52,8 +459,11 @@ static mut LOGGER: *const BufferLogger = 0 as *const _;
 impl BufferLogger {
     pub fn new(buffer: &'static mut [u8]) -> BufferLogger {
         BufferLogger {
-            buffer: RefCell::new(LogBuffer::new(buffer)),
+            buffer: RefCell::new(LogRing::new(buffer)),
+            dropped: Cell::new(0),
+            buffer_filter: Cell::new(LevelFilter::Info),
             uart_filter: Cell::new(LevelFilter::Info),
+            target_filter: RefCell::new(TargetFilter::empty()),
         }
     }
 
@@ -63,7 +473,7 @@ impl BufferLogger {
             log::set_logger(&*LOGGER)
                 .expect("global logger can only be initialized once");
         }
-        log::set_max_level(LevelFilter::Info);
+        self.update_max_level();
         f();
     }
 
@@ -74,10 +484,33 @@ impl BufferLogger {
     pub fn buffer<'a>(&'a self) -> Result<LogBufferRef<'a>, ()> {
         self.buffer
             .try_borrow_mut()
-            .map(LogBufferRef::new)
+            .map(|buffer| LogBufferRef::new(buffer, self))
             .map_err(|_| ())
     }
 
+    /// Like `LogBufferRef::next_seq`, but without taking exclusive access;
+    /// returns `None` if the buffer is currently borrowed.
+    pub fn next_seq(&self) -> Option<u64> {
+        self.buffer
+            .try_borrow()
+            .map(|buffer| buffer.next_seq)
+            .ok()
+    }
+
+    fn update_max_level(&self) {
+        log::set_max_level(cmp::max(self.buffer_filter.get(),
+                                    self.target_filter.borrow().max_level()))
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.buffer_filter.get()
+    }
+
+    pub fn set_log_level(&self, max_level: LevelFilter) {
+        self.buffer_filter.set(max_level);
+        self.update_max_level()
+    }
+
     pub fn uart_log_level(&self) -> LevelFilter {
         self.uart_filter.get()
     }
@@ -85,32 +518,55 @@ impl BufferLogger {
     pub fn set_uart_log_level(&self, max_level: LevelFilter) {
         self.uart_filter.set(max_level)
     }
+
+    pub fn target_filter(&self) -> TargetFilter {
+        *self.target_filter.borrow()
+    }
+
+    /// Overrides both the buffer and the UART log level for the targets
+    /// named in `filter`; other targets keep using the global levels.
+    pub fn set_target_filter(&self, filter: TargetFilter) {
+        *self.target_filter.borrow_mut() = filter;
+        self.update_max_level()
+    }
+
+    // (buffer level, UART level) that apply to `target`
+    fn levels_for(&self, target: &str) -> (LevelFilter, LevelFilter) {
+        let level = self.target_filter.try_borrow().ok()
+                        .and_then(|filter| filter.level_for(target));
+        match level {
+            Some(level) => (level, level),
+            None => (self.buffer_filter.get(),
+                     cmp::min(self.buffer_filter.get(), self.uart_filter.get()))
+        }
+    }
 }
 
 // required for impl Log
 unsafe impl Sync for BufferLogger {}
 
 impl Log for BufferLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let (buffer_level, uart_level) = self.levels_for(metadata.target());
+        metadata.level() <= cmp::max(buffer_level, uart_level)
     }
 
     fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            let timestamp = clock::get_us();
-            let seconds   = timestamp / 1_000_000;
-            let micros    = timestamp % 1_000_000;
-
-            if let Ok(mut buffer) = self.buffer.try_borrow_mut() {
-                writeln!(buffer, "[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args()).unwrap();
-            }
+        let (buffer_level, uart_level) = self.levels_for(record.target());
+        let timestamp = uptime_us();
 
-            if record.level() <= self.uart_filter.get() {
-                println!("[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args());
+        if record.level() <= buffer_level {
+            match self.buffer.try_borrow_mut() {
+                Ok(mut buffer) =>
+                    buffer.push(timestamp, record.level(), record.target(), *record.args()),
+                Err(_) =>
+                    self.dropped.set(self.dropped.get() + 1)
             }
         }
+
+        if record.level() <= uart_level {
+            print_uart(timestamp, record)
+        }
     }
 
     fn flush(&self) {
@@ -0,0 +1,155 @@
+use core::{cmp, str, fmt};
+use core::fmt::Write;
+use log::{Level, LevelFilter};
+use {BufferLogger, LogRecord, TruncatingWriter};
+
+// RFC 5424 recommends that receivers accept at least 480 bytes, and that they
+// should accept 2048; stay well within that.
+const MAX_MESSAGE_SIZE: usize = 1024;
+const MAX_MSGID_LEN: usize = 32;
+const APP_NAME: &'static str = "artiq";
+const FACILITY_USER: u8 = 1;
+
+// At most RATE records per second are forwarded, with bursts of up to BURST.
+const RATE:  u64 = 50;
+const BURST: u64 = 25;
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn  => 4,
+        Level::Info  => 6,
+        Level::Debug => 7,
+        Level::Trace => 7,
+    }
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    let mut length = cmp::min(s.len(), max_len);
+    while !s.is_char_boundary(length) {
+        length -= 1
+    }
+    &s[..length]
+}
+
+/// Formats an RFC 5424 message. The core device has no wall clock, so the
+/// timestamp is left out and the uptime is prepended to the message instead.
+pub fn format(buf: &mut [u8], hostname: &fmt::Display, record: &LogRecord) -> usize {
+    let mut writer = TruncatingWriter { buf, offset: 0 };
+    let msgid = if record.target.is_empty() { "-" }
+                else { truncate(record.target, MAX_MSGID_LEN) };
+    let _ = write!(writer, "<{}>1 - {} {} - {} - [{:6}.{:06}s] {}",
+                   FACILITY_USER * 8 + severity(record.level),
+                   hostname, APP_NAME, msgid,
+                   record.timestamp / 1_000_000, record.timestamp % 1_000_000,
+                   record.message);
+    writer.offset
+}
+
+/// Forwards records from the log buffer to a syslog server. The forwarder does
+/// not do any networking by itself; `poll` hands each formatted message to a
+/// caller-provided function, which e.g. queues it on a UDP socket.
+pub struct SyslogForwarder {
+    level:       LevelFilter,
+    next_seq:    u64,
+    lost:        u64,
+    tokens:      u64,
+    last_refill: u64,
+}
+
+impl SyslogForwarder {
+    /// Creates a forwarder that starts with the oldest record still in the buffer,
+    /// so that messages logged before the network came up are sent too.
+    pub fn new(level: LevelFilter) -> SyslogForwarder {
+        SyslogForwarder { level, next_seq: 0, lost: 0, tokens: BURST, last_refill: 0 }
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: LevelFilter) {
+        self.level = level
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        let earned = now_ms.saturating_sub(self.last_refill) * RATE / 1000;
+        if earned > 0 {
+            self.tokens = cmp::min(self.tokens + earned, BURST);
+            self.last_refill += earned * 1000 / RATE;
+        }
+        if self.tokens == BURST {
+            self.last_refill = now_ms
+        }
+    }
+
+    /// Forwards as many new records as the rate limit allows. `send` returns
+    /// `Err(())` if it cannot accept a message right now; the message is then
+    /// retried on the next call. Records that are evicted from the buffer before
+    /// they could be forwarded are reported as lost.
+    pub fn poll<F>(&mut self, logger: &BufferLogger, hostname: &fmt::Display,
+                   now_ms: u64, mut send: F)
+            where F: FnMut(&[u8]) -> Result<(), ()> {
+        // Taking the buffer is not free; don't do it on every iteration of
+        // the main loop if there is nothing new.
+        if logger.next_seq().map_or(true, |seq| seq <= self.next_seq) {
+            return
+        }
+
+        self.refill(now_ms);
+        if self.tokens == 0 {
+            return
+        }
+
+        let buffer = match logger.buffer() {
+            Ok(buffer) => buffer,
+            Err(()) => return
+        };
+
+        let mut message = [0; MAX_MESSAGE_SIZE];
+        for record in buffer.records(self.next_seq) {
+            if record.seq > self.next_seq {
+                self.lost += record.seq - self.next_seq;
+                self.next_seq = record.seq;
+            }
+
+            if self.lost > 0 {
+                if self.tokens == 0 {
+                    return
+                }
+                let mut text = [0; 64];
+                let text_len = {
+                    let mut writer = TruncatingWriter { buf: &mut text, offset: 0 };
+                    let _ = write!(writer, "{} log messages were not forwarded", self.lost);
+                    writer.offset
+                };
+                let notice = LogRecord {
+                    seq:       record.seq,
+                    timestamp: record.timestamp,
+                    level:     Level::Warn,
+                    target:    "logger_artiq::syslog",
+                    message:   str::from_utf8(&text[..text_len]).unwrap()
+                };
+                let size = format(&mut message, hostname, &notice);
+                if send(&message[..size]).is_err() {
+                    return
+                }
+                self.tokens -= 1;
+                self.lost = 0;
+            }
+
+            if record.level <= self.level {
+                if self.tokens == 0 {
+                    return
+                }
+                let size = format(&mut message, hostname, &record);
+                if send(&message[..size]).is_err() {
+                    return
+                }
+                self.tokens -= 1;
+            }
+
+            self.next_seq = record.seq + 1;
+        }
+    }
+}
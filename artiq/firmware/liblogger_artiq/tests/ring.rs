@@ -0,0 +1,131 @@
+extern crate log;
+extern crate logger_artiq;
+
+use log::{Level, Log, Record};
+use logger_artiq::BufferLogger;
+
+fn logger(size: usize) -> BufferLogger {
+    BufferLogger::new(Box::leak(vec![0; size].into_boxed_slice()))
+}
+
+fn log(logger: &BufferLogger, index: u64) {
+    logger.log(&Record::builder()
+        .level(Level::Info)
+        .target("test")
+        .args(format_args!("record {}", index))
+        .build())
+}
+
+// (seq, message) of every record from `from_seq` on
+fn records(logger: &BufferLogger, from_seq: u64) -> Vec<(u64, String)> {
+    let buffer = logger.buffer().unwrap();
+    let records = buffer.records(from_seq)
+        .map(|record| (record.seq, String::from(record.message)))
+        .collect();
+    records
+}
+
+#[test]
+fn in_order() {
+    let logger = logger(1024);
+    for index in 0..3 {
+        log(&logger, index)
+    }
+    assert_eq!(records(&logger, 0), vec![
+        (0, "record 0".to_string()),
+        (1, "record 1".to_string()),
+        (2, "record 2".to_string()),
+    ]);
+    assert_eq!(logger.next_seq(), Some(3));
+
+    let buffer = logger.buffer().unwrap();
+    let record = buffer.records(0).next().unwrap();
+    assert_eq!(record.level, Level::Info);
+    assert_eq!(record.target, "test");
+}
+
+#[test]
+fn wraps_and_evicts_oldest() {
+    let logger = logger(256);
+    for index in 0..100 {
+        log(&logger, index);
+
+        let records = records(&logger, 0);
+        assert_eq!(records.last().unwrap().0, index);
+        for (offset, &(seq, ref message)) in records.iter().enumerate() {
+            assert_eq!(seq, records[0].0 + offset as u64);
+            assert_eq!(*message, format!("record {}", seq));
+        }
+    }
+    let records = records(&logger, 0);
+    assert!(records[0].0 > 0);
+    assert!(records.len() > 1);
+}
+
+#[test]
+fn from_seq() {
+    let logger = logger(256);
+    for index in 0..100 {
+        log(&logger, index)
+    }
+    let oldest = records(&logger, 0)[0].0;
+
+    // Evicted records are skipped.
+    assert_eq!(records(&logger, oldest - 1)[0].0, oldest);
+    assert_eq!(records(&logger, 98), vec![
+        (98, "record 98".to_string()),
+        (99, "record 99".to_string()),
+    ]);
+    assert_eq!(records(&logger, 100), vec![]);
+    assert_eq!(records(&logger, 1000), vec![]);
+}
+
+#[test]
+fn dropped_while_held() {
+    let logger = logger(1024);
+    log(&logger, 0);
+    {
+        let _buffer = logger.buffer().unwrap();
+        log(&logger, 1);
+        log(&logger, 2);
+    }
+    log(&logger, 3);
+    assert_eq!(records(&logger, 0), vec![
+        (0, "record 0".to_string()),
+        (3, "record 3".to_string()),
+    ]);
+    assert_eq!(records(&logger, 1), vec![
+        (3, "record 3".to_string()),
+    ]);
+    assert_eq!(logger.next_seq(), Some(4));
+}
+
+#[test]
+fn clear_keeps_seq() {
+    let logger = logger(1024);
+    log(&logger, 0);
+    log(&logger, 1);
+    logger.buffer().unwrap().clear();
+    assert_eq!(records(&logger, 0), vec![]);
+
+    log(&logger, 2);
+    assert_eq!(records(&logger, 0), vec![
+        (2, "record 2".to_string()),
+    ]);
+}
+
+#[test]
+fn truncates_long_messages() {
+    let logger = logger(256);
+    let message = "\u{2603}".repeat(100);
+    logger.log(&Record::builder()
+        .level(Level::Warn)
+        .target("test")
+        .args(format_args!("{}", message))
+        .build());
+
+    let records = records(&logger, 0);
+    assert_eq!(records.len(), 1);
+    // 256 / 4 bytes per record, minus the header and target
+    assert_eq!(records[0].1, "\u{2603}".repeat((64 - 20 - 4) / 3));
+}
@@ -0,0 +1,151 @@
+extern crate log;
+extern crate logger_artiq;
+
+use std::str;
+use std::net::UdpSocket;
+use log::{Level, LevelFilter, Log, Record};
+use logger_artiq::{BufferLogger, LogRecord};
+use logger_artiq::syslog::{self, SyslogForwarder};
+
+const HOSTNAME: &'static str = "192.168.1.70";
+
+fn logger(size: usize) -> BufferLogger {
+    BufferLogger::new(Box::leak(vec![0; size].into_boxed_slice()))
+}
+
+fn log(logger: &BufferLogger, level: Level, index: u64) {
+    logger.log(&Record::builder()
+        .level(level)
+        .target("runtime::test")
+        .args(format_args!("record {}", index))
+        .build())
+}
+
+fn poll(forwarder: &mut SyslogForwarder, logger: &BufferLogger, now_ms: u64) -> Vec<String> {
+    let mut messages = Vec::new();
+    forwarder.poll(logger, &HOSTNAME, now_ms, |message| {
+        messages.push(String::from(str::from_utf8(message).unwrap()));
+        Ok(())
+    });
+    messages
+}
+
+#[test]
+fn format() {
+    let record = LogRecord {
+        seq:       7,
+        timestamp: 12_000_345,
+        level:     Level::Warn,
+        target:    "runtime::rtio_mgt",
+        message:   "RTIO clock failed"
+    };
+    let mut buf = [0; 256];
+    let size = syslog::format(&mut buf, &HOSTNAME, &record);
+    assert_eq!(str::from_utf8(&buf[..size]).unwrap(),
+               "<12>1 - 192.168.1.70 artiq - runtime::rtio_mgt - \
+                [    12.000345s] RTIO clock failed");
+}
+
+#[test]
+fn format_truncates() {
+    let record = LogRecord {
+        seq:       0,
+        timestamp: 0,
+        level:     Level::Error,
+        target:    "",
+        message:   &"x".repeat(100)
+    };
+    let mut buf = [0; 64];
+    let size = syslog::format(&mut buf, &HOSTNAME, &record);
+    assert_eq!(size, 64);
+    assert!(str::from_utf8(&buf).unwrap().starts_with("<11>1 - 192.168.1.70 artiq - - "));
+}
+
+#[test]
+fn filters_by_level() {
+    let logger = logger(4096);
+    log(&logger, Level::Info, 0);
+    log(&logger, Level::Warn, 1);
+    log(&logger, Level::Error, 2);
+
+    let mut forwarder = SyslogForwarder::new(LevelFilter::Warn);
+    let messages = poll(&mut forwarder, &logger, 0);
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].starts_with("<12>1 ") && messages[0].ends_with("record 1"));
+    assert!(messages[1].starts_with("<11>1 ") && messages[1].ends_with("record 2"));
+
+    // Nothing is forwarded twice.
+    assert_eq!(poll(&mut forwarder, &logger, 0), Vec::<String>::new());
+}
+
+#[test]
+fn rate_limit() {
+    let logger = logger(16384);
+    for index in 0..100 {
+        log(&logger, Level::Info, index)
+    }
+
+    let mut forwarder = SyslogForwarder::new(LevelFilter::Info);
+    // A full burst goes out at once...
+    assert_eq!(poll(&mut forwarder, &logger, 0).len(), 25);
+    assert_eq!(poll(&mut forwarder, &logger, 0).len(), 0);
+    // ... and then one record every 20 ms.
+    assert_eq!(poll(&mut forwarder, &logger, 19).len(), 0);
+    assert_eq!(poll(&mut forwarder, &logger, 20).len(), 1);
+    assert_eq!(poll(&mut forwarder, &logger, 100).len(), 4);
+    // The bucket never holds more than a burst.
+    let messages = poll(&mut forwarder, &logger, 10_000);
+    assert_eq!(messages.len(), 25);
+    assert!(messages[0].ends_with("record 30"));
+}
+
+#[test]
+fn retries_unsent() {
+    let logger = logger(4096);
+    log(&logger, Level::Info, 0);
+    log(&logger, Level::Info, 1);
+
+    let mut forwarder = SyslogForwarder::new(LevelFilter::Info);
+    forwarder.poll(&logger, &HOSTNAME, 0, |_| Err(()));
+    let messages = poll(&mut forwarder, &logger, 0);
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].ends_with("record 0"));
+}
+
+#[test]
+fn loss_notice() {
+    let logger = logger(256);
+    let mut forwarder = SyslogForwarder::new(LevelFilter::Info);
+    log(&logger, Level::Info, 0);
+    assert_eq!(poll(&mut forwarder, &logger, 0).len(), 1);
+
+    // Overflow the buffer before the forwarder gets to run again.
+    for index in 1..50 {
+        log(&logger, Level::Info, index)
+    }
+    let oldest = logger.buffer().unwrap().records(0).next().unwrap().seq;
+    let messages = poll(&mut forwarder, &logger, 10_000);
+    assert!(messages[0].starts_with("<12>1 - 192.168.1.70 artiq - logger_artiq::syslog - "));
+    assert!(messages[0].ends_with(&format!("{} log messages were not forwarded", oldest - 1)));
+    assert!(messages[1].ends_with(&format!("record {}", oldest)));
+    assert_eq!(messages.len() as u64, 1 + 50 - oldest);
+}
+
+#[test]
+fn udp_listener() {
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.connect(listener.local_addr().unwrap()).unwrap();
+
+    let logger = logger(4096);
+    log(&logger, Level::Error, 0);
+    let mut forwarder = SyslogForwarder::new(LevelFilter::Info);
+    forwarder.poll(&logger, &HOSTNAME, 0, |message| {
+        sender.send(message).map(|_| ()).map_err(|_| ())
+    });
+
+    let mut datagram = [0; 1024];
+    let size = listener.recv(&mut datagram).unwrap();
+    assert_eq!(str::from_utf8(&datagram[..size]).unwrap(),
+               "<11>1 - 192.168.1.70 artiq - runtime::test - [     0.000000s] record 0");
+}
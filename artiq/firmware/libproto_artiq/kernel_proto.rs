@@ -6,6 +6,12 @@ pub const KERNELCPU_PAYLOAD_ADDRESS: usize = 0x40840000;
 pub const KERNELCPU_LAST_ADDRESS:    usize = 0x4fffffff;
 pub const KSUPPORT_HEADER_SIZE:      usize = 0x80;
 
+// A profiling sample is the interrupted program counter and, if edges are being
+// recorded, the return address of the interrupted function, or else zero.
+// The first word of the ring buffer counts the samples ever written; sample `n`
+// follows at `1 + (n % len) * PROFILE_SAMPLE_WORDS`.
+pub const PROFILE_SAMPLE_WORDS: usize = 2;
+
 #[derive(Debug, Clone)]
 pub struct Exception<'a> {
     pub name:     &'a str,
@@ -22,6 +28,15 @@ pub enum Message<'a> {
     LoadRequest(&'a [u8]),
     LoadReply(Result<(), dyld::Error<'a>>),
 
+    // Sent before LoadRequest to make the kernel CPU sample its program counter
+    // every `period` cycles into a ring buffer of `len` samples; see PROFILE_*.
+    ProfileStart {
+        period:  u32,
+        edges:   bool,
+        samples: *mut usize,
+        len:     usize
+    },
+
     NowInitRequest,
     NowInitReply(u64),
     NowSave(u64),
@@ -28,3 +28,6 @@ pub mod moninj_proto;
 #[cfg(feature = "alloc")]
 pub mod session_proto;
 pub mod rpc_proto;
+
+// Data formats.
+pub mod pprof;
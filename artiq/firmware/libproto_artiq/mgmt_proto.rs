@@ -50,13 +50,18 @@ pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
 
 #[derive(Debug)]
 pub enum Request {
+    GetChallenge,
+    Authenticate { response: Vec<u8> },
+
     GetLog,
     ClearLog,
-    PullLog,
+    PullLog { from_seq: u64 },
     #[cfg(feature = "log")]
     SetLogFilter(log::LevelFilter),
     #[cfg(feature = "log")]
     SetUartLogFilter(log::LevelFilter),
+    SetLogTargetFilter(String),
+    GetLastCrash,
 
     ConfigRead   { key: String },
     ConfigWrite  { key: String, value: Vec<u8> },
@@ -67,30 +72,95 @@ pub enum Request {
         interval_us: u32,
         hits_size: u32,
         edges_size: u32,
+        kernel_cpu: bool,
     },
     StopProfiler,
     GetProfile,
+    GetProfilePprof,
 
     Hotswap(Vec<u8>),
+    FlashFirmware(Vec<u8>),
+    GetFirmwareSlots,
     Reboot,
 
+    GetNetworkStats,
+    StartCapture { buffer_size: u32, snap_len: u32 },
+    StopCapture,
+    GetCapture,
+
+    GetHeapStats,
     DebugAllocator,
+
+    GetThreads,
+}
+
+// The numeric values are part of the protocol; never renumber them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Other                   = 0,
+    Unavailable             = 1,
+    UnknownRequest          = 2,
+    InvalidArgument         = 3,
+    Unauthorized            = 4,
+    AuthenticationFailed    = 5,
+    OutOfMemory             = 6,
+
+    ConfigAlreadyLocked     = 16,
+    ConfigSpaceExhausted    = 17,
+    ConfigCorrupted         = 18,
+    ConfigUtf8              = 19,
+    ConfigRejected          = 20,
+
+    InvalidFirmware         = 32,
+    UnauthenticatedFirmware = 33,
+    FlashWriteFailed        = 34,
 }
 
 pub enum Reply<'a> {
     Success,
-    Error,
-    Unavailable,
+    Error { code: ErrorCode, message: &'a str },
+
+    Challenge(&'a [u8]),
 
     LogContent(&'a str),
+    #[cfg(feature = "log")]
+    LogRecord {
+        seq:       u64,
+        timestamp: u64,
+        level:     log::Level,
+        target:    &'a str,
+        message:   &'a str
+    },
+
+    LastCrash(&'a str),
 
     ConfigData(&'a [u8]),
 
-    Profile,
+    Profile { kernel_cpu: bool },
+    ProfilePprof(u32),
+
+    // Slots are numbered from 0; NO_SLOT means none.
+    FirmwareSlots { running: u8, active: u8, pending: u8 },
+
+    NetworkStats,
+    Capture(u32),
+
+    HeapStats {
+        busy:         u32,
+        idle:         u32,
+        meta:         u32,
+        largest_idle: u32,
+        blocks:       u32,
+        peak_busy:    u32,
+    },
+
+    Threads,
 
     RebootImminent,
 }
 
+pub const NO_SLOT: u8 = 0xff;
+
 impl Request {
     pub fn read_from<R>(reader: &mut R) -> Result<Self, Error<R::ReadError>>
         where R: Read + ?Sized
@@ -110,13 +180,22 @@ impl Request {
         }
 
         Ok(match reader.read_u8()? {
+            20 => Request::GetChallenge,
+            21 => Request::Authenticate {
+                response: reader.read_bytes()?
+            },
+
             1  => Request::GetLog,
             2  => Request::ClearLog,
-            7  => Request::PullLog,
+            7  => Request::PullLog {
+                from_seq: reader.read_u64()?
+            },
             #[cfg(feature = "log")]
             3 => Request::SetLogFilter(read_log_level_filter(reader)?),
             #[cfg(feature = "log")]
             6 => Request::SetUartLogFilter(read_log_level_filter(reader)?),
+            16 => Request::SetLogTargetFilter(reader.read_string()?),
+            17 => Request::GetLastCrash,
 
             12 => Request::ConfigRead {
                 key: reader.read_string()?
@@ -134,18 +213,51 @@ impl Request {
                 interval_us: reader.read_u32()?,
                 hits_size: reader.read_u32()?,
                 edges_size: reader.read_u32()?,
+                kernel_cpu: reader.read_bool()?,
             },
             10 => Request::StopProfiler,
             11 => Request::GetProfile,
+            27 => Request::GetProfilePprof,
 
             4 => Request::Hotswap(reader.read_bytes()?),
+            18 => Request::FlashFirmware(reader.read_bytes()?),
+            19 => Request::GetFirmwareSlots,
             5 => Request::Reboot,
 
+            22 => Request::GetNetworkStats,
+            23 => Request::StartCapture {
+                buffer_size: reader.read_u32()?,
+                snap_len: reader.read_u32()?,
+            },
+            24 => Request::StopCapture,
+            25 => Request::GetCapture,
+
+            26 => Request::GetHeapStats,
             8 => Request::DebugAllocator,
 
+            28 => Request::GetThreads,
+
             ty => return Err(Error::UnknownPacket(ty))
         })
     }
+
+    /// Whether the request only reads state of the core device, and may be
+    /// made before the client has authenticated itself.
+    pub fn is_read_only(&self) -> bool {
+        match *self {
+            Request::GetChallenge |
+            Request::Authenticate { .. } |
+            Request::GetLog |
+            Request::PullLog { .. } |
+            Request::GetLastCrash |
+            Request::ConfigRead { .. } |
+            Request::GetFirmwareSlots |
+            Request::GetNetworkStats |
+            Request::GetHeapStats |
+            Request::GetThreads => true,
+            _ => false
+        }
+    }
 }
 
 impl<'a> Reply<'a> {
@@ -156,28 +268,84 @@ impl<'a> Reply<'a> {
             Reply::Success => {
                 writer.write_u8(1)?;
             }
-            Reply::Error => {
+            Reply::Error { code, message } => {
                 writer.write_u8(6)?;
+                writer.write_u32(code as u32)?;
+                writer.write_string(message)?;
             }
 
-            Reply::Unavailable => {
-                writer.write_u8(4)?;
+            Reply::Challenge(nonce) => {
+                writer.write_u8(11)?;
+                writer.write_bytes(nonce)?;
             }
 
             Reply::LogContent(ref log) => {
                 writer.write_u8(2)?;
                 writer.write_string(log)?;
             }
+            #[cfg(feature = "log")]
+            Reply::LogRecord { seq, timestamp, level, target, message } => {
+                writer.write_u8(8)?;
+                writer.write_u64(seq)?;
+                writer.write_u64(timestamp)?;
+                writer.write_u8(level as u8)?;
+                writer.write_string(target)?;
+                writer.write_string(message)?;
+            }
+
+            Reply::LastCrash(ref report) => {
+                writer.write_u8(9)?;
+                writer.write_string(report)?;
+            }
 
             Reply::ConfigData(ref bytes) => {
                 writer.write_u8(7)?;
                 writer.write_bytes(bytes)?;
             },
 
-            Reply::Profile => {
+            Reply::Profile { kernel_cpu } => {
                 writer.write_u8(5)?;
+                writer.write_bool(kernel_cpu)?;
                 // profile data follows
             }
+            Reply::ProfilePprof(size) => {
+                writer.write_u8(15)?;
+                writer.write_u32(size)?;
+                // pprof data follows
+            }
+
+            Reply::FirmwareSlots { running, active, pending } => {
+                writer.write_u8(10)?;
+                writer.write_u8(running)?;
+                writer.write_u8(active)?;
+                writer.write_u8(pending)?;
+            }
+
+            Reply::NetworkStats => {
+                writer.write_u8(12)?;
+                // statistics follow
+            }
+
+            Reply::Capture(size) => {
+                writer.write_u8(13)?;
+                writer.write_u32(size)?;
+                // pcap data follows
+            }
+
+            Reply::HeapStats { busy, idle, meta, largest_idle, blocks, peak_busy } => {
+                writer.write_u8(14)?;
+                writer.write_u32(busy)?;
+                writer.write_u32(idle)?;
+                writer.write_u32(meta)?;
+                writer.write_u32(largest_idle)?;
+                writer.write_u32(blocks)?;
+                writer.write_u32(peak_busy)?;
+            }
+
+            Reply::Threads => {
+                writer.write_u8(16)?;
+                // thread list follows
+            }
 
             Reply::RebootImminent => {
                 writer.write_u8(3)?;
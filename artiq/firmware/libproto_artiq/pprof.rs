@@ -0,0 +1,175 @@
+// Encoder for profiles in the pprof protobuf format, as understood by `pprof`,
+// `go tool pprof` and the flamegraph tools that import it. See
+// https://github.com/google/pprof/blob/master/proto/profile.proto.
+//
+// The encoder writes each message as soon as it is given, so a profile can be
+// streamed without buffering it; use `io::SizeCounter` to find out its size first.
+
+use io::{Write, Error as IoError};
+
+// Wire types.
+const VARINT:    u64 = 0;
+const LENGTH:    u64 = 2;
+
+// Fields of the Profile message.
+const PROFILE_SAMPLE_TYPE:  u64 = 1;
+const PROFILE_SAMPLE:       u64 = 2;
+const PROFILE_MAPPING:      u64 = 3;
+const PROFILE_LOCATION:     u64 = 4;
+const PROFILE_STRING_TABLE: u64 = 6;
+const PROFILE_PERIOD_TYPE:  u64 = 11;
+const PROFILE_PERIOD:       u64 = 12;
+const PROFILE_COMMENT:      u64 = 13;
+
+// Indices into the string table, which is written in the header;
+// the string at index 0 is always empty.
+const STR_SAMPLES:     u64 = 1;
+const STR_COUNT:       u64 = 2;
+const STR_CPU:         u64 = 3;
+const STR_NANOSECONDS: u64 = 4;
+const STR_BINARY:      u64 = 5;
+const STR_IDENT:       u64 = 6;
+
+// All locations belong to the single mapping, which covers the whole address space.
+const MAPPING_ID: u64 = 1;
+
+pub struct Header<'a> {
+    /// Name of the profiled binary, e.g. `runtime` or `kernel`.
+    pub binary:    &'a str,
+    /// Software identifier of the profiled binary; stored as its build ID
+    /// and as a comment.
+    pub ident:     &'a str,
+    /// Sampling period.
+    pub period_ns: u64,
+}
+
+fn varint_size(mut value: u64) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+fn varint_field_size(field: u64, value: u64) -> usize {
+    varint_size(field << 3) + varint_size(value)
+}
+
+fn length_field_size(field: u64, length: usize) -> usize {
+    varint_size(field << 3) + varint_size(length as u64) + length
+}
+
+pub struct Encoder<W: Write> {
+    writer:    W,
+    period_ns: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W) -> Encoder<W> {
+        Encoder { writer: writer, period_ns: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn varint(&mut self, mut value: u64) -> Result<(), IoError<W::WriteError>> {
+        let mut buf = [0; 10];
+        let mut size = 0;
+        loop {
+            buf[size] = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 { break }
+            buf[size] |= 0x80;
+            size += 1;
+        }
+        self.writer.write_all(&buf[..size + 1])
+    }
+
+    fn varint_field(&mut self, field: u64, value: u64) -> Result<(), IoError<W::WriteError>> {
+        self.varint(field << 3 | VARINT)?;
+        self.varint(value)
+    }
+
+    fn length_field(&mut self, field: u64, length: usize) -> Result<(), IoError<W::WriteError>> {
+        self.varint(field << 3 | LENGTH)?;
+        self.varint(length as u64)
+    }
+
+    fn string(&mut self, field: u64, value: &str) -> Result<(), IoError<W::WriteError>> {
+        self.length_field(field, value.len())?;
+        self.writer.write_all(value.as_bytes())
+    }
+
+    fn value_type(&mut self, field: u64, type_: u64, unit: u64)
+                 -> Result<(), IoError<W::WriteError>> {
+        self.length_field(field, varint_field_size(1, type_) + varint_field_size(2, unit))?;
+        self.varint_field(1, type_)?;
+        self.varint_field(2, unit)
+    }
+
+    /// Writes the sample types, the mapping, and the string table. Must be called
+    /// before any samples are written.
+    pub fn header(&mut self, header: &Header) -> Result<(), IoError<W::WriteError>> {
+        self.period_ns = header.period_ns;
+
+        self.value_type(PROFILE_SAMPLE_TYPE, STR_SAMPLES, STR_COUNT)?;
+        self.value_type(PROFILE_SAMPLE_TYPE, STR_CPU, STR_NANOSECONDS)?;
+        self.value_type(PROFILE_PERIOD_TYPE, STR_CPU, STR_NANOSECONDS)?;
+        self.varint_field(PROFILE_PERIOD, header.period_ns)?;
+        self.varint_field(PROFILE_COMMENT, STR_IDENT)?;
+
+        let memory_limit = 1 << 32;
+        self.length_field(PROFILE_MAPPING,
+                          varint_field_size(1, MAPPING_ID) +
+                          varint_field_size(3, memory_limit) +
+                          varint_field_size(5, STR_BINARY) +
+                          varint_field_size(6, STR_IDENT))?;
+        self.varint_field(1, MAPPING_ID)?;
+        self.varint_field(3, memory_limit)?;
+        self.varint_field(5, STR_BINARY)?;
+        self.varint_field(6, STR_IDENT)?;
+
+        let strings = ["", "samples", "count", "cpu", "nanoseconds",
+                       header.binary, header.ident];
+        for string in strings.iter() {
+            self.string(PROFILE_STRING_TABLE, string)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a location at `address`. Its ID is the address itself, so every
+    /// address must be written exactly once.
+    pub fn location(&mut self, address: u32) -> Result<(), IoError<W::WriteError>> {
+        let address = address as u64;
+        self.length_field(PROFILE_LOCATION,
+                          varint_field_size(1, address) +
+                          varint_field_size(2, MAPPING_ID) +
+                          varint_field_size(3, address))?;
+        self.varint_field(1, address)?;
+        self.varint_field(2, MAPPING_ID)?;
+        self.varint_field(3, address)
+    }
+
+    /// Writes a sample that was taken `count` times with the call stack `stack`,
+    /// given as location addresses starting with the innermost one.
+    pub fn sample(&mut self, stack: &[u32], count: u32) -> Result<(), IoError<W::WriteError>> {
+        let values = [count as u64, count as u64 * self.period_ns];
+        let locations_size = stack.iter().map(|&addr| varint_size(addr as u64)).sum();
+        let values_size = values.iter().map(|&value| varint_size(value)).sum();
+
+        self.length_field(PROFILE_SAMPLE,
+                          length_field_size(1, locations_size) +
+                          length_field_size(2, values_size))?;
+        self.length_field(1, locations_size)?;
+        for &addr in stack {
+            self.varint(addr as u64)?;
+        }
+        self.length_field(2, values_size)?;
+        for &value in values.iter() {
+            self.varint(value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+extern crate io;
+extern crate proto_artiq;
+
+use std::fmt;
+use io::{Write, Cursor, SizeCounter};
+use proto_artiq::pprof::{Encoder, Header};
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Varint(u64),
+    Bytes(Vec<u8>),
+}
+
+fn varint(data: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[0];
+        *data = &data[1..];
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value
+        }
+        shift += 7;
+    }
+}
+
+// Decodes a message into (field, value) pairs, checking that every
+// length-delimited field fits into it exactly.
+fn decode(mut data: &[u8]) -> Vec<(u64, Value)> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = varint(&mut data);
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut data)),
+            2 => {
+                let length = varint(&mut data) as usize;
+                assert!(length <= data.len(), "field {} overruns the message", key >> 3);
+                let (bytes, rest) = data.split_at(length);
+                data = rest;
+                Value::Bytes(bytes.to_vec())
+            }
+            wire_type => panic!("unexpected wire type {}", wire_type)
+        };
+        fields.push((key >> 3, value));
+    }
+    fields
+}
+
+fn decode_packed(mut data: &[u8]) -> Vec<u64> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        values.push(varint(&mut data))
+    }
+    values
+}
+
+fn submessages(fields: &[(u64, Value)], field: u64) -> Vec<Vec<(u64, Value)>> {
+    fields.iter()
+        .filter(|&&(f, _)| f == field)
+        .map(|&(_, ref value)| match *value {
+            Value::Bytes(ref bytes) => decode(bytes),
+            ref value => panic!("field {} is {:?}, not a message", field, value)
+        })
+        .collect()
+}
+
+fn strings(fields: &[(u64, Value)]) -> Vec<String> {
+    fields.iter()
+        .filter(|&&(field, _)| field == 6)
+        .map(|&(_, ref value)| match *value {
+            Value::Bytes(ref bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            ref value => panic!("string is {:?}", value)
+        })
+        .collect()
+}
+
+const HEADER: Header<'static> = Header {
+    binary:    "runtime",
+    ident:     "7.0.ab1234cd;kasli",
+    period_ns: 1_000_000,
+};
+
+// Writes the header and the given locations and samples.
+fn write_profile<W: Write>(writer: W, locations: &[u32], samples: &[(&[u32], u32)]) -> W
+        where W::WriteError: fmt::Debug {
+    let mut encoder = Encoder::new(writer);
+    encoder.header(&HEADER).unwrap();
+    for &address in locations {
+        encoder.location(address).unwrap();
+    }
+    for &(stack, count) in samples {
+        encoder.sample(stack, count).unwrap();
+    }
+    encoder.into_inner()
+}
+
+fn encode(locations: &[u32], samples: &[(&[u32], u32)]) -> Vec<(u64, Value)> {
+    let mut buf = [0; 4096];
+    let size = write_profile(Cursor::new(&mut buf[..]), locations, samples).position();
+    assert!(size < buf.len());
+    decode(&buf[..size])
+}
+
+#[test]
+fn header() {
+    let profile = encode(&[], &[]);
+    let strings = strings(&profile);
+    assert_eq!(strings, ["", "samples", "count", "cpu", "nanoseconds",
+                         "runtime", "7.0.ab1234cd;kasli"]);
+    let string = |value: &Value| match *value {
+        Value::Varint(index) => strings[index as usize].as_str(),
+        ref value => panic!("string index is {:?}", value)
+    };
+
+    let sample_types = submessages(&profile, 1);
+    assert_eq!(sample_types.len(), 2);
+    assert_eq!((string(&sample_types[0][0].1), string(&sample_types[0][1].1)),
+               ("samples", "count"));
+    assert_eq!((string(&sample_types[1][0].1), string(&sample_types[1][1].1)),
+               ("cpu", "nanoseconds"));
+    let period_type = submessages(&profile, 11);
+    assert_eq!(period_type.len(), 1);
+    assert_eq!((string(&period_type[0][0].1), string(&period_type[0][1].1)),
+               ("cpu", "nanoseconds"));
+    assert!(profile.contains(&(12, Value::Varint(1_000_000))));
+    assert!(profile.contains(&(13, Value::Varint(6))));
+
+    let mapping = submessages(&profile, 3);
+    assert_eq!(mapping, vec![vec![
+        (1, Value::Varint(1)),
+        (3, Value::Varint(1 << 32)),
+        (5, Value::Varint(5)),
+        (6, Value::Varint(6)),
+    ]]);
+    assert_eq!(string(&mapping[0][2].1), "runtime");
+}
+
+#[test]
+fn location() {
+    let profile = encode(&[0x1234, 0x4000_0100], &[]);
+    let locations = submessages(&profile, 4);
+    assert_eq!(locations, vec![
+        vec![(1, Value::Varint(0x1234)), (2, Value::Varint(1)), (3, Value::Varint(0x1234))],
+        vec![(1, Value::Varint(0x4000_0100)), (2, Value::Varint(1)),
+             (3, Value::Varint(0x4000_0100))],
+    ]);
+}
+
+#[test]
+fn sample() {
+    let profile = encode(&[0x1234, 0x100], &[(&[0x1234, 0x100], 3), (&[0x100], 1)]);
+    let samples = submessages(&profile, 2);
+    assert_eq!(samples.len(), 2);
+    let packed = |sample: &[(u64, Value)], field: u64| {
+        match sample.iter().find(|&&(f, _)| f == field) {
+            Some(&(_, Value::Bytes(ref bytes))) => decode_packed(bytes),
+            field => panic!("packed field is {:?}", field)
+        }
+    };
+    assert_eq!(packed(&samples[0], 1), [0x1234, 0x100]);
+    assert_eq!(packed(&samples[0], 2), [3, 3_000_000]);
+    assert_eq!(packed(&samples[1], 1), [0x100]);
+    assert_eq!(packed(&samples[1], 2), [1, 1_000_000]);
+}
+
+#[test]
+fn deep_sample() {
+    // Long enough for the lengths to take more than one byte.
+    let stack: Vec<u32> = (0..100).map(|index| 0x4000_0000 + index * 4).collect();
+    let profile = encode(&stack, &[(&stack, 1)]);
+    let samples = submessages(&profile, 2);
+    assert_eq!(samples.len(), 1);
+    match samples[0][0] {
+        (1, Value::Bytes(ref bytes)) => {
+            assert_eq!(bytes.len(), 500);
+            assert_eq!(decode_packed(bytes),
+                       stack.iter().map(|&address| address as u64).collect::<Vec<_>>());
+        }
+        ref field => panic!("locations are {:?}", field)
+    }
+}
+
+#[test]
+fn size() {
+    let stack = [0x4000_0100, 0x4000_0000, 0x100];
+    let samples: &[(&[u32], u32)] = &[(&stack, 1000), (&stack[1..], 1)];
+    let counter = write_profile(SizeCounter::default(), &stack, samples);
+
+    let mut buf = [0; 4096];
+    let cursor = write_profile(Cursor::new(&mut buf[..]), &stack, samples);
+    assert_eq!(counter.size, cursor.position());
+}
@@ -11,10 +11,18 @@ const UW_REG_SP: c_int = -2;
 
 pub fn backtrace<F>(f: F) -> Result<(), uw::_Unwind_Reason_Code>
     where F: FnMut(usize) -> ()
+{
+    backtrace_limit(usize::max_value(), f)
+}
+
+/// Like `backtrace`, but stops after `max_frames` frames.
+pub fn backtrace_limit<F>(max_frames: usize, f: F) -> Result<(), uw::_Unwind_Reason_Code>
+    where F: FnMut(usize) -> ()
 {
     struct TraceContext<F> {
         step_fn: F,
-        prev_sp: uw::_Unwind_Word
+        prev_sp: uw::_Unwind_Word,
+        frames_left: usize
     }
 
     extern fn trace<F>(context: *mut uw::_Unwind_Context, arg: *mut c_void)
@@ -32,13 +40,18 @@ pub fn backtrace<F>(f: F) -> Result<(), uw::_Unwind_Reason_Code>
                 trace_context.prev_sp = cur_sp;
             }
 
+            if trace_context.frames_left == 0 {
+                return uw::_URC_END_OF_STACK
+            }
+            trace_context.frames_left -= 1;
+
             (trace_context.step_fn)(uw::_Unwind_GetIP(context));
             uw::_URC_NO_REASON
         }
     }
 
     unsafe {
-        let mut trace_context = TraceContext { step_fn: f, prev_sp: 0 };
+        let mut trace_context = TraceContext { step_fn: f, prev_sp: 0, frames_left: max_frames };
         match uw::_Unwind_Backtrace(trace::<F>, &mut trace_context as *mut _ as *mut c_void) {
             uw::_URC_NO_REASON => Ok(()),
             err => Err(err)
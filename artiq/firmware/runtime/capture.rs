@@ -0,0 +1,207 @@
+use core::cmp;
+use alloc::Vec;
+use alloc::alloc::{alloc_zeroed, Layout};
+use byteorder::{ByteOrder, NativeEndian, BigEndian};
+use board_misoc::clock;
+
+pub const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Ethernet frames are at most 1514 bytes without the FCS, which the MAC strips.
+const MAX_SNAP_LEN: usize = 1514;
+
+// timestamp_us: u64, orig_len: u16, incl_len: u16
+const RECORD_HEADER_SIZE: usize = 12;
+
+// See https://wiki.wireshark.org/Development/LibpcapFileFormat.
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "capture buffer size must be between {} and {} bytes",
+           RECORD_HEADER_SIZE, MAX_BUFFER_SIZE)]
+    InvalidBufferSize,
+    #[fail(display = "snapshot length must not be zero")]
+    InvalidSnapLen,
+    #[fail(display = "not enough memory for a capture buffer of {} bytes", _0)]
+    OutOfMemory(usize),
+}
+
+/// A ring buffer of the most recent Ethernet frames; when it is full, the oldest
+/// frames are discarded to make room.
+pub struct Capture {
+    buffer:   Vec<u8>,
+    snap_len: usize,
+    head:     usize,
+    used:     usize,
+    frames:   usize,
+    dropped:  usize,
+}
+
+impl Capture {
+    fn new(buffer_size: usize, snap_len: usize) -> Result<Capture, Error> {
+        // Unlike `vec![0; buffer_size]`, which panics if the heap is too fragmented
+        // for a buffer this large.
+        let buffer = unsafe {
+            let layout = Layout::from_size_align(buffer_size, 1)
+                .map_err(|_| Error::InvalidBufferSize)?;
+            let ptr = alloc_zeroed(layout);
+            if ptr.is_null() {
+                return Err(Error::OutOfMemory(buffer_size))
+            }
+            Vec::from_raw_parts(ptr, buffer_size, buffer_size)
+        };
+        Ok(Capture {
+            buffer:   buffer,
+            snap_len: cmp::min(snap_len, MAX_SNAP_LEN),
+            head:     0,
+            used:     0,
+            frames:   0,
+            dropped:  0,
+        })
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = self.buffer[(self.head + offset + index) % self.buffer.len()]
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        let length = self.buffer.len();
+        for (index, &byte) in data.iter().enumerate() {
+            self.buffer[(self.head + offset + index) % length] = byte
+        }
+    }
+
+    fn record_header(&self, offset: usize) -> (u64, usize, usize) {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.read(offset, &mut header);
+        (NativeEndian::read_u64(&header[0..]),
+         NativeEndian::read_u16(&header[8..]) as usize,
+         NativeEndian::read_u16(&header[10..]) as usize)
+    }
+
+    fn push(&mut self, timestamp_us: u64, frame: &[u8]) {
+        let incl_len = cmp::min(frame.len(), self.snap_len);
+        let size = RECORD_HEADER_SIZE + incl_len;
+        if size > self.buffer.len() {
+            self.dropped += 1;
+            return
+        }
+
+        while self.buffer.len() - self.used < size {
+            let (_, _, oldest_len) = self.record_header(0);
+            let oldest_size = RECORD_HEADER_SIZE + oldest_len;
+            self.head = (self.head + oldest_size) % self.buffer.len();
+            self.used -= oldest_size;
+            self.frames -= 1;
+            self.dropped += 1;
+        }
+
+        let mut header = [0; RECORD_HEADER_SIZE];
+        NativeEndian::write_u64(&mut header[0..], timestamp_us);
+        NativeEndian::write_u16(&mut header[8..], frame.len() as u16);
+        NativeEndian::write_u16(&mut header[10..], incl_len as u16);
+        let offset = self.used;
+        self.write(offset, &header);
+        self.write(offset + RECORD_HEADER_SIZE, &frame[..incl_len]);
+        self.used += size;
+        self.frames += 1;
+    }
+
+    /// The number of frames that were captured, but discarded for lack of space.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The size of the capture in libpcap format.
+    pub fn pcap_size(&self) -> usize {
+        PCAP_HEADER_SIZE + self.used +
+            self.frames * (PCAP_RECORD_HEADER_SIZE - RECORD_HEADER_SIZE)
+    }
+
+    /// Converts the capture to libpcap format, piece by piece, passing each
+    /// piece to `f`. Timestamps count from startup, i.e. from 1970-01-01.
+    pub fn to_pcap<F, E>(&self, mut f: F) -> Result<(), E>
+            where F: FnMut(&[u8]) -> Result<(), E> {
+        let mut header = [0; PCAP_HEADER_SIZE];
+        BigEndian::write_u32(&mut header[0..], PCAP_MAGIC);
+        BigEndian::write_u16(&mut header[4..], 2);
+        BigEndian::write_u16(&mut header[6..], 4);
+        // thiszone and sigfigs are zero
+        BigEndian::write_u32(&mut header[16..], self.snap_len as u32);
+        BigEndian::write_u32(&mut header[20..], LINKTYPE_ETHERNET);
+        f(&header)?;
+
+        let mut offset = 0;
+        // The management thread has a small stack.
+        let mut frame = vec![0; self.snap_len];
+        while offset < self.used {
+            let (timestamp_us, orig_len, incl_len) = self.record_header(offset);
+            let mut header = [0; PCAP_RECORD_HEADER_SIZE];
+            BigEndian::write_u32(&mut header[0..], (timestamp_us / 1_000_000) as u32);
+            BigEndian::write_u32(&mut header[4..], (timestamp_us % 1_000_000) as u32);
+            BigEndian::write_u32(&mut header[8..], incl_len as u32);
+            BigEndian::write_u32(&mut header[12..], orig_len as u32);
+            f(&header)?;
+
+            self.read(offset + RECORD_HEADER_SIZE, &mut frame[..incl_len]);
+            f(&frame[..incl_len])?;
+            offset += RECORD_HEADER_SIZE + incl_len;
+        }
+        Ok(())
+    }
+}
+
+static mut CAPTURE: Option<Capture> = None;
+static mut RUNNING: bool = false;
+
+/// Starts capturing into a new buffer, discarding any previous capture.
+pub fn start(buffer_size: usize, snap_len: usize) -> Result<(), Error> {
+    if buffer_size < RECORD_HEADER_SIZE || buffer_size > MAX_BUFFER_SIZE {
+        return Err(Error::InvalidBufferSize)
+    }
+    if snap_len == 0 {
+        return Err(Error::InvalidSnapLen)
+    }
+    unsafe {
+        RUNNING = false;
+        // Free the previous buffer first, to make room for the new one.
+        CAPTURE = None;
+        CAPTURE = Some(Capture::new(buffer_size, snap_len)?);
+        RUNNING = true;
+    }
+    Ok(())
+}
+
+pub fn stop() {
+    unsafe { RUNNING = false }
+}
+
+/// Records a frame, if a capture is running.
+pub fn record(frame: &[u8]) {
+    unsafe {
+        if RUNNING {
+            if let Some(ref mut capture) = CAPTURE {
+                capture.push(clock::get_us(), frame)
+            }
+        }
+    }
+}
+
+/// Calls `f` with the current capture, if any. Frames sent or received while
+/// `f` runs, e.g. those carrying the capture itself, are not recorded.
+pub fn with<F: FnOnce(Option<&Capture>) -> R, R>(f: F) -> R {
+    unsafe {
+        let capture = CAPTURE.take();
+        let result = f(capture.as_ref());
+        // Unless a new capture was started in the meantime.
+        if CAPTURE.is_none() {
+            CAPTURE = capture;
+        }
+        result
+    }
+}
@@ -0,0 +1,267 @@
+use core::{str, fmt};
+use alloc::String;
+use log::LevelFilter;
+use board_misoc::config;
+use logger_artiq::TargetFilter;
+use {firmware_image, net_acl};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint};
+
+// Shared secrets shorter than this are too easy to brute-force.
+const MIN_SECRET_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Type {
+    Binary,
+    Bool,
+    Integer,
+    LogLevel,
+    LogFilter,
+    MacAddress,
+    IpAddress,
+    InterfaceAddress,
+    IpEndpoint,
+    HostList,
+    Ed25519PublicKey,
+    Secret,
+    Choice(&'static [&'static str]),
+}
+
+impl Type {
+    fn accepts(&self, value: &str) -> bool {
+        match *self {
+            Type::Binary => true,
+            Type::Bool => value == "0" || value == "1",
+            Type::Integer => value.parse::<u32>().is_ok(),
+            Type::LogLevel => value.parse::<LevelFilter>().is_ok(),
+            Type::LogFilter => TargetFilter::parse(value).is_ok(),
+            Type::MacAddress => value.parse::<EthernetAddress>().is_ok(),
+            Type::IpAddress => value.parse::<IpAddress>().is_ok(),
+            Type::InterfaceAddress => value == "dhcp" || parse_interface_address(value).is_some(),
+            Type::IpEndpoint => parse_ip_endpoint(value, 0).is_some(),
+            Type::HostList => net_acl::is_valid(value),
+            Type::Ed25519PublicKey => firmware_image::is_public_key(value),
+            Type::Secret => value.len() >= MIN_SECRET_LEN,
+            Type::Choice(choices) => choices.iter().any(|&choice| choice == value),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Binary => write!(f, "binary data"),
+            Type::Bool => write!(f, "0 or 1"),
+            Type::Integer => write!(f, "a decimal unsigned integer"),
+            Type::LogLevel => write!(f, "one of OFF, ERROR, WARN, INFO, DEBUG, TRACE"),
+            Type::LogFilter => write!(f, "comma-separated TARGET=LEVEL pairs, \
+                                          e.g. runtime::rtio_mgt=debug,runtime::session=warn"),
+            Type::MacAddress => write!(f, "a MAC address, e.g. 02:00:00:00:00:01"),
+            Type::IpAddress => write!(f, "an IP address, e.g. 192.168.1.1"),
+            Type::InterfaceAddress => write!(f, "an IP address with an optional prefix length, \
+                                                 e.g. 192.168.1.50/24 or 2001:db8::50/64, or \"dhcp\""),
+            Type::IpEndpoint => write!(f, "an IP address with an optional port, \
+                                           e.g. 192.168.1.1:514"),
+            Type::HostList => write!(f, "IP addresses and CIDRs separated by commas, \
+                                         e.g. 192.168.1.0/24,10.0.0.5"),
+            Type::Ed25519PublicKey => write!(f, "an Ed25519 public key as 64 hexadecimal digits"),
+            Type::Secret => write!(f, "at least {} bytes of binary data", MIN_SECRET_LEN),
+            Type::Choice(choices) => {
+                write!(f, "one of")?;
+                for (index, choice) in choices.iter().enumerate() {
+                    write!(f, "{}{:?}", if index == 0 { " " } else { ", " }, choice)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Key {
+    pub name:     &'static str,
+    pub ty:       Type,
+    pub consumer: &'static str,
+    /// Maintained by the firmware itself; cannot be written or removed from
+    /// the management interface or the runtime UART shell.
+    pub internal: bool,
+}
+
+pub static SCHEMA: &'static [Key] = &[
+    Key { name: "mac",               ty: Type::MacAddress,          consumer: "network",       internal: false },
+    Key { name: "ip",                ty: Type::InterfaceAddress,    consumer: "network",       internal: false },
+    Key { name: "gateway",           ty: Type::IpAddress,           consumer: "network",       internal: false },
+    Key { name: "allowed_hosts",     ty: Type::HostList,            consumer: "network",       internal: false },
+    Key { name: "net_trace",         ty: Type::Bool,                consumer: "network",       internal: false },
+    Key { name: "log_level",         ty: Type::LogLevel,            consumer: "logger",        internal: false },
+    Key { name: "uart_log_level",    ty: Type::LogLevel,            consumer: "logger",        internal: false },
+    Key { name: "log_filter",        ty: Type::LogFilter,           consumer: "logger",        internal: false },
+    Key { name: "syslog_server",     ty: Type::IpEndpoint,          consumer: "logger",        internal: false },
+    Key { name: "syslog_level",      ty: Type::LogLevel,            consumer: "logger",        internal: false },
+    Key { name: "hotswap_pubkey",    ty: Type::Ed25519PublicKey,    consumer: "mgmt",          internal: false },
+    Key { name: "mgmt_secret",       ty: Type::Secret,              consumer: "mgmt",          internal: false },
+    Key { name: "mgmt_epoch",        ty: Type::Integer,             consumer: "mgmt",          internal: true },
+    Key { name: "boot_slot",         ty: Type::Choice(&["a", "b"]), consumer: "bootloader",    internal: true },
+    Key { name: "boot_pending",      ty: Type::Choice(&["a", "b"]), consumer: "bootloader",    internal: true },
+    Key { name: "boot_trial",        ty: Type::Choice(&["a", "b"]), consumer: "bootloader",    internal: true },
+    Key { name: "panic_reset",       ty: Type::Bool,                consumer: "panic handler", internal: false },
+    Key { name: "rtio_clock",        ty: Type::Choice(&["i", "e"]), consumer: "rtio_mgt",      internal: false },
+    Key { name: "startup_kernel",    ty: Type::Binary,              consumer: "session",       internal: false },
+    Key { name: "idle_kernel",       ty: Type::Binary,              consumer: "session",       internal: false },
+    Key { name: "sysref_phase_fpga", ty: Type::Integer,             consumer: "jesd204sync",   internal: false },
+    Key { name: "sysref_phase_dac",  ty: Type::Integer,             consumer: "jesd204sync",   internal: false },
+];
+
+/// Parses `address[/prefix_len]`; if omitted, the prefix length is 0 for IPv4
+/// and 64 for IPv6 addresses.
+pub fn parse_interface_address(value: &str) -> Option<IpCidr> {
+    match value.parse::<IpCidr>() {
+        Ok(cidr) => Some(cidr),
+        Err(()) => match value.parse::<IpAddress>().ok()? {
+            // IPv6 subnets are /64 in all but exotic setups.
+            IpAddress::Ipv6(addr) => Some(IpCidr::new(IpAddress::Ipv6(addr), 64)),
+            addr => Some(IpCidr::new(addr, 0))
+        }
+    }
+}
+
+/// Parses `address[:port]`, using `default_port` if the port is omitted. IPv6
+/// addresses must be enclosed in brackets if a port is given, e.g. `[2001:db8::1]:514`.
+pub fn parse_ip_endpoint(value: &str, default_port: u16) -> Option<IpEndpoint> {
+    let (addr, port) = if value.starts_with('[') {
+        let end = value.find(']')?;
+        (&value[1..end], &value[end + 1..])
+    } else if value.matches(':').count() > 1 {
+        (value, "")
+    } else {
+        match value.find(':') {
+            Some(index) => (&value[..index], &value[index..]),
+            None => (value, "")
+        }
+    };
+    let addr = addr.parse::<IpAddress>().ok()?;
+    let port = match port {
+        "" => default_port,
+        port if port.starts_with(':') => port[1..].parse::<u16>().ok()?,
+        _ => return None
+    };
+    Some(IpEndpoint::new(addr, port))
+}
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "config key `{}` is maintained by the {} and cannot be changed",
+           key, consumer)]
+    Internal {
+        key:      &'static str,
+        consumer: &'static str
+    },
+    #[fail(display = "value of `{}` is not valid UTF-8", _0)]
+    NotUtf8(&'static str),
+    #[fail(display = "invalid value {:?} for `{}` (used by {}): expected {}",
+           value, key, consumer, expected)]
+    InvalidValue {
+        key:      &'static str,
+        consumer: &'static str,
+        value:    String,
+        expected: Type
+    }
+}
+
+pub fn lookup(name: &str) -> Option<&'static Key> {
+    SCHEMA.iter().find(|key| key.name == name)
+}
+
+/// Whether the key holds a secret, which must never be read back over the network.
+pub fn is_secret(name: &str) -> bool {
+    match lookup(name) {
+        Some(&Key { ty: Type::Secret, .. }) => true,
+        _ => false
+    }
+}
+
+/// Checks a write of `value` to the key `name`, or its removal if `value` is empty.
+pub fn validate(name: &str, value: &[u8]) -> Result<(), Error> {
+    let key = match lookup(name) {
+        Some(key) => key,
+        None => {
+            warn!("config key {:?} is not used by this firmware", name);
+            return Ok(())
+        }
+    };
+    if key.internal {
+        return Err(Error::Internal { key: key.name, consumer: key.consumer })
+    }
+    validate_value(key, value)?;
+
+    if name == "log_level" || name == "syslog_level" {
+        // The level being written, or else the stored one; both default to INFO.
+        let level = |key| {
+            let level = if key == name {
+                str::from_utf8(value).ok().and_then(|value| value.parse().ok())
+            } else {
+                config::read_str(key, |result| result.ok().and_then(|value| value.parse().ok()))
+            };
+            level.unwrap_or(LevelFilter::Info)
+        };
+        check_syslog_level(level("log_level"), level("syslog_level"));
+    }
+    Ok(())
+}
+
+/// Warns if `syslog_level` asks for messages that are never forwarded: the syslog
+/// forwarder reads the log buffer, which only keeps messages at `log_level` and above.
+pub fn check_syslog_level(log_level: LevelFilter, syslog_level: LevelFilter) {
+    if syslog_level > log_level {
+        warn!("`syslog_level` {} is more verbose than `log_level` {}; \
+               only messages at {} and above are forwarded",
+              syslog_level, log_level, log_level);
+    }
+}
+
+fn validate_value(key: &Key, value: &[u8]) -> Result<(), Error> {
+    // An empty value is how the configuration store represents removal.
+    if value.is_empty() {
+        return Ok(())
+    }
+
+    match key.ty {
+        Type::Binary => Ok(()),
+        Type::Secret if value.len() >= MIN_SECRET_LEN => Ok(()),
+        Type::Secret => Err(Error::InvalidValue {
+            key:      key.name,
+            consumer: key.consumer,
+            value:    format!("({} bytes)", value.len()),
+            expected: Type::Secret
+        }),
+        ty => {
+            let value = str::from_utf8(value).map_err(|_| Error::NotUtf8(key.name))?;
+            if ty.accepts(value) {
+                Ok(())
+            } else {
+                Err(Error::InvalidValue {
+                    key:      key.name,
+                    consumer: key.consumer,
+                    value:    String::from(value),
+                    expected: ty
+                })
+            }
+        }
+    }
+}
+
+/// Validates every known key already in the configuration store, so that bad values
+/// written by `artiq_mkfs` or by older firmware are reported once at boot rather
+/// than only where, and if, they are consumed.
+pub fn check_stored() {
+    for key in SCHEMA {
+        config::read(key.name, |result| {
+            match result.map(|value| validate_value(key, value)) {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) =>
+                    warn!("invalid stored configuration: {}", err),
+                Err(err) =>
+                    warn!("cannot read config key `{}`: {}", key.name, err)
+            }
+        })
+    }
+}
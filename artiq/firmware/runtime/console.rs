@@ -0,0 +1,110 @@
+use log::LevelFilter;
+use board_misoc::uart_shell;
+use logger_artiq::BufferLogger;
+use config_schema;
+#[cfg(has_rtio_core)]
+use rtio_mgt::{drtio, drtio_dbg};
+
+const HELP: &'static str = "\
+log level [LEVEL]        print or set the log level
+log uart_level [LEVEL]   print or set the UART log level
+netinfo                  print network addresses and statistics
+drtio status             print the state of DRTIO links
+heap                     print heap usage
+reboot                   restart the runtime";
+
+pub struct Console {
+    editor: uart_shell::LineEditor
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console { editor: uart_shell::LineEditor::new() }
+    }
+
+    /// Processes any pending UART input without blocking. `netinfo` prints
+    /// the state of the network interface, which the console cannot reach.
+    pub fn poll(&mut self, netinfo: &Fn()) {
+        while let Some(byte) = uart_shell::read_byte() {
+            if let Some(line) = self.editor.feed(byte) {
+                execute(line, netinfo);
+                print!("runtime> ");
+            }
+        }
+    }
+}
+
+fn log_command(args: &str) {
+    let (action, level) = uart_shell::split_word(args);
+    let level = match level {
+        "" => None,
+        level => match level.parse::<LevelFilter>() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                println!("unknown log level {:?}", level);
+                return
+            }
+        }
+    };
+
+    BufferLogger::with(|logger| {
+        match (action, level) {
+            ("level", None) =>
+                println!("{}", logger.log_level()),
+            ("level", Some(level)) =>
+                logger.set_log_level(level),
+            ("uart_level", None) =>
+                println!("{}", logger.uart_log_level()),
+            ("uart_level", Some(level)) =>
+                logger.set_uart_log_level(level),
+            _ => println!("{}", HELP)
+        }
+    })
+}
+
+#[cfg(has_rtio_core)]
+fn drtio_status() {
+    if drtio::link_count() == 0 {
+        println!("no DRTIO links");
+    }
+    for linkno in 0..drtio::link_count() as u8 {
+        let (tx, rx) = drtio_dbg::get_packet_counts(linkno);
+        println!("link {}: rx {}, link {}, packets tx {} rx {}, buffer space requests {}",
+                 linkno,
+                 if drtio::link_rx_up(linkno) { "up" } else { "down" },
+                 if drtio::link_up(linkno) { "up" } else { "down" },
+                 tx, rx, drtio_dbg::get_buffer_space_req_count(linkno));
+    }
+}
+
+#[cfg(not(has_rtio_core))]
+fn drtio_status() {
+    println!("no DRTIO links");
+}
+
+fn execute(line: &str, netinfo: &Fn()) {
+    let (command, args) = uart_shell::split_word(line);
+    match (command, args) {
+        ("", _) => (),
+        ("help", _) => {
+            println!("{}", HELP);
+            println!("{}", uart_shell::CONFIG_HELP);
+        }
+        ("config", args) =>
+            uart_shell::config_command(args, config_schema::is_secret, |key, value| {
+                match config_schema::validate(key, value) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        println!("{}", err);
+                        false
+                    }
+                }
+            }),
+        ("log", args) => log_command(args),
+        ("netinfo", "") => netinfo(),
+        ("drtio", "status") => drtio_status(),
+        ("heap", "") => println!("{}", unsafe { &::ALLOC }),
+        ("reboot", "") => ::mgmt::reboot(),
+        _ => println!("unknown command {:?}; type `help` for a list of commands", line)
+    }
+}
@@ -0,0 +1,156 @@
+use core::{cmp, fmt, slice, str};
+use core::fmt::Write;
+use byteorder::{ByteOrder, NativeEndian};
+use crc::crc32;
+use board_misoc::cache;
+use logger_artiq::BufferLogger;
+
+// The crash log lives in a RAM region that is neither part of the runtime image nor
+// touched by the bootloader memory test, so it survives both `boot::reset()` and
+// a reset through the bootloader. It is carved out of the end of the RPC queue;
+// see `board_artiq::rpc_queue`.
+const BEGIN: usize = 0x407f0000;
+const END:   usize = 0x407fff80;
+
+const MAGIC: u32 = 0x43524153; // "CRAS"
+
+// magic: u32, length: u32, crc32: u32, unreported: u32
+const HEADER_SIZE: usize = 16;
+
+static mut CRASHED_LAST_BOOT: bool = false;
+
+fn storage() -> &'static mut [u8] {
+    unsafe { slice::from_raw_parts_mut(BEGIN as *mut u8, END - BEGIN) }
+}
+
+fn contents(storage: &[u8]) -> Option<&str> {
+    if NativeEndian::read_u32(&storage[0..]) != MAGIC {
+        return None
+    }
+    let length = NativeEndian::read_u32(&storage[4..]) as usize;
+    if length > storage.len() - HEADER_SIZE {
+        return None
+    }
+    let report = &storage[HEADER_SIZE..HEADER_SIZE + length];
+    if crc32::checksum_ieee(report) != NativeEndian::read_u32(&storage[8..]) {
+        return None
+    }
+    str::from_utf8(report).ok()
+}
+
+/// Checks for a crash report left by the previous boot. Must be called once
+/// at startup, before anything can panic again.
+pub fn init() {
+    let storage = storage();
+    if contents(storage).is_some() && NativeEndian::read_u32(&storage[12..]) != 0 {
+        NativeEndian::write_u32(&mut storage[12..], 0);
+        cache::flush_l2_cache();
+        unsafe { CRASHED_LAST_BOOT = true }
+        warn!("the previous boot ended in a panic; use `artiq_coremgmt log crash` for details");
+    }
+}
+
+/// Whether the previous boot ended in a panic.
+pub fn crashed_last_boot() -> bool {
+    unsafe { CRASHED_LAST_BOOT }
+}
+
+/// The most recent crash report, if one survived since power-up.
+pub fn last_crash() -> Option<&'static str> {
+    contents(storage())
+}
+
+struct Storage {
+    buf:    &'static mut [u8],
+    offset: usize
+}
+
+impl Storage {
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+}
+
+impl fmt::Write for Storage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut length = cmp::min(s.len(), self.remaining());
+        while !s.is_char_boundary(length) {
+            length -= 1
+        }
+        self.buf[self.offset..self.offset + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.offset += length;
+        Ok(())
+    }
+}
+
+struct Counter(usize);
+
+impl fmt::Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// A crash report being written by the panic handler. Everything written to it
+/// is also printed to the UART.
+pub struct Report {
+    header:  &'static mut [u8],
+    storage: Storage
+}
+
+impl Report {
+    pub fn new() -> Report {
+        let (header, buf) = storage().split_at_mut(HEADER_SIZE);
+        // Invalidate any previous report until this one is complete.
+        NativeEndian::write_u32(&mut header[0..], 0);
+        Report {
+            header,
+            storage: Storage { buf, offset: 0 }
+        }
+    }
+
+    /// Appends as many of the most recent log records as still fit.
+    pub fn append_log_tail(&mut self) {
+        let storage = &mut self.storage;
+        let _ = BufferLogger::with(|logger| logger.buffer().map(|buffer| {
+            let _ = write!(storage, "log tail:\n");
+
+            let mut total = 0;
+            for record in buffer.records(0) {
+                let mut counter = Counter(0);
+                let _ = write!(counter, "{}\n", record);
+                total += counter.0;
+            }
+
+            let mut skip = total.saturating_sub(storage.remaining());
+            for record in buffer.records(0) {
+                if skip > 0 {
+                    let mut counter = Counter(0);
+                    let _ = write!(counter, "{}\n", record);
+                    skip = skip.saturating_sub(counter.0);
+                    continue
+                }
+                let _ = write!(storage, "{}\n", record);
+            }
+        }));
+    }
+
+    pub fn finish(self) {
+        let Report { header, storage } = self;
+        let length = storage.offset;
+        let crc = crc32::checksum_ieee(&storage.buf[..length]);
+        NativeEndian::write_u32(&mut header[4..], length as u32);
+        NativeEndian::write_u32(&mut header[8..], crc);
+        NativeEndian::write_u32(&mut header[12..], 1);
+        NativeEndian::write_u32(&mut header[0..], MAGIC);
+        cache::flush_l2_cache();
+    }
+}
+
+impl fmt::Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        self.storage.write_str(s)
+    }
+}
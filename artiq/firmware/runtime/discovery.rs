@@ -0,0 +1,68 @@
+use core::fmt::{self, Write};
+use smoltcp::socket::UdpSocket;
+use smoltcp::wire::{EthernetAddress, IpCidr};
+use board_misoc::{csr, ident};
+use net_acl;
+
+// Host tools broadcast REQUEST to this port; every core device that receives it
+// replies to the sender with REPLY, followed by `key: value` lines.
+pub const PORT: u16 = 1384;
+
+const REQUEST: &'static [u8] = b"ARTIQ discovery\n";
+const REPLY:   &'static str = "ARTIQ core device\n";
+
+pub const MAX_PACKET_SIZE: usize = 512;
+
+struct Writer<'a> {
+    buf:    &'a mut [u8],
+    offset: usize
+}
+
+impl<'a> Write for Writer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.offset + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error)
+        }
+        self.buf[self.offset..end].copy_from_slice(s.as_bytes());
+        self.offset = end;
+        Ok(())
+    }
+}
+
+/// Answers any discovery requests received on `socket`, which must be bound to `PORT`.
+pub fn respond(socket: &mut UdpSocket, hardware_addr: EthernetAddress, ip_addrs: &[IpCidr]) {
+    loop {
+        let client = match socket.recv() {
+            // Hosts that may not connect should not learn about the device either.
+            Ok((request, client)) if request == REQUEST &&
+                                     net_acl::is_allowed(client.addr) => client,
+            Ok(_) => continue,
+            Err(_) => break
+        };
+
+        let mut reply = [0; MAX_PACKET_SIZE];
+        let length = {
+            let mut writer = Writer { buf: &mut reply, offset: 0 };
+            let result = (|| -> fmt::Result {
+                write!(writer, "{}", REPLY)?;
+                write!(writer, "software_ident: {}\n", csr::CONFIG_IDENTIFIER_STR)?;
+                write!(writer, "gateware_ident: {}\n", ident::read(&mut [0; 64]))?;
+                write!(writer, "mac: {}\n", hardware_addr)?;
+                for addr in ip_addrs.iter().filter(|addr| !addr.address().is_unspecified()) {
+                    write!(writer, "ip: {}\n", addr)?;
+                }
+                Ok(())
+            })();
+            if result.is_err() {
+                warn!("discovery reply does not fit into {} bytes", MAX_PACKET_SIZE);
+            }
+            writer.offset
+        };
+
+        debug!("answering discovery request from {}", client);
+        if let Err(err) = socket.send_slice(&reply[..length], client) {
+            debug!("cannot answer discovery request from {}: {}", client, err);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use core::cmp;
+use byteorder::{ByteOrder, BigEndian};
+use crc::crc32;
+use sha2::{Sha512, Digest};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use board_misoc::config;
+use board_misoc::boot::MAX_FIRMWARE_SIZE;
+
+// Same as the header of the .fbi images that the bootloader boots from flash.
+// length: u32, crc32: u32
+pub const HEADER_SIZE: usize = 8;
+
+// How much of the image to checksum or hash before letting other threads run;
+// a few megabytes take long enough to stall the network otherwise.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "image of {} bytes is too short to contain a header", _0)]
+    TooShort(usize),
+    #[fail(display = "firmware size {} is outside of 1..{} bytes", _0, MAX_FIRMWARE_SIZE)]
+    InvalidSize(usize),
+    #[fail(display = "header declares {} bytes of firmware, but the image is {} bytes long \
+                      (neither unsigned nor followed by a {}-byte signature)",
+           declared, actual, SIGNATURE_LENGTH)]
+    LengthMismatch { declared: usize, actual: usize },
+    #[fail(display = "CRC32 mismatch (header {:08x}, firmware {:08x})", expected, actual)]
+    CrcMismatch { expected: u32, actual: u32 },
+    #[fail(display = "image is not signed, but `hotswap_pubkey` is set")]
+    Unsigned,
+    #[fail(display = "`hotswap_pubkey` is not a valid Ed25519 public key")]
+    InvalidPublicKey,
+    #[fail(display = "signature does not match `hotswap_pubkey`")]
+    BadSignature,
+}
+
+impl Error {
+    /// Whether the image is intact, but could not be authenticated.
+    pub fn is_authentication_failure(&self) -> bool {
+        match *self {
+            Error::Unsigned | Error::InvalidPublicKey | Error::BadSignature => true,
+            _ => false
+        }
+    }
+}
+
+fn parse_hex(hex: &str, bytes: &mut [u8]) -> Result<(), ()> {
+    if hex.len() != bytes.len() * 2 {
+        return Err(())
+    }
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2).ok_or(())?, 16)
+                   .map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+/// Whether `value` is an Ed25519 public key written as hexadecimal digits,
+/// which is how `hotswap_pubkey` is stored.
+pub fn is_public_key(value: &str) -> bool {
+    parse_hex(value, &mut [0; PUBLIC_KEY_LENGTH]).is_ok()
+}
+
+fn public_key() -> Result<Option<PublicKey>, Error> {
+    config::read_str("hotswap_pubkey", |result| {
+        match result {
+            Ok(value) if !value.is_empty() => {
+                let mut bytes = [0; PUBLIC_KEY_LENGTH];
+                parse_hex(value, &mut bytes).map_err(|()| Error::InvalidPublicKey)?;
+                PublicKey::from_bytes(&bytes)
+                    .map(Some)
+                    .map_err(|_| Error::InvalidPublicKey)
+            }
+            _ => Ok(None)
+        }
+    })
+}
+
+/// Whether firmware images must be signed, i.e. `hotswap_pubkey` is set.
+/// A key that cannot be parsed counts as set, since it rejects every image.
+pub fn is_signature_required() -> bool {
+    match public_key() {
+        Ok(None) => false,
+        _ => true
+    }
+}
+
+fn checksum<F: FnMut()>(data: &[u8], relinquish: &mut F) -> u32 {
+    data.chunks(CHUNK_SIZE).fold(0, |crc, chunk| {
+        let crc = crc32::update(crc, &crc32::IEEE_TABLE, chunk);
+        relinquish();
+        crc
+    })
+}
+
+// Same as `PublicKey::verify::<Sha512>`, except that the message is hashed in chunks.
+fn verify_signature<F: FnMut()>(public_key: &PublicKey, message: &[u8], signature: &[u8],
+                                relinquish: &mut F) -> Result<(), Error> {
+    // Rejects signatures of the wrong length or with an unreduced scalar.
+    Signature::from_bytes(signature).map_err(|_| Error::BadSignature)?;
+    let mut r = [0; 32];
+    r.copy_from_slice(&signature[..32]);
+    let mut s = [0; 32];
+    s.copy_from_slice(&signature[32..]);
+    let a = CompressedEdwardsY(*public_key.as_bytes()).decompress()
+        .ok_or(Error::InvalidPublicKey)?;
+
+    let mut hasher = Sha512::new();
+    hasher.input(&r[..]);
+    hasher.input(&public_key.as_bytes()[..]);
+    for chunk in message.chunks(CHUNK_SIZE) {
+        hasher.input(chunk);
+        relinquish();
+    }
+    let k = Scalar::from_hash(hasher);
+
+    let expected_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(
+        &k, &-a, &Scalar::from_bits(s));
+    if expected_r.compress() == CompressedEdwardsY(r) {
+        Ok(())
+    } else {
+        Err(Error::BadSignature)
+    }
+}
+
+/// Checks a firmware image and returns the firmware it contains.
+///
+/// The image consists of an 8-byte header holding the big-endian length and
+/// CRC32 of the firmware, followed by the firmware itself; this is the .fbi
+/// format produced by the build. It may be followed by an Ed25519 signature
+/// of the header and firmware. If `hotswap_pubkey` is set, the signature is
+/// mandatory and must have been made with the corresponding private key.
+/// `relinquish` is called after each chunk of the image is checked.
+pub fn verify<F: FnMut()>(image: &[u8], mut relinquish: F) -> Result<&[u8], Error> {
+    if image.len() < HEADER_SIZE {
+        return Err(Error::TooShort(image.len()))
+    }
+    let length = BigEndian::read_u32(&image[0..]) as usize;
+    let expected_crc = BigEndian::read_u32(&image[4..]);
+    if length == 0 || length > MAX_FIRMWARE_SIZE {
+        return Err(Error::InvalidSize(length))
+    }
+
+    let signed = &image[..cmp::min(HEADER_SIZE + length, image.len())];
+    let signature = &image[signed.len()..];
+    if signed.len() != HEADER_SIZE + length ||
+            (signature.len() != 0 && signature.len() != SIGNATURE_LENGTH) {
+        return Err(Error::LengthMismatch { declared: length, actual: image.len() })
+    }
+
+    let firmware = &signed[HEADER_SIZE..];
+    let actual_crc = checksum(firmware, &mut relinquish);
+    if actual_crc != expected_crc {
+        return Err(Error::CrcMismatch { expected: expected_crc, actual: actual_crc })
+    }
+
+    match public_key()? {
+        Some(public_key) => {
+            if signature.is_empty() {
+                return Err(Error::Unsigned)
+            }
+            verify_signature(&public_key, signed, signature, &mut relinquish)?;
+            info!("firmware image signature verified");
+        }
+        None => warn!("`hotswap_pubkey` is not set; firmware image is not authenticated")
+    }
+
+    Ok(firmware)
+}
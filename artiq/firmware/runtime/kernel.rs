@@ -41,6 +41,16 @@ pub unsafe fn stop() {
     rpc_queue::init();
 }
 
+#[cfg(has_kernel_cpu)]
+pub fn is_running() -> bool {
+    unsafe { csr::kernel_cpu::reset_read() == 0 }
+}
+
+#[cfg(not(has_kernel_cpu))]
+pub fn is_running() -> bool {
+    false
+}
+
 pub fn validate(ptr: usize) -> bool {
     ptr >= KERNELCPU_EXEC_ADDRESS && ptr <= KERNELCPU_LAST_ADDRESS
 }
@@ -12,6 +12,11 @@ extern crate cslice;
 #[macro_use]
 extern crate log;
 extern crate byteorder;
+extern crate crc;
+extern crate sha2;
+extern crate hmac;
+extern crate curve25519_dalek;
+extern crate ed25519_dalek;
 extern crate fringe;
 extern crate managed;
 extern crate smoltcp;
@@ -23,10 +28,12 @@ extern crate io;
 extern crate board_misoc;
 extern crate board_artiq;
 extern crate logger_artiq;
+extern crate dhcp;
 extern crate proto_artiq;
 
 use core::convert::TryFrom;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv6Address};
 
 use board_misoc::{csr, irq, ident, clock, boot, config};
 #[cfg(has_ethmac)]
@@ -46,7 +53,17 @@ mod sched;
 mod cache;
 mod rtio_dma;
 
+mod capture;
+mod config_schema;
+#[cfg(has_ethmac)]
+mod discovery;
+#[cfg(has_uart)]
+mod console;
+mod crash_log;
+mod firmware_image;
 mod mgmt;
+mod net_acl;
+mod net_stats;
 mod profiler;
 mod kernel;
 mod kern_hwreq;
@@ -64,24 +81,13 @@ fn startup() {
     info!("ARTIQ runtime starting...");
     info!("software ident {}", csr::CONFIG_IDENTIFIER_STR);
     info!("gateware ident {}", ident::read(&mut [0; 64]));
+    crash_log::init();
 
-    match config::read_str("log_level", |r| r.map(|s| s.parse())) {
-        Ok(Ok(log_level_filter)) => {
-            info!("log level set to {} by `log_level` config key",
-                  log_level_filter);
-            log::set_max_level(log_level_filter);
-        }
-        _ => info!("log level set to INFO by default")
-    }
-    match config::read_str("uart_log_level", |r| r.map(|s| s.parse())) {
-        Ok(Ok(uart_log_level_filter)) => {
-            info!("UART log level set to {} by `uart_log_level` config key",
-                  uart_log_level_filter);
-            logger_artiq::BufferLogger::with(|logger|
-                logger.set_uart_log_level(uart_log_level_filter));
-        }
-        _ => info!("UART log level set to INFO by default")
-    }
+    setup_log_levels();
+    config_schema::check_stored();
+    config::subscribe("log_level", |_| setup_log_levels());
+    config::subscribe("uart_log_level", |_| setup_log_levels());
+    config::subscribe("log_filter", |_| setup_log_levels());
 
     #[cfg(has_slave_fpga_cfg)]
     board_artiq::slave_fpga::load().expect("cannot load RTM FPGA gateware");
@@ -92,14 +98,14 @@ fn startup() {
         let t = clock::get_ms();
         info!("press 'e' to erase startup and idle kernels...");
         while clock::get_ms() < t + 1000 {
-            if unsafe { csr::uart::rxtx_read() == b'e' } {
+            if board_misoc::uart_shell::read_byte() == Some(b'e') {
                 config::remove("startup_kernel").unwrap();
                 config::remove("idle_kernel").unwrap();
                 info!("startup and idle kernels erased");
                 break
             }
         }
-        info!("continuing boot");
+        info!("continuing boot; type `help` on the UART for console commands");
     }
 
     #[cfg(has_i2c)]
@@ -128,7 +134,73 @@ fn startup() {
     #[cfg(not(has_ethmac))]
     {
         info!("done");
-        loop {}
+        confirm_boot();
+        #[cfg(has_uart)]
+        let mut console = console::Console::new();
+        loop {
+            #[cfg(has_uart)]
+            console.poll(&|| println!("no network interface"));
+        }
+    }
+}
+
+#[cfg(has_spiflash)]
+fn confirm_boot() {
+    use board_misoc::boot_slot;
+
+    match boot_slot::confirm() {
+        Ok(Some(slot)) =>
+            info!("firmware in flash slot {} started successfully and is now active", slot),
+        Ok(None) =>
+            info!("running firmware from flash slot {}", boot_slot::running()),
+        Err(err) =>
+            error!("cannot confirm successful boot: {}", err)
+    }
+}
+
+#[cfg(not(has_spiflash))]
+fn confirm_boot() {}
+
+fn setup_log_levels() {
+    match config::read_str("log_level", |r| r.map(|s| s.parse())) {
+        Ok(Ok(log_level_filter)) => {
+            info!("log level set to {} by `log_level` config key",
+                  log_level_filter);
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_log_level(log_level_filter));
+        }
+        _ => {
+            info!("log level set to INFO by default");
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_log_level(log::LevelFilter::Info));
+        }
+    }
+    match config::read_str("uart_log_level", |r| r.map(|s| s.parse())) {
+        Ok(Ok(uart_log_level_filter)) => {
+            info!("UART log level set to {} by `uart_log_level` config key",
+                  uart_log_level_filter);
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_uart_log_level(uart_log_level_filter));
+        }
+        _ => {
+            info!("UART log level set to INFO by default");
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_uart_log_level(log::LevelFilter::Info));
+        }
+    }
+    let filter = config::read_str("log_filter", |r| {
+        r.map(|s| logger_artiq::TargetFilter::parse(s))
+         .unwrap_or(Ok(logger_artiq::TargetFilter::empty()))
+    });
+    match filter {
+        Ok(filter) => {
+            if !filter.is_empty() {
+                info!("per-target log levels set to {} by `log_filter` config key", filter);
+            }
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_target_filter(filter));
+        }
+        Err(err) => error!("ignoring `log_filter` config key: {}", err)
     }
 }
 
@@ -199,8 +271,17 @@ fn grabber_thread(io: sched::Io) {
     }
 }
 
+// Catches heap corruption that happens in memory which is rarely freed or reused.
+#[cfg(feature = "alloc_debug")]
+fn heap_check_thread(io: sched::Io) {
+    loop {
+        unsafe { ALLOC.check() }
+        io.sleep(1000).unwrap();
+    }
+}
+
 #[cfg(has_ethmac)]
-fn startup_ethernet() {
+fn get_hardware_addr() -> EthernetAddress {
     let hardware_addr;
     match config::read_str("mac", |r| r.map(|s| s.parse())) {
         Ok(Ok(addr)) => {
@@ -223,29 +304,131 @@ fn startup_ethernet() {
             warn!("using default MAC address {}; consider changing it", hardware_addr);
         }
     }
+    hardware_addr
+}
+
+// Returns None if the address is to be obtained through DHCP. An address without
+// a prefix length makes every host appear to be on-link.
+#[cfg(has_ethmac)]
+fn get_protocol_addr() -> Option<IpCidr> {
+    if config::read_str("ip", |r| r.map(|s| s == "dhcp").unwrap_or(false)) {
+        info!("using DHCP to obtain an IP address");
+        return None
+    }
 
     let protocol_addr;
-    match config::read_str("ip", |r| r.map(|s| s.parse())) {
-        Ok(Ok(addr)) => {
+    match config::read_str("ip", |r| r.map(config_schema::parse_interface_address)) {
+        Ok(Some(addr)) => {
             protocol_addr = addr;
             info!("using IP address {}", protocol_addr);
         }
         _ => {
             #[cfg(soc_platform = "kasli")]
             {
-                protocol_addr = IpAddress::v4(192, 168, 1, 70);
+                protocol_addr = IpCidr::new(IpAddress::v4(192, 168, 1, 70), 0);
             }
             #[cfg(soc_platform = "sayma_amc")]
             {
-                protocol_addr = IpAddress::v4(192, 168, 1, 60);
+                protocol_addr = IpCidr::new(IpAddress::v4(192, 168, 1, 60), 0);
             }
             #[cfg(soc_platform = "kc705")]
             {
-                protocol_addr = IpAddress::v4(192, 168, 1, 50);
+                protocol_addr = IpCidr::new(IpAddress::v4(192, 168, 1, 50), 0);
             }
-            info!("using default IP address {}", protocol_addr);
+            info!("using default IP address {}", protocol_addr.address());
+        }
+    }
+    Some(protocol_addr)
+}
+
+// The stateless link-local address (RFC 4862), with an interface identifier derived
+// from the MAC address as in RFC 2464.
+#[cfg(has_ethmac)]
+fn link_local_addr(hardware_addr: EthernetAddress) -> IpCidr {
+    let mac = hardware_addr.as_bytes();
+    let addr = Ipv6Address::new(
+        0xfe80, 0, 0, 0,
+        (((mac[0] ^ 0x02) as u16) << 8) | mac[1] as u16,
+        ((mac[2] as u16) << 8) | 0x00ff,
+        0xfe00 | mac[3] as u16,
+        ((mac[4] as u16) << 8) | mac[5] as u16);
+    IpCidr::new(IpAddress::Ipv6(addr), 64)
+}
+
+#[cfg(has_ethmac)]
+fn get_gateway() -> Option<IpAddress> {
+    match config::read_str("gateway", |r| r.map(|s| s.parse())) {
+        Ok(Ok(gateway)) => {
+            info!("using gateway {}", gateway);
+            Some(gateway)
         }
+        _ => None
+    }
+}
+
+#[cfg(has_ethmac)]
+fn set_gateway(routes: &mut smoltcp::iface::Routes, gateway: Option<IpAddress>) {
+    routes.update(|storage| {
+        storage.remove(&IpCidr::new(UNSPECIFIED_ADDR, 0));
+        storage.remove(&IpCidr::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0));
+    });
+    match gateway {
+        Some(IpAddress::Ipv4(gateway)) =>
+            routes.add_default_ipv4_route(gateway).map(|_| ()),
+        Some(IpAddress::Ipv6(gateway)) =>
+            routes.add_default_ipv6_route(gateway).map(|_| ()),
+        _ => Ok(())
+    }.expect("cannot add default route");
+}
+
+#[cfg(has_ethmac)]
+fn new_dhcp_client(protocol_addr: Option<IpCidr>,
+                   hardware_addr: EthernetAddress) -> Option<dhcp::DhcpClient> {
+    match protocol_addr {
+        Some(_) => None,
+        None => Some(dhcp::DhcpClient::new(hardware_addr, clock::get_ms()))
     }
+}
+
+#[cfg(has_ethmac)]
+const UNSPECIFIED_ADDR: IpAddress = IpAddress::Ipv4(Ipv4Address::UNSPECIFIED);
+
+#[cfg(has_ethmac)]
+static NET_CONFIG_CHANGED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(has_ethmac)]
+const SYSLOG_PORT: u16 = 514;
+
+#[cfg(has_ethmac)]
+static SYSLOG_CONFIG_CHANGED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(has_ethmac)]
+fn get_syslog_config() -> Option<(IpEndpoint, log::LevelFilter)> {
+    let server = config::read_str("syslog_server", |r| {
+        r.ok().and_then(|s| config_schema::parse_ip_endpoint(s, SYSLOG_PORT))
+    })?;
+    let level = match config::read_str("syslog_level", |r| r.map(|s| s.parse())) {
+        Ok(Ok(level)) => level,
+        _ => log::LevelFilter::Info
+    };
+    info!("forwarding log messages at {} and above to syslog server {}", level, server);
+    let log_level = logger_artiq::BufferLogger::with(|logger| logger.log_level());
+    config_schema::check_syslog_level(log_level, level);
+    Some((server, level))
+}
+
+#[cfg(has_ethmac)]
+fn startup_ethernet() {
+    let hardware_addr = get_hardware_addr();
+    let protocol_addr = get_protocol_addr();
+    let gateway = get_gateway();
+    info!("using IPv6 link-local address {}", link_local_addr(hardware_addr).address());
+
+    config::subscribe("mac", |_| NET_CONFIG_CHANGED.store(true, Ordering::SeqCst));
+    config::subscribe("ip", |_| NET_CONFIG_CHANGED.store(true, Ordering::SeqCst));
+    config::subscribe("gateway", |_| NET_CONFIG_CHANGED.store(true, Ordering::SeqCst));
+    config::subscribe("syslog_server", |_| SYSLOG_CONFIG_CHANGED.store(true, Ordering::SeqCst));
+    config::subscribe("syslog_level", |_| SYSLOG_CONFIG_CHANGED.store(true, Ordering::SeqCst));
 
     let mut net_device = unsafe { ethmac::EthernetDevice::new() };
     net_device.reset_phy_if_any();
@@ -267,7 +450,7 @@ fn startup_ethernet() {
             Ok(true) => net_trace_fn = net_trace_writer,
             _ => net_trace_fn = net_trace_silent
         }
-        smoltcp::phy::EthernetTracer::new(net_device, net_trace_fn)
+        smoltcp::phy::EthernetTracer::new(net_stats::Monitor::new(net_device), net_trace_fn)
     };
 
     let neighbor_cache =
@@ -276,21 +459,66 @@ fn startup_ethernet() {
         smoltcp::iface::EthernetInterfaceBuilder::new(net_device)
                        .neighbor_cache(neighbor_cache)
                        .ethernet_addr(hardware_addr)
-                       .ip_addrs([IpCidr::new(protocol_addr, 0)])
+                       .ip_addrs([protocol_addr.unwrap_or(IpCidr::new(UNSPECIFIED_ADDR, 0)),
+                                  link_local_addr(hardware_addr)])
+                       .routes(smoltcp::iface::Routes::new(alloc::btree_map::BTreeMap::new()))
                        .finalize();
+    set_gateway(interface.routes_mut(), gateway);
 
     let mut scheduler = sched::Scheduler::new();
     let io = scheduler.io();
     #[cfg(has_rtio_core)]
     rtio_mgt::startup(&io);
-    io.spawn(4096, mgmt::thread);
-    io.spawn(16384, session::thread);
+    io.spawn("mgmt", 4096, mgmt::thread);
+    io.spawn("session", 16384, session::thread);
     #[cfg(any(has_rtio_moninj, has_drtio))]
-    io.spawn(4096, moninj::thread);
+    io.spawn("moninj", 4096, moninj::thread);
     #[cfg(has_rtio_analyzer)]
-    io.spawn(4096, analyzer::thread);
+    io.spawn("analyzer", 4096, analyzer::thread);
     #[cfg(has_grabber)]
-    io.spawn(4096, grabber_thread);
+    io.spawn("grabber", 4096, grabber_thread);
+    #[cfg(feature = "alloc_debug")]
+    io.spawn("heap_check", 4096, heap_check_thread);
+
+    let syslog_handle = {
+        use smoltcp::socket::{UdpSocket, UdpSocketBuffer, UdpPacketMetadata};
+
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 64]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 16], vec![0; 8192]);
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        socket.bind(SYSLOG_PORT).expect("cannot bind syslog socket");
+        scheduler.sockets().borrow_mut().add(socket)
+    };
+    let mut syslog = get_syslog_config()
+        .map(|(server, level)| (server, logger_artiq::syslog::SyslogForwarder::new(level)));
+    let dhcp_handle = {
+        use smoltcp::socket::{UdpSocket, UdpSocketBuffer, UdpPacketMetadata};
+
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 2],
+                                             vec![0; 2 * dhcp::MAX_PACKET_SIZE]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1],
+                                             vec![0; dhcp::MAX_PACKET_SIZE]);
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        socket.bind(dhcp::CLIENT_PORT).expect("cannot bind DHCP socket");
+        scheduler.sockets().borrow_mut().add(socket)
+    };
+    let mut dhcp = new_dhcp_client(protocol_addr, hardware_addr);
+    let discovery_handle = {
+        use smoltcp::socket::{UdpSocket, UdpSocketBuffer, UdpPacketMetadata};
+
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 256]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4],
+                                             vec![0; 4 * discovery::MAX_PACKET_SIZE]);
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        socket.bind(discovery::PORT).expect("cannot bind discovery socket");
+        scheduler.sockets().borrow_mut().add(socket)
+    };
+    let mut hostname = protocol_addr.map(|cidr| cidr.address()).unwrap_or(UNSPECIFIED_ADDR);
+
+    confirm_boot();
+
+    #[cfg(has_uart)]
+    let mut console = console::Console::new();
 
     let mut net_stats = ethmac::EthernetStatistics::new();
     loop {
@@ -303,15 +531,98 @@ fn startup_ethernet() {
                 match interface.poll(sockets, timestamp) {
                     Ok(true) => (),
                     Ok(false) => break,
-                    Err(smoltcp::Error::Unrecognized) => (),
-                    Err(err) => debug!("network error: {}", err)
+                    Err(smoltcp::Error::Unrecognized) =>
+                        net_stats::count_poll_error(&smoltcp::Error::Unrecognized),
+                    Err(err) => {
+                        net_stats::count_poll_error(&err);
+                        debug!("network error: {}", err)
+                    }
                 }
             }
         }
 
+        if NET_CONFIG_CHANGED.swap(false, Ordering::SeqCst) {
+            // Existing connections are bound to the old addresses and will time out.
+            let hardware_addr = get_hardware_addr();
+            let protocol_addr = get_protocol_addr();
+            interface.set_ethernet_addr(hardware_addr);
+            interface.update_ip_addrs(|addrs| {
+                addrs[0] = protocol_addr.unwrap_or(IpCidr::new(UNSPECIFIED_ADDR, 0));
+                addrs[1] = link_local_addr(hardware_addr);
+            });
+            set_gateway(interface.routes_mut(), get_gateway());
+            hostname = protocol_addr.map(|cidr| cidr.address()).unwrap_or(UNSPECIFIED_ADDR);
+            dhcp = new_dhcp_client(protocol_addr, hardware_addr);
+        }
+
+        if let Some(ref mut dhcp) = dhcp {
+            use smoltcp::socket::UdpSocket;
+
+            let event = {
+                let sockets = &mut *scheduler.sockets().borrow_mut();
+                let mut socket = sockets.get::<UdpSocket>(dhcp_handle);
+                dhcp.poll(clock::get_ms(), &mut *socket)
+            };
+            let config = match event {
+                Some(dhcp::Event::Configured(lease)) =>
+                    Some((IpCidr::Ipv4(lease.cidr()), lease.router.map(IpAddress::Ipv4).or_else(get_gateway))),
+                Some(dhcp::Event::Deconfigured) =>
+                    Some((IpCidr::new(UNSPECIFIED_ADDR, 0), None)),
+                None => None
+            };
+            if let Some((protocol_addr, gateway)) = config {
+                interface.update_ip_addrs(|addrs| addrs[0] = protocol_addr);
+                set_gateway(interface.routes_mut(), gateway);
+                hostname = protocol_addr.address();
+            }
+        }
+
+        {
+            use smoltcp::socket::UdpSocket;
+
+            let sockets = &mut *scheduler.sockets().borrow_mut();
+            let mut socket = sockets.get::<UdpSocket>(discovery_handle);
+            discovery::respond(&mut socket, interface.ethernet_addr(), interface.ip_addrs());
+        }
+
+        if SYSLOG_CONFIG_CHANGED.swap(false, Ordering::SeqCst) {
+            syslog = match (syslog.take(), get_syslog_config()) {
+                (Some((_, mut forwarder)), Some((server, level))) => {
+                    forwarder.set_level(level);
+                    Some((server, forwarder))
+                }
+                (None, Some((server, level))) =>
+                    Some((server, logger_artiq::syslog::SyslogForwarder::new(level))),
+                (_, None) => None
+            };
+        }
+
+        if let Some((server, ref mut forwarder)) = syslog {
+            use smoltcp::socket::UdpSocket;
+
+            let sockets = &mut *scheduler.sockets().borrow_mut();
+            let mut socket = sockets.get::<UdpSocket>(syslog_handle);
+            logger_artiq::BufferLogger::with(|logger|
+                forwarder.poll(logger, &hostname, clock::get_ms(), |message|
+                    socket.send_slice(message, server).map_err(|_| ())));
+        }
+
         if let Some(_net_stats_diff) = net_stats.update() {
             debug!("ethernet mac:{}", ethmac::EthernetStatistics::new());
         }
+
+        #[cfg(has_uart)]
+        console.poll(&|| {
+            println!("mac: {}", interface.ethernet_addr());
+            for addr in interface.ip_addrs() {
+                println!("ip:  {}", addr);
+            }
+            if let Some(lease) = dhcp.as_ref().and_then(|dhcp| dhcp.lease()) {
+                println!("dhcp: {}", lease);
+            }
+            println!("ethernet mac:{}", net_stats);
+            println!("rejected connections: {}", net_acl::rejected_count());
+        });
     }
 }
 
@@ -383,27 +694,44 @@ pub fn oom(layout: core::alloc::Layout) -> ! {
 #[no_mangle] // https://github.com/rust-lang/rust/issues/{38281,51647}
 #[panic_implementation]
 pub fn panic_impl(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
     irq::set_ie(false);
 
+    // Everything written to the report is also printed to the UART.
+    let mut report = crash_log::Report::new();
+
     if let Some(location) = info.location() {
-        print!("panic at {}:{}:{}", location.file(), location.line(), location.column());
+        let _ = write!(report, "panic at {}:{}:{}",
+                       location.file(), location.line(), location.column());
     } else {
-        print!("panic at unknown location");
+        let _ = write!(report, "panic at unknown location");
     }
     if let Some(message) = info.message() {
-        println!("{}", message);
+        let _ = write!(report, "{}\n", message);
     } else {
-        println!("");
+        let _ = write!(report, "\n");
     }
 
-    println!("backtrace for software version {}:", csr::CONFIG_IDENTIFIER_STR);
+    let _ = write!(report, "backtrace for software version {}:\n", csr::CONFIG_IDENTIFIER_STR);
     let _ = unwind_backtrace::backtrace(|ip| {
         // Backtrace gives us the return address, i.e. the address after the delay slot,
         // but we're interested in the call instruction.
-        println!("{:#08x}", ip - 2 * 4);
+        let _ = write!(report, "{:#08x}\n", ip - 2 * 4);
     });
 
+    report.append_log_tail();
+    report.finish();
+
     if config::read_str("panic_reset", |r| r == Ok("1")) {
+        #[cfg(has_spiflash)]
+        {
+            if let Some(slot) = board_misoc::boot_slot::trial() {
+                // Let the bootloader fall back to the previous firmware.
+                println!("firmware in flash slot {} failed to start, rebooting...", slot);
+                unsafe { boot::reboot() }
+            }
+        }
         println!("restarting...");
         unsafe { boot::reset() }
     } else {
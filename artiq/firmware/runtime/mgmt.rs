@@ -1,11 +1,19 @@
+use core::fmt::Write as FmtWrite;
+use alloc::{Vec, String};
 use log::{self, LevelFilter};
+use byteorder::{ByteOrder, NativeEndian};
+use sha2::{Sha256, Digest};
+use hmac::{Hmac, Mac};
+use smoltcp::wire::IpEndpoint;
 
-use io::{Write, ProtoWrite, Error as IoError};
-use board_misoc::{config, boot};
-use logger_artiq::BufferLogger;
+use io::{Write, ProtoWrite, SizeCounter, Error as IoError};
+use board_misoc::{csr, config, boot, clock};
+#[cfg(has_spiflash)]
+use board_misoc::boot_slot;
+use logger_artiq::{BufferLogger, TargetFilter};
 use mgmt_proto::*;
-use sched::{Io, TcpListener, TcpStream, Error as SchedError};
-use profiler;
+use sched::{Io, TcpListener, TcpStream, ThreadState, Error as SchedError};
+use {capture, config_schema, crash_log, firmware_image, net_acl, net_stats, profiler};
 
 impl From<SchedError> for Error<SchedError> {
     fn from(value: SchedError) -> Error<SchedError> {
@@ -13,16 +21,233 @@ impl From<SchedError> for Error<SchedError> {
     }
 }
 
+fn config_error_code(error: &config::Error) -> ErrorCode {
+    match *error {
+        config::Error::AlreadyLocked => ErrorCode::ConfigAlreadyLocked,
+        config::Error::SpaceExhausted => ErrorCode::ConfigSpaceExhausted,
+        config::Error::Truncated { .. } |
+        config::Error::InvalidSize { .. } |
+        config::Error::MissingSeparator { .. } => ErrorCode::ConfigCorrupted,
+        config::Error::Utf8Error(_) => ErrorCode::ConfigUtf8
+    }
+}
+
+fn write_config_error(stream: &mut TcpStream, error: config::Error)
+                     -> Result<(), IoError<SchedError>> {
+    warn!("configuration error: {}", error);
+    Reply::Error {
+        code:    config_error_code(&error),
+        message: &format!("{}", error)
+    }.write_to(stream)
+}
+
+fn write_firmware_error(stream: &mut TcpStream, error: firmware_image::Error)
+                        -> Result<(), IoError<SchedError>> {
+    warn!("rejected firmware image: {}", error);
+    Reply::Error {
+        code:    if error.is_authentication_failure() {
+                     ErrorCode::UnauthenticatedFirmware
+                 } else {
+                     ErrorCode::InvalidFirmware
+                 },
+        message: &format!("{}", error)
+    }.write_to(stream)
+}
+
+// How long to wait before reporting a failed login, to slow down guessing.
+const AUTH_FAILURE_DELAY_MS: u64 = 1000;
+
+// The time since boot and the challenge counter start over on every boot, so they
+// are combined with a boot epoch that is kept in flash. It is advanced once per
+// boot, when the first challenge is made, so that flash isn't written on every boot.
+fn boot_epoch() -> Result<u32, config::Error> {
+    static mut EPOCH: Option<u32> = None;
+
+    if let Some(epoch) = unsafe { EPOCH } {
+        return Ok(epoch)
+    }
+    let epoch = config::read_str("mgmt_epoch", |result| {
+        result.ok().and_then(|value| value.parse::<u32>().ok()).unwrap_or(0)
+    }).wrapping_add(1);
+    config::write_int("mgmt_epoch", epoch)?;
+    unsafe { EPOCH = Some(epoch) }
+    Ok(epoch)
+}
+
+// The nonce only has to be unique, not unpredictable; the boot epoch, the time since
+// boot, a counter and the client endpoint make sure that it is never repeated, so that
+// a recorded response cannot be replayed. (Erasing the configuration resets the epoch,
+// but also removes the secret.)
+fn make_challenge(epoch: u32, remote: IpEndpoint) -> Vec<u8> {
+    static mut COUNTER: u32 = 0;
+
+    let mut data = [0; 16];
+    NativeEndian::write_u32(&mut data[0..], epoch);
+    NativeEndian::write_u64(&mut data[4..], clock::get_us());
+    NativeEndian::write_u32(&mut data[12..], unsafe { COUNTER += 1; COUNTER });
+
+    let mut hasher = Sha256::default();
+    hasher.input(&data);
+    hasher.input(format!("{}", remote).as_bytes());
+    hasher.result().to_vec()
+}
+
+fn check_response(secret: &[u8], challenge: &[u8], response: &[u8]) -> bool {
+    match Hmac::<Sha256>::new_varkey(secret) {
+        Ok(mut mac) => {
+            mac.input(challenge);
+            // Compares in constant time.
+            mac.verify(response).is_ok()
+        }
+        Err(_) => false
+    }
+}
+
+fn mgmt_secret() -> Option<Vec<u8>> {
+    config::read("mgmt_secret", |result| {
+        match result {
+            Ok(value) if !value.is_empty() => Some(value.to_vec()),
+            _ => None
+        }
+    })
+}
+
+// Whether the request could allow someone to run code that is not signed with the
+// key in `hotswap_pubkey`, by replacing or removing that key, by taking over the
+// secret that protects it, or by installing a kernel, which runs on the kernel CPU
+// and can write anywhere in main RAM. The `boot_*` keys, which could roll back
+// to older firmware, cannot be changed over the network at all.
+fn weakens_firmware_signing(request: &Request) -> bool {
+    match *request {
+        Request::ConfigWrite { ref key, .. } | Request::ConfigRemove { ref key } =>
+            match key.as_str() {
+                "hotswap_pubkey" | "mgmt_secret" | "startup_kernel" | "idle_kernel" => true,
+                _ => false
+            },
+        Request::ConfigErase => true,
+        _ => false
+    }
+}
+
+#[cfg(has_spiflash)]
+fn slot_index(slot: boot_slot::Slot) -> u8 {
+    match slot {
+        boot_slot::Slot::A => 0,
+        boot_slot::Slot::B => 1
+    }
+}
+
+/// Restarts the device; through the bootloader if new firmware is waiting to
+/// be tried, since only the bootloader can start it.
+pub fn reboot() -> ! {
+    profiler::stop();
+    #[cfg(has_spiflash)]
+    {
+        if boot_slot::pending().is_some() {
+            warn!("rebooting into new firmware");
+            unsafe { boot::reboot() }
+        }
+    }
+    warn!("restarting");
+    unsafe { boot::reset() }
+}
+
 fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
     read_magic(stream)?;
     info!("new connection from {}", stream.remote_endpoint());
 
+    // If `mgmt_secret` is set, clients have to prove that they know it before
+    // they may change anything, by returning the HMAC-SHA256 of a challenge.
+    let secret = mgmt_secret();
+    let mut authenticated = secret.is_none();
+    // Unlike `authenticated`, only set once the client has actually proven that
+    // it knows the secret.
+    let mut proven = false;
+    let mut challenge = None;
+
     loop {
-        match Request::read_from(stream)? {
+        let request = match Request::read_from(stream) {
+            Ok(request) => request,
+            Err(Error::UnknownPacket(ty)) => {
+                Reply::Error {
+                    code:    ErrorCode::UnknownRequest,
+                    message: &format!("unknown request type {}", ty)
+                }.write_to(stream)?;
+                return Err(Error::UnknownPacket(ty))
+            }
+            Err(err) => return Err(err)
+        };
+
+        if !authenticated && !request.is_read_only() {
+            warn!("rejected request from unauthenticated client {}",
+                  stream.remote_endpoint());
+            Reply::Error {
+                code:    ErrorCode::Unauthorized,
+                message: "this request requires authentication"
+            }.write_to(stream)?;
+            continue
+        }
+
+        // Otherwise, the signature check would only keep out those who don't
+        // know about `artiq_coremgmt config remove hotswap_pubkey`.
+        if !proven && weakens_firmware_signing(&request) &&
+                firmware_image::is_signature_required() {
+            warn!("rejected change to firmware signing configuration from {}",
+                  stream.remote_endpoint());
+            Reply::Error {
+                code:    ErrorCode::Unauthorized,
+                message: "`hotswap_pubkey` is set; changing it, `mgmt_secret`, the startup \
+                          or idle kernel, or erasing the configuration requires \
+                          authentication with `mgmt_secret`, or the UART shell"
+            }.write_to(stream)?;
+            continue
+        }
+
+        match request {
+            Request::GetChallenge => {
+                // Without a secret, any response is accepted, and the epoch is not needed.
+                let epoch = if secret.is_some() { boot_epoch() } else { Ok(0) };
+                match epoch {
+                    Ok(epoch) => {
+                        let nonce = make_challenge(epoch, stream.remote_endpoint());
+                        Reply::Challenge(&nonce).write_to(stream)?;
+                        challenge = Some(nonce);
+                    }
+                    Err(err) => write_config_error(stream, err)?
+                }
+            }
+            Request::Authenticate { ref response } => {
+                let valid = match (&secret, challenge.take()) {
+                    (&None, _) => true,
+                    (&Some(ref secret), Some(ref nonce)) =>
+                        check_response(secret, nonce, response),
+                    (&Some(_), None) => false
+                };
+                if valid {
+                    info!("client authenticated");
+                    authenticated = true;
+                    proven = secret.is_some();
+                    Reply::Success.write_to(stream)?;
+                } else {
+                    warn!("authentication failed for {}", stream.remote_endpoint());
+                    io.sleep(AUTH_FAILURE_DELAY_MS)?;
+                    Reply::Error {
+                        code:    ErrorCode::AuthenticationFailed,
+                        message: "authentication failed"
+                    }.write_to(stream)?;
+                    stream.close()?;
+                    return Ok(())
+                }
+            }
+
             Request::GetLog => {
                 BufferLogger::with(|logger| {
-                    let mut buffer = io.until_ok(|| logger.buffer())?;
-                    Reply::LogContent(buffer.extract()).write_to(stream)
+                    let buffer = io.until_ok(|| logger.buffer())?;
+                    let mut content = String::new();
+                    for record in buffer.records(0) {
+                        writeln!(content, "{}", record).unwrap();
+                    }
+                    Reply::LogContent(&content).write_to(stream)
                 })?;
             }
             Request::ClearLog => {
@@ -33,17 +258,36 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
 
                 Reply::Success.write_to(stream)?;
             }
-            Request::PullLog => {
+            Request::PullLog { from_seq } => {
                 BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
+                    let mut next_seq = from_seq;
+                    if next_seq > io.until_ok(|| logger.next_seq().ok_or(()))? {
+                        // The client is resuming a session from before a reboot.
+                        next_seq = 0;
+                    }
+
                     loop {
-                        // Do this *before* acquiring the buffer, since that sets the log level
-                        // to OFF.
-                        let log_level = log::max_level();
+                        io.until(|| logger.next_seq().map_or(false, |seq| seq > next_seq))?;
 
-                        let mut buffer = io.until_ok(|| logger.buffer())?;
-                        if buffer.is_empty() { continue }
+                        let log_level = log::max_level();
 
-                        stream.write_string(buffer.extract())?;
+                        let buffer = io.until_ok(|| logger.buffer())?;
+                        let mut batch = Vec::new();
+                        for record in buffer.records(next_seq) {
+                            Reply::LogRecord {
+                                seq:       record.seq,
+                                timestamp: record.timestamp,
+                                level:     record.level,
+                                target:    record.target,
+                                message:   record.message
+                            }.write_to(&mut batch).unwrap();
+                        }
+                        // Records dropped since the last batch have no entry, but the
+                        // client will notice the gap when the next one arrives.
+                        next_seq = buffer.next_seq();
+                        if batch.is_empty() {
+                            continue
+                        }
 
                         if log_level == LevelFilter::Trace {
                             // Hold exclusive access over the logger until we get positive
@@ -51,20 +295,23 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                             // trace messages being transmitted and causing more network
                             // trace messages to be emitted.
                             //
-                            // Any messages unrelated to this management socket that arrive
-                            // while it is flushed are lost, but such is life.
+                            // Any messages that arrive while it is flushed are dropped, but
+                            // they still take up sequence numbers, so the client sees a gap.
+                            Write::write_all(stream, &batch)?;
                             stream.flush()?;
+                        } else {
+                            // Release the buffer first, so that messages logged while we
+                            // are blocked on the network are recorded and sent next time.
+                            drop(buffer);
+                            Write::write_all(stream, &batch)?;
                         }
-
-                        // Clear the log *after* flushing the network buffers, or we're just
-                        // going to resend all the trace messages on the next iteration.
-                        buffer.clear();
                     }
                 })?;
             }
             Request::SetLogFilter(level) => {
                 info!("changing log level to {}", level);
-                log::set_max_level(level);
+                BufferLogger::with(|logger|
+                    logger.set_log_level(level));
                 Reply::Success.write_to(stream)?;
             }
             Request::SetUartLogFilter(level) => {
@@ -73,40 +320,91 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                     logger.set_uart_log_level(level));
                 Reply::Success.write_to(stream)?;
             }
+            Request::GetLastCrash => {
+                match crash_log::last_crash() {
+                    Some(report) => Reply::LastCrash(report).write_to(stream)?,
+                    None => Reply::Error {
+                        code:    ErrorCode::Unavailable,
+                        message: "no crash has been recorded since power-up"
+                    }.write_to(stream)?
+                }
+            }
+            Request::SetLogTargetFilter(ref spec) => {
+                match TargetFilter::parse(spec) {
+                    Ok(filter) => {
+                        info!("changing per-target log levels to {:?}", spec);
+                        BufferLogger::with(|logger|
+                            logger.set_target_filter(filter));
+                        Reply::Success.write_to(stream)?;
+                    }
+                    Err(err) => {
+                        let message = format!("invalid log filter: {}", err);
+                        Reply::Error { code: ErrorCode::InvalidArgument, message: &message }
+                            .write_to(stream)?;
+                    }
+                }
+            }
 
+            Request::ConfigRead { ref key } if config_schema::is_secret(key) => {
+                warn!("rejected read of secret config key {:?}", key);
+                Reply::Error {
+                    code:    ErrorCode::Unauthorized,
+                    message: &format!("config key {:?} is write-only", key)
+                }.write_to(stream)?;
+            }
             Request::ConfigRead { ref key } => {
                 config::read(key, |result| {
                     match result {
                         Ok(value) => Reply::ConfigData(&value).write_to(stream),
-                        Err(_)    => Reply::Error.write_to(stream)
+                        Err(err)  => write_config_error(stream, err)
                     }
                 })?;
             }
             Request::ConfigWrite { ref key, ref value } => {
+                if let Err(err) = config_schema::validate(key, value) {
+                    warn!("rejected write to config key {:?}: {}", key, err);
+                    Reply::Error {
+                        code:    ErrorCode::ConfigRejected,
+                        message: &format!("{}", err)
+                    }.write_to(stream)?;
+                    continue
+                }
+
                 match config::write(key, value) {
-                    Ok(_)  => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
+                    Ok(_)    => Reply::Success.write_to(stream),
+                    Err(err) => write_config_error(stream, err)
                 }?;
             }
             Request::ConfigRemove { ref key } => {
+                if let Err(err) = config_schema::validate(key, &[]) {
+                    warn!("rejected removal of config key {:?}: {}", key, err);
+                    Reply::Error {
+                        code:    ErrorCode::ConfigRejected,
+                        message: &format!("{}", err)
+                    }.write_to(stream)?;
+                    continue
+                }
+
                 match config::remove(key) {
-                    Ok(()) => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
+                    Ok(())   => Reply::Success.write_to(stream),
+                    Err(err) => write_config_error(stream, err)
                 }?;
-
             }
             Request::ConfigErase => {
                 match config::erase() {
-                    Ok(()) => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
+                    Ok(())   => Reply::Success.write_to(stream),
+                    Err(err) => write_config_error(stream, err)
                 }?;
             }
 
-            Request::StartProfiler { interval_us, hits_size, edges_size } => {
+            Request::StartProfiler { interval_us, hits_size, edges_size, kernel_cpu } => {
                 match profiler::start(interval_us as u64,
-                                      hits_size as usize, edges_size as usize) {
+                                      hits_size as usize, edges_size as usize, kernel_cpu) {
                     Ok(()) => Reply::Success.write_to(stream)?,
-                    Err(()) => Reply::Unavailable.write_to(stream)?
+                    Err(()) => Reply::Error {
+                        code:    ErrorCode::Unavailable,
+                        message: "profiling is unsupported by this gateware or interval"
+                    }.write_to(stream)?
                 }
             }
             Request::StopProfiler => {
@@ -116,11 +414,14 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
             Request::GetProfile => {
                 profiler::pause(|profile| {
                     let profile = match profile {
-                        None => return Reply::Unavailable.write_to(stream),
+                        None => return Reply::Error {
+                            code:    ErrorCode::Unavailable,
+                            message: "profiler is not running"
+                        }.write_to(stream),
                         Some(profile) => profile
                     };
 
-                    Reply::Profile.write_to(stream)?;
+                    Reply::Profile { kernel_cpu: profile.kernel_cpu() }.write_to(stream)?;
                     {
                         let hits = profile.hits();
                         stream.write_u32(hits.len() as u32)?;
@@ -142,26 +443,239 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                     Ok(())
                 })?;
             }
+            Request::GetProfilePprof => {
+                profiler::pause(|profile| {
+                    let profile = match profile {
+                        None => return Reply::Error {
+                            code:    ErrorCode::Unavailable,
+                            message: "profiler is not running"
+                        }.write_to(stream),
+                        Some(profile) => profile
+                    };
+
+                    let ident = csr::CONFIG_IDENTIFIER_STR;
+                    let size = match profile.write_pprof(SizeCounter::default(), ident) {
+                        Ok(counter) => counter.size,
+                        // SizeCounter takes every byte, so it never ends short.
+                        Err(IoError::UnexpectedEnd) => unreachable!(),
+                        Err(IoError::Other(err)) => match err {}
+                    };
+                    Reply::ProfilePprof(size as u32).write_to(stream)?;
+                    profile.write_pprof(&mut *stream, ident)?;
+                    Ok(())
+                })?;
+            }
+
+            Request::Hotswap(image) => {
+                let verified = firmware_image::verify(&image, || { let _ = io.relinquish(); });
+                let firmware = match verified {
+                    Ok(firmware) => firmware,
+                    Err(err) => {
+                        write_firmware_error(stream, err)?;
+                        continue
+                    }
+                };
 
-            Request::Hotswap(firmware) => {
                 Reply::RebootImminent.write_to(stream)?;
                 stream.close()?;
                 stream.flush()?;
 
                 profiler::stop();
                 warn!("hotswapping firmware");
-                unsafe { boot::hotswap(&firmware) }
+                unsafe { boot::hotswap(firmware) }
+            }
+            #[cfg(has_spiflash)]
+            Request::FlashFirmware(_) if !boot_slot::Slot::B.exists() => {
+                Reply::Error {
+                    code:    ErrorCode::Unavailable,
+                    message: "the flash of this board has no room for a second firmware slot"
+                }.write_to(stream)?;
+            }
+            #[cfg(has_spiflash)]
+            Request::FlashFirmware(image) => {
+                let verified = firmware_image::verify(&image, || { let _ = io.relinquish(); });
+                let length = match verified {
+                    Ok(firmware) => firmware.len(),
+                    Err(err) => {
+                        write_firmware_error(stream, err)?;
+                        continue
+                    }
+                };
+
+                // Never overwrite the firmware that is running, so that a failed
+                // update can always be recovered from by rebooting.
+                let slot = boot_slot::running().other();
+                info!("writing firmware of {} bytes to flash slot {}", length, slot);
+                // The signature, if any, is not stored; only the header and firmware.
+                let result = boot_slot::write(slot, &image[..firmware_image::HEADER_SIZE + length], || {
+                    let _ = io.relinquish();
+                }).and_then(|()| boot_slot::set_pending(slot));
+                match result {
+                    Ok(()) => {
+                        info!("firmware in flash slot {} will be tried on next reboot", slot);
+                        Reply::Success.write_to(stream)?
+                    }
+                    Err(err) => {
+                        error!("cannot write firmware to flash slot {}: {}", slot, err);
+                        Reply::Error {
+                            code:    ErrorCode::FlashWriteFailed,
+                            message: &format!("cannot write flash slot {}: {}", slot, err)
+                        }.write_to(stream)?
+                    }
+                }
+            }
+            #[cfg(has_spiflash)]
+            Request::GetFirmwareSlots => {
+                Reply::FirmwareSlots {
+                    running: slot_index(boot_slot::running()),
+                    active:  slot_index(boot_slot::active()),
+                    pending: boot_slot::pending().map(slot_index).unwrap_or(NO_SLOT)
+                }.write_to(stream)?;
+            }
+            #[cfg(not(has_spiflash))]
+            Request::FlashFirmware(_) | Request::GetFirmwareSlots => {
+                Reply::Error {
+                    code:    ErrorCode::Unavailable,
+                    message: "this device has no SPI flash"
+                }.write_to(stream)?;
             }
             Request::Reboot => {
                 Reply::RebootImminent.write_to(stream)?;
                 stream.close()?;
                 stream.flush()?;
 
-                profiler::stop();
-                warn!("restarting");
-                unsafe { boot::reset() }
+                reboot()
+            }
+
+            Request::GetNetworkStats => {
+                let mac = net_stats::mac_errors();
+                let frames = net_stats::frames();
+                let sockets = io.tcp_sockets();
+
+                Reply::NetworkStats.write_to(stream)?;
+                stream.write_u32(mac.rx_preamble_errors)?;
+                stream.write_u32(mac.rx_crc_errors)?;
+                stream.write_u32(mac.rx_dropped)?;
+                stream.write_u64(frames.rx_frames)?;
+                stream.write_u64(frames.rx_bytes)?;
+                stream.write_u64(frames.tx_frames)?;
+                stream.write_u64(frames.tx_bytes)?;
+                stream.write_u32(net_acl::rejected_count() as u32)?;
+
+                let poll_errors = net_stats::poll_errors();
+                stream.write_u32(poll_errors.len() as u32)?;
+                for (kind, &count) in net_stats::POLL_ERROR_KINDS.iter().zip(poll_errors.iter()) {
+                    stream.write_string(kind)?;
+                    stream.write_u32(count)?;
+                }
+
+                stream.write_u32(sockets.len() as u32)?;
+                for &(local, remote, state) in sockets.iter() {
+                    stream.write_string(&format!("{}", local))?;
+                    stream.write_string(&format!("{}", remote))?;
+                    stream.write_string(&format!("{}", state))?;
+                }
+
+                let services = net_stats::services();
+                let services = services.iter().filter_map(|service| *service);
+                stream.write_u32(services.clone().count() as u32)?;
+                for service in services {
+                    stream.write_u16(service.port)?;
+                    stream.write_u64(service.rx_bytes)?;
+                    stream.write_u64(service.tx_bytes)?;
+                }
+
+                let now_ms = clock::get_ms();
+                let neighbors = net_stats::neighbors();
+                let neighbors = neighbors.iter().filter_map(|neighbor| *neighbor);
+                stream.write_u32(neighbors.clone().count() as u32)?;
+                for neighbor in neighbors {
+                    stream.write_string(&format!("{}", neighbor.protocol_addr))?;
+                    stream.write_string(&format!("{}", neighbor.hardware_addr))?;
+                    stream.write_u64(now_ms - neighbor.last_seen_ms)?;
+                }
             }
 
+            Request::StartCapture { buffer_size, snap_len } => {
+                match capture::start(buffer_size as usize, snap_len as usize) {
+                    Ok(()) => {
+                        info!("capturing up to {} bytes of network traffic", buffer_size);
+                        Reply::Success.write_to(stream)?
+                    }
+                    Err(err) => {
+                        warn!("cannot start capture: {}", err);
+                        Reply::Error {
+                            code:    match err {
+                                         capture::Error::OutOfMemory(_) =>
+                                             ErrorCode::OutOfMemory,
+                                         _ => ErrorCode::InvalidArgument
+                                     },
+                            message: &format!("{}", err)
+                        }.write_to(stream)?
+                    }
+                }
+            }
+            Request::StopCapture => {
+                capture::stop();
+                Reply::Success.write_to(stream)?;
+            }
+            Request::GetCapture => {
+                capture::with(|capture| -> Result<(), Error<SchedError>> {
+                    match capture {
+                        None => Reply::Error {
+                            code:    ErrorCode::Unavailable,
+                            message: "no capture has been started"
+                        }.write_to(stream)?,
+                        Some(capture) => {
+                            if capture.dropped() > 0 {
+                                warn!("{} frames did not fit into the capture buffer",
+                                      capture.dropped());
+                            }
+                            Reply::Capture(capture.pcap_size() as u32).write_to(stream)?;
+                            capture.to_pcap(|data| Write::write_all(stream, data))?;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+
+            Request::GetThreads => {
+                let threads = io.threads();
+
+                Reply::Threads.write_to(stream)?;
+                stream.write_u32(threads.len() as u32)?;
+                for thread in threads.iter() {
+                    stream.write_string(thread.name)?;
+                    stream.write_u32(thread.stack_size as u32)?;
+                    stream.write_u32(thread.stack_used as u32)?;
+                    match thread.state {
+                        ThreadState::Running => stream.write_string("running")?,
+                        ThreadState::Ready => stream.write_string("ready")?,
+                        ThreadState::Event => stream.write_string("event")?,
+                        ThreadState::Timeout { remaining_ms } => {
+                            stream.write_string("timeout")?;
+                            stream.write_u64(remaining_ms)?;
+                        }
+                        ThreadState::Socket { local, remote } => {
+                            stream.write_string("socket")?;
+                            stream.write_string(&format!("{}", local))?;
+                            stream.write_string(&format!("{}", remote))?;
+                        }
+                    }
+                }
+            }
+
+            Request::GetHeapStats => {
+                let stats = unsafe { ::ALLOC.stats() };
+                Reply::HeapStats {
+                    busy:         stats.busy as u32,
+                    idle:         stats.idle as u32,
+                    meta:         stats.meta as u32,
+                    largest_idle: stats.largest_idle as u32,
+                    blocks:       stats.blocks as u32,
+                    peak_busy:    stats.peak_busy as u32,
+                }.write_to(stream)?;
+            }
             Request::DebugAllocator =>
                 unsafe { println!("{}", ::ALLOC) },
         };
@@ -175,7 +689,7 @@ pub fn thread(io: Io) {
 
     loop {
         let stream = listener.accept().expect("mgmt: cannot accept").into_handle();
-        io.spawn(4096, move |io| {
+        io.spawn("mgmt_worker", 4096, move |io| {
             let mut stream = TcpStream::from_handle(&io, stream);
             match worker(&io, &mut stream) {
                 Ok(()) => (),
@@ -234,7 +234,7 @@ pub fn thread(io: Io) {
 
     loop {
         let stream = listener.accept().expect("moninj: cannot accept").into_handle();
-        io.spawn(16384, move |io| {
+        io.spawn("moninj_worker", 16384, move |io| {
             let mut stream = TcpStream::from_handle(&io, stream);
             match connection_worker(&io, &mut stream) {
                 Ok(()) => {},
@@ -0,0 +1,66 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT,
+                        Ordering};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Cidr, Ipv6Cidr};
+use board_misoc::config;
+
+static REJECTED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Connections are checked often; only complain about the configuration once per boot.
+static WARNED_INVALID: AtomicBool = ATOMIC_BOOL_INIT;
+static WARNED_UNREADABLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+fn parse_entry(entry: &str) -> Option<IpCidr> {
+    if let Ok(cidr) = entry.parse::<IpCidr>() {
+        return Some(cidr)
+    }
+    match entry.parse::<IpAddress>() {
+        Ok(IpAddress::Ipv4(addr)) => Some(IpCidr::Ipv4(Ipv4Cidr::new(addr, 32))),
+        Ok(IpAddress::Ipv6(addr)) => Some(IpCidr::Ipv6(Ipv6Cidr::new(addr, 128))),
+        _ => None
+    }
+}
+
+fn entries<'a>(spec: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+    spec.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|entry| !entry.is_empty())
+}
+
+/// Whether `spec` is a list of addresses and CIDRs separated by commas or spaces,
+/// e.g. `192.168.1.0/24, 10.0.0.5`, which is the format of `allowed_hosts`.
+pub fn is_valid(spec: &str) -> bool {
+    entries(spec).all(|entry| parse_entry(entry).is_some())
+}
+
+/// Whether a host may connect to the services of the core device. If
+/// `allowed_hosts` is not set, any host may connect; if it cannot be read,
+/// no host may.
+pub fn is_allowed(addr: IpAddress) -> bool {
+    config::read_str("allowed_hosts", |result| {
+        match result {
+            Ok(spec) if entries(spec).next().is_none() => true,
+            Ok(spec) => {
+                if !is_valid(spec) && !WARNED_INVALID.swap(true, Ordering::Relaxed) {
+                    warn!("ignoring invalid entries in allowed_hosts {:?}", spec);
+                }
+                entries(spec).filter_map(parse_entry).any(|cidr| cidr.contains_addr(&addr))
+            }
+            // Without flash, there is no configuration that could restrict access.
+            Err(_) if cfg!(not(has_spiflash)) => true,
+            Err(err) => {
+                if !WARNED_UNREADABLE.swap(true, Ordering::Relaxed) {
+                    error!("cannot read allowed_hosts, rejecting all connections: {}", err);
+                }
+                false
+            }
+        }
+    })
+}
+
+pub fn count_rejected() {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of connections rejected since startup.
+pub fn rejected_count() -> usize {
+    REJECTED.load(Ordering::Relaxed)
+}
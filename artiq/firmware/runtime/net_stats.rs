@@ -0,0 +1,251 @@
+use smoltcp::{Error, Result};
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, EthernetFrame, EthernetProtocol,
+                    ArpPacket, ArpRepr, Ipv4Address};
+use board_misoc::clock;
+use capture;
+#[cfg(has_ethmac)]
+use board_misoc::ethmac::EthernetStatistics;
+
+// Statistics are gathered by the network loop and the scheduler, and read by the
+// management interface. The threads are cooperative, so plain statics suffice.
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCounters {
+    pub rx_frames: u64,
+    pub rx_bytes:  u64,
+    pub tx_frames: u64,
+    pub tx_bytes:  u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceCounters {
+    pub port:     u16,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Neighbor {
+    pub protocol_addr: Ipv4Address,
+    pub hardware_addr: EthernetAddress,
+    pub last_seen_ms:  u64,
+}
+
+pub const POLL_ERROR_KINDS: &'static [&'static str] = &[
+    "exhausted", "illegal", "unaddressable", "truncated", "checksum",
+    "unrecognized", "fragmented", "malformed", "dropped", "other"
+];
+
+const MAX_SERVICES:  usize = 8;
+const MAX_NEIGHBORS: usize = 16;
+
+static mut FRAMES: FrameCounters =
+    FrameCounters { rx_frames: 0, rx_bytes: 0, tx_frames: 0, tx_bytes: 0 };
+static mut POLL_ERRORS: [u32; 10] = [0; 10];
+static mut SERVICES: [Option<ServiceCounters>; MAX_SERVICES] = [None; MAX_SERVICES];
+static mut NEIGHBORS: [Option<Neighbor>; MAX_NEIGHBORS] = [None; MAX_NEIGHBORS];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MacErrors {
+    pub rx_preamble_errors: u32,
+    pub rx_crc_errors:      u32,
+    pub rx_dropped:         u32,
+}
+
+/// Receive errors counted by the Ethernet MAC gateware since startup.
+#[cfg(has_ethmac)]
+pub fn mac_errors() -> MacErrors {
+    let stats = EthernetStatistics::new();
+    MacErrors {
+        rx_preamble_errors: stats.rx_preamble_errors,
+        rx_crc_errors:      stats.rx_crc_errors,
+        rx_dropped:         stats.rx_dropped,
+    }
+}
+
+#[cfg(not(has_ethmac))]
+pub fn mac_errors() -> MacErrors {
+    MacErrors::default()
+}
+
+pub fn frames() -> FrameCounters {
+    unsafe { FRAMES }
+}
+
+/// Counts of errors returned by `EthernetInterface::poll`, in the order of
+/// `POLL_ERROR_KINDS`.
+pub fn poll_errors() -> [u32; 10] {
+    unsafe { POLL_ERRORS }
+}
+
+pub fn count_poll_error(error: &Error) {
+    let index = match *error {
+        Error::Exhausted     => 0,
+        Error::Illegal       => 1,
+        Error::Unaddressable => 2,
+        Error::Truncated     => 3,
+        Error::Checksum      => 4,
+        Error::Unrecognized  => 5,
+        Error::Fragmented    => 6,
+        Error::Malformed     => 7,
+        Error::Dropped       => 8,
+        _                    => 9,
+    };
+    unsafe { POLL_ERRORS[index] = POLL_ERRORS[index].wrapping_add(1) }
+}
+
+/// Bytes received and sent over TCP, per local port.
+pub fn services() -> [Option<ServiceCounters>; MAX_SERVICES] {
+    unsafe { SERVICES }
+}
+
+pub fn count_tcp(port: u16, rx_bytes: usize, tx_bytes: usize) {
+    let services = unsafe { &mut SERVICES };
+    let index = services.iter().position(|service| match *service {
+        Some(ServiceCounters { port: p, .. }) => p == port,
+        None => true
+    });
+    // Ports beyond the first MAX_SERVICES are not counted; the runtime has fewer.
+    if let Some(index) = index {
+        let service = services[index].get_or_insert(
+            ServiceCounters { port, rx_bytes: 0, tx_bytes: 0 });
+        service.rx_bytes += rx_bytes as u64;
+        service.tx_bytes += tx_bytes as u64;
+    }
+}
+
+/// Hosts whose addresses were learned from ARP traffic, which is also how
+/// they get into the neighbor cache of the interface.
+pub fn neighbors() -> [Option<Neighbor>; MAX_NEIGHBORS] {
+    unsafe { NEIGHBORS }
+}
+
+fn learn_neighbor(protocol_addr: Ipv4Address, hardware_addr: EthernetAddress) {
+    let neighbors = unsafe { &mut NEIGHBORS };
+    let now_ms = clock::get_ms();
+    let mut index = 0;
+    for (i, neighbor) in neighbors.iter().enumerate() {
+        match *neighbor {
+            Some(Neighbor { protocol_addr: addr, .. }) if addr == protocol_addr => {
+                index = i;
+                break
+            }
+            None => {
+                index = i;
+                break
+            }
+            // Otherwise, replace the least recently seen neighbor.
+            Some(Neighbor { last_seen_ms, .. }) => {
+                if let Some(oldest) = neighbors[index] {
+                    if last_seen_ms < oldest.last_seen_ms {
+                        index = i
+                    }
+                }
+            }
+        }
+    }
+    neighbors[index] = Some(Neighbor { protocol_addr, hardware_addr, last_seen_ms: now_ms });
+}
+
+fn record_rx(buffer: &[u8]) {
+    unsafe {
+        FRAMES.rx_frames += 1;
+        FRAMES.rx_bytes += buffer.len() as u64;
+    }
+    capture::record(buffer);
+
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return
+    };
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return
+    }
+    let packet = match ArpPacket::new_checked(frame.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return
+    };
+    match ArpRepr::parse(&packet) {
+        Ok(ArpRepr::EthernetIpv4 { source_hardware_addr, source_protocol_addr, .. }) => {
+            if source_hardware_addr.is_unicast() && !source_protocol_addr.is_unspecified() {
+                learn_neighbor(source_protocol_addr, source_hardware_addr)
+            }
+        }
+        _ => ()
+    }
+}
+
+fn record_tx(buffer: &[u8]) {
+    unsafe {
+        FRAMES.tx_frames += 1;
+        FRAMES.tx_bytes += buffer.len() as u64;
+    }
+    capture::record(buffer);
+}
+
+/// A device wrapper that counts the frames passing through it, learns
+/// neighbors from ARP traffic, and feeds packet captures.
+pub struct Monitor<D: for<'a> Device<'a>> {
+    inner: D
+}
+
+impl<D: for<'a> Device<'a>> Monitor<D> {
+    pub fn new(inner: D) -> Monitor<D> {
+        Monitor { inner }
+    }
+}
+
+impl<'a, D> Device<'a> for Monitor<D>
+        where D: for<'b> Device<'b> {
+    type RxToken = RxToken<<D as Device<'a>>::RxToken>;
+    type TxToken = TxToken<<D as Device<'a>>::TxToken>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        self.inner.receive().map(|(rx_token, tx_token)| {
+            (RxToken { token: rx_token }, TxToken { token: tx_token })
+        })
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        self.inner.transmit().map(|tx_token| TxToken { token: tx_token })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken<Rx: phy::RxToken> {
+    token: Rx
+}
+
+impl<Rx: phy::RxToken> phy::RxToken for RxToken<Rx> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+            where F: FnOnce(&[u8]) -> Result<R> {
+        self.token.consume(timestamp, |buffer| {
+            record_rx(buffer);
+            f(buffer)
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<Tx: phy::TxToken> {
+    token: Tx
+}
+
+impl<Tx: phy::TxToken> phy::TxToken for TxToken<Tx> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+            where F: FnOnce(&mut [u8]) -> Result<R> {
+        self.token.consume(timestamp, len, |buffer| {
+            let result = f(buffer);
+            if result.is_ok() {
+                record_tx(buffer);
+            }
+            result
+        })
+    }
+}
@@ -2,8 +2,14 @@
 
 use core::mem;
 use core::fmt;
+use core::ptr;
+use core::cmp::Ordering;
 use core::num::NonZeroUsize;
 use alloc::Vec;
+use io::{Write, Error as IoError};
+use board_misoc::cache;
+use proto_artiq::kernel_proto::{self as kern, PROFILE_SAMPLE_WORDS};
+use proto_artiq::pprof;
 use managed::ManagedMap;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -19,20 +25,34 @@ impl Address {
     }
 }
 
+// Number of samples the kernel CPU can take before the comms CPU has to drain them.
+const RING_LEN: usize = 256;
+
+// The ring buffer the kernel CPU writes samples to; see kernel_proto::PROFILE_SAMPLE_WORDS.
+// It is not allocated on the heap since the kernel CPU may keep writing to it
+// until the next kernel is loaded, even after the profiler is stopped.
+static mut RING: [usize; 1 + RING_LEN * PROFILE_SAMPLE_WORDS] =
+    [0; 1 + RING_LEN * PROFILE_SAMPLE_WORDS];
+
 pub struct Profile {
     hits:  Vec<Option<(Address, u32)>>,
     edges: Vec<Option<((Address, Address), u32)>>,
+    interval_us: u64,
+    kernel_cpu: bool,
+    // Count of samples read from RING.
+    tail:  usize,
 }
 
 impl fmt::Debug for Profile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Profile {{ hits: vec![...; {}], edges: vec![...; {}] }}",
-               self.hits.len(), self.edges.len())
+        write!(f, "Profile {{ hits: vec![...; {}], edges: vec![...; {}], kernel_cpu: {} }}",
+               self.hits.len(), self.edges.len(), self.kernel_cpu())
     }
 }
 
 impl Profile {
-    pub fn new(hits_size: usize, edges_size: usize) -> Profile {
+    pub fn new(interval_us: u64, hits_size: usize, edges_size: usize,
+               kernel_cpu: bool) -> Profile {
         let mut hits  = vec![None; hits_size];
         hits.shrink_to_fit();
         let mut edges = vec![None; edges_size];
@@ -40,18 +60,25 @@ impl Profile {
         Profile {
             hits:  hits.into(),
             edges: edges.into(),
+            interval_us: interval_us,
+            kernel_cpu: kernel_cpu,
+            tail:  0,
         }
     }
 
     pub fn overhead(&self) -> usize {
         let hit_size  = mem::size_of::<Option<(Address, u32)>>();
-        let edge_size = mem::size_of::<Option<(Address, u32)>>();
+        let edge_size = mem::size_of::<Option<((Address, Address), u32)>>();
         self.hits.capacity() * hit_size +
             self.edges.capacity() * edge_size
     }
 
     pub fn has_edges(&self) -> bool {
-        self.edges.is_empty()
+        !self.edges.is_empty()
+    }
+
+    pub fn kernel_cpu(&self) -> bool {
+        self.kernel_cpu
     }
 
     pub fn hits<'a>(&'a mut self) -> ManagedMap<'a, Address, u32> {
@@ -73,7 +100,6 @@ impl Profile {
         return Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn record_edge(&mut self, caller: Address, callee: Address) -> Result<(), ()> {
         let mut edges = self.edges();
         if let Some(count) = edges.get_mut(&(caller, callee)) {
@@ -84,13 +110,121 @@ impl Profile {
         }
         Ok(())
     }
+
+    fn hit_index(&self, addr: Address) -> Option<usize> {
+        self.hits.binary_search_by(|entry| {
+            match *entry {
+                Some((entry_addr, _)) => entry_addr.cmp(&addr),
+                None => Ordering::Greater
+            }
+        }).ok()
+    }
+
+    /// Writes the profile in the pprof format. Every edge becomes a sample with
+    /// a two-frame stack, and the hits that are not accounted for by any edge
+    /// become samples with a single frame.
+    pub fn write_pprof<W: Write>(&self, writer: W, ident: &str)
+                                -> Result<W, IoError<W::WriteError>> {
+        let hits  = || self.hits.iter().filter_map(|&entry| entry);
+        let edges = || self.edges.iter().filter_map(|&entry| entry)
+                                 .filter(move |&((_, callee), _)| self.hit_index(callee).is_some());
+
+        let mut self_counts: Vec<u32> = hits().map(|(_, count)| count).collect();
+        for ((_, callee), count) in edges() {
+            let index = self.hit_index(callee).unwrap();
+            self_counts[index] = self_counts[index].saturating_sub(count);
+        }
+
+        let mut encoder = pprof::Encoder::new(writer);
+        encoder.header(&pprof::Header {
+            binary:    if self.kernel_cpu { "kernel" } else { "runtime" },
+            ident:     ident,
+            period_ns: self.interval_us * 1000
+        })?;
+
+        for (addr, _) in hits() {
+            encoder.location(addr.as_raw() as u32)?;
+        }
+        // Edges are sorted by caller, so duplicates are adjacent.
+        let mut prev_caller = None;
+        for ((caller, _), _) in edges() {
+            if prev_caller != Some(caller) && self.hit_index(caller).is_none() {
+                encoder.location(caller.as_raw() as u32)?;
+            }
+            prev_caller = Some(caller);
+        }
+
+        for ((caller, callee), count) in edges() {
+            encoder.sample(&[callee.as_raw() as u32, caller.as_raw() as u32], count)?;
+        }
+        for ((addr, _), &count) in hits().zip(self_counts.iter()) {
+            if count > 0 {
+                encoder.sample(&[addr.as_raw() as u32], count)?;
+            }
+        }
+
+        Ok(encoder.into_inner())
+    }
+
+    // Prepares the ring buffer for a newly loaded kernel, and returns
+    // the message that makes the kernel CPU write samples to it.
+    fn kernel_request(&mut self, period: u32) -> kern::Message<'static> {
+        unsafe { ptr::write_volatile(&mut RING[0], 0) }
+        self.tail = 0;
+        kern::ProfileStart {
+            period:  period,
+            edges:   self.has_edges(),
+            samples: unsafe { RING.as_mut_ptr() },
+            len:     RING_LEN
+        }
+    }
+
+    // Records the samples the kernel CPU has written since the last call.
+    // Addresses in the kernel are recorded relative to KERNELCPU_PAYLOAD_ADDRESS;
+    // time spent in ksupport is attributed to its caller in the kernel, if any.
+    fn drain_kernel(&mut self) -> Result<(), ()> {
+        fn kernel_address(pc: usize) -> Option<Address> {
+            if pc > kern::KERNELCPU_PAYLOAD_ADDRESS {
+                Some(Address::new(pc - kern::KERNELCPU_PAYLOAD_ADDRESS))
+            } else {
+                None
+            }
+        }
+
+        cache::flush_cpu_dcache();
+        let head = unsafe { ptr::read_volatile(&RING[0]) };
+        if head.wrapping_sub(self.tail) > RING_LEN {
+            // The kernel CPU has overwritten samples that were not drained in time.
+            self.tail = head - RING_LEN;
+        }
+
+        let mut result = Ok(());
+        while self.tail != head && result.is_ok() {
+            let offset = 1 + (self.tail % RING_LEN) * PROFILE_SAMPLE_WORDS;
+            let (pc, caller) = unsafe {
+                (ptr::read_volatile(&RING[offset]),
+                 ptr::read_volatile(&RING[offset + 1]))
+            };
+            self.tail += 1;
+
+            result = match (kernel_address(pc), kernel_address(caller)) {
+                (Some(pc), Some(caller)) if self.has_edges() =>
+                    self.record_hit(pc).and_then(|()| self.record_edge(caller, pc)),
+                (Some(pc), _) | (None, Some(pc)) =>
+                    self.record_hit(pc),
+                (None, None) => Ok(())
+            };
+        }
+        result
+    }
 }
 
 #[cfg(has_timer1)]
 mod imp {
-    use unwind_backtrace::backtrace;
+    use unwind_backtrace::backtrace_limit;
     use board_misoc::{csr, irq};
-    use super::{Address, Profile};
+    use proto_artiq::kernel_proto as kern;
+    use super::{Address, Profile, RING_LEN};
 
     static mut PROFILE: Option<Profile> = None;
 
@@ -135,17 +269,33 @@ mod imp {
 
     use self::lock::Lock;
 
-    pub fn start(interval_us: u64, hits_size: usize, edges_size: usize) -> Result<(), ()> {
+    static mut KERNEL_PERIOD: u32 = 0;
+
+    pub fn start(interval_us: u64, hits_size: usize, edges_size: usize,
+                 kernel_cpu: bool) -> Result<(), ()> {
         stop();
 
-        let profile = Profile::new(hits_size, edges_size);
-        info!("starting at {}us interval using {} heap bytes",
+        let mut reload = csr::CONFIG_CLOCK_FREQUENCY as u64 * interval_us / 1_000_000;
+        if kernel_cpu {
+            // The kernel CPU tick timer period is 28 bits wide.
+            if reload == 0 || reload > 0x0fff_ffff {
+                error!("interval of {}us cannot be used on the kernel CPU", interval_us);
+                return Err(())
+            }
+            unsafe { KERNEL_PERIOD = reload as u32 }
+            // Sample the kernel CPU at the requested interval, and drain its samples
+            // often enough that the ring buffer never fills up.
+            reload = reload * RING_LEN as u64 / 4;
+        }
+
+        let profile = Profile::new(interval_us, hits_size, edges_size, kernel_cpu);
+        info!("starting {} at {}us interval using {} heap bytes",
+              if kernel_cpu { "on kernel CPU" } else { "on comms CPU" },
               interval_us, profile.overhead());
 
         *Lock::take().expect("cannot lock") = Some(profile);
 
         unsafe {
-            let reload = csr::CONFIG_CLOCK_FREQUENCY as u64 * interval_us / 1_000_000;
             csr::timer1::load_write(reload);
             csr::timer1::reload_write(reload);
             csr::timer1::ev_pending_write(1);
@@ -183,6 +333,11 @@ mod imp {
 
             let result = {
                 let mut profile = Lock::take().expect("cannot lock");
+                if let Some(ref mut profile) = *profile {
+                    if profile.kernel_cpu() && profile.drain_kernel().is_err() {
+                        warn!("out of space");
+                    }
+                }
                 f(profile.as_mut())
             };
 
@@ -193,6 +348,18 @@ mod imp {
         }
     }
 
+    /// Returns the message that has to be sent to the kernel CPU before loading
+    /// a kernel, if the kernel CPU is being profiled.
+    pub fn kernel_request() -> Option<kern::Message<'static>> {
+        pause(|profile| {
+            match profile {
+                Some(ref mut profile) if profile.kernel_cpu() =>
+                    Some(profile.kernel_request(unsafe { KERNEL_PERIOD })),
+                _ => None
+            }
+        })
+    }
+
     // Skip frames: ::profiler::sample, ::exception, exception vector.
     const SKIP_FRAMES: i32 = 3;
 
@@ -207,7 +374,8 @@ mod imp {
         // with the https://reviews.llvm.org/D46971 patch applied.
         if profile.has_edges() {
             let mut prev_pc = 0;
-            let _ = backtrace(|pc| {
+            // Only the interrupted frame and its caller are needed for an edge.
+            let _ = backtrace_limit(SKIP_FRAMES as usize + 2, |pc| {
                 // Backtrace gives us the return address, i.e. the address after the delay slot,
                 // but we're interested in the call instruction, *except* when going through
                 // the frame directly below the exception frame, which has the address that's
@@ -242,7 +410,12 @@ mod imp {
     pub fn sample(pc: usize) {
         let result = {
             let mut profile = Lock::take().expect("cannot lock");
-            record(profile.as_mut().expect("profiler not running"), pc)
+            let profile = profile.as_mut().expect("profiler not running");
+            if profile.kernel_cpu() {
+                profile.drain_kernel()
+            } else {
+                record(profile, pc)
+            }
         };
 
         if result.is_err() {
@@ -260,7 +433,10 @@ mod imp {
 mod imp {
     #![allow(dead_code)]
 
-    pub fn start(_interval_us: u64, _hits_size: usize, _edges_size: usize) -> Result<(), ()> {
+    use proto_artiq::kernel_proto as kern;
+
+    pub fn start(_interval_us: u64, _hits_size: usize, _edges_size: usize,
+                 _kernel_cpu: bool) -> Result<(), ()> {
         error!("timer not available");
 
         Err(())
@@ -272,6 +448,10 @@ mod imp {
         f(None)
     }
 
+    pub fn kernel_request() -> Option<kern::Message<'static>> {
+        None
+    }
+
     pub fn sample(_pc: usize) {}
 }
 
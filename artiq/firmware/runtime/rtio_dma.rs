@@ -1,12 +1,50 @@
-use core::mem;
+use core::{mem, cmp, slice, fmt};
 use alloc::{Vec, String, BTreeMap};
 
 const ALIGNMENT: usize = 64;
 
+// The DMA core requires traces to be aligned; allocating them in units of
+// an aligned type makes the allocator take care of that.
+#[repr(align(64))]
+#[derive(Clone, Copy)]
+struct Chunk([u8; ALIGNMENT]);
+
+#[derive(Default)]
+struct Trace {
+    chunks: Vec<Chunk>,
+    len: usize
+}
+
+impl Trace {
+    fn extend_from_slice(&mut self, mut data: &[u8]) {
+        self.chunks.reserve((data.len() + ALIGNMENT - 1) / ALIGNMENT);
+        while !data.is_empty() {
+            let offset = self.len % ALIGNMENT;
+            if offset == 0 {
+                self.chunks.push(Chunk([0; ALIGNMENT]))
+            }
+            let chunk = &mut self.chunks.last_mut().unwrap().0[offset..];
+            let len = cmp::min(chunk.len(), data.len());
+            chunk[..len].copy_from_slice(&data[..len]);
+            self.len += len;
+            data = &data[len..];
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.chunks.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Trace {{ len: {} }}", self.len)
+    }
+}
+
 #[derive(Debug)]
 struct Entry {
-    trace: Vec<u8>,
-    padding_len: usize,
+    trace: Trace,
     duration: u64
 }
 
@@ -14,7 +52,7 @@ struct Entry {
 pub struct Manager {
     entries: BTreeMap<String, Entry>,
     recording_name: String,
-    recording_trace: Vec<u8>
+    recording_trace: Trace
 }
 
 impl Manager {
@@ -22,13 +60,13 @@ impl Manager {
         Manager {
             entries: BTreeMap::new(),
             recording_name: String::new(),
-            recording_trace: Vec::new(),
+            recording_trace: Trace::default(),
         }
     }
 
     pub fn record_start(&mut self, name: &str) {
         self.recording_name = String::from(name);
-        self.recording_trace = Vec::new();
+        self.recording_trace = Trace::default();
 
         // or we could needlessly OOM replacing a large trace
         self.entries.remove(name);
@@ -39,28 +77,14 @@ impl Manager {
     }
 
     pub fn record_stop(&mut self, duration: u64) {
-        let mut trace = Vec::new();
+        let mut trace = Trace::default();
         mem::swap(&mut self.recording_trace, &mut trace);
-        trace.push(0);
-        let data_len = trace.len();
-
-        // Realign.
-        trace.reserve(ALIGNMENT - 1);
-        let padding = ALIGNMENT - trace.as_ptr() as usize % ALIGNMENT;
-        let padding = if padding == ALIGNMENT { 0 } else { padding };
-        for _ in 0..padding {
-            // Vec guarantees that this will not reallocate
-            trace.push(0)
-        }
-        for i in 1..data_len + 1 {
-            trace[data_len + padding - i] = trace[data_len - i]
-        }
+        trace.extend_from_slice(&[0]);
 
         let mut name = String::new();
         mem::swap(&mut self.recording_name, &mut name);
         self.entries.insert(name, Entry {
             trace: trace,
-            padding_len: padding,
             duration: duration
         });
     }
@@ -72,7 +96,7 @@ impl Manager {
     pub fn with_trace<F, R>(&self, name: &str, f: F) -> R
             where F: FnOnce(Option<&[u8]>, u64) -> R {
         match self.entries.get(name) {
-            Some(entry) => f(Some(&entry.trace[entry.padding_len..]), entry.duration),
+            Some(entry) => f(Some(entry.trace.as_slice()), entry.duration),
             None => f(None, 0)
         }
     }
@@ -1,6 +1,4 @@
 use board_misoc::csr;
-#[cfg(has_rtio_clock_switch)]
-use board_misoc::config;
 use sched::Io;
 
 #[cfg(has_rtio_crg)]
@@ -46,10 +44,14 @@ pub mod drtio {
         unsafe {
             csr::drtio_transceiver::stable_clkin_write(1);
         }
-        io.spawn(4096, link_thread);
+        io.spawn("drtio_link", 4096, link_thread);
     }
 
-    fn link_rx_up(linkno: u8) -> bool {
+    pub fn link_count() -> usize {
+        csr::DRTIO.len()
+    }
+
+    pub fn link_rx_up(linkno: u8) -> bool {
         let linkno = linkno as usize;
         unsafe {
             (csr::DRTIO[linkno].rx_up_read)() == 1
@@ -231,6 +233,8 @@ pub mod drtio {
 
     pub fn startup(_io: &Io) {}
     pub fn init() {}
+    pub fn link_count() -> usize { 0 }
+    pub fn link_rx_up(_linkno: u8) -> bool { false }
     pub fn link_up(_linkno: u8) -> bool { false }
 }
 
@@ -256,6 +260,70 @@ fn async_error_thread(io: Io) {
     }
 }
 
+#[cfg(all(has_rtio_crg, has_rtio_clock_switch))]
+mod clock_switch {
+    use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+    use board_misoc::config;
+    use sched::Io;
+    use kernel;
+    use super::{crg, init_core};
+
+    #[derive(Debug)]
+    enum RtioClock {
+        Internal = 0,
+        External = 1
+    }
+
+    static PENDING: AtomicBool = ATOMIC_BOOL_INIT;
+
+    fn read_config() -> RtioClock {
+        config::read("rtio_clock", |result| {
+            match result {
+                Ok(b"i") => {
+                    info!("using internal RTIO clock");
+                    RtioClock::Internal
+                },
+                Ok(b"e") => {
+                    info!("using external RTIO clock");
+                    RtioClock::External
+                },
+                _ => {
+                    info!("using internal RTIO clock (by default)");
+                    RtioClock::Internal
+                },
+            }
+        })
+    }
+
+    pub fn init() {
+        if !crg::init(read_config() as u8) {
+            error!("RTIO clock failed");
+        }
+    }
+
+    fn thread(io: Io) {
+        loop {
+            // Switching the clock while a kernel is running would corrupt its timeline.
+            io.until(|| PENDING.load(Ordering::SeqCst) && !kernel::is_running()).unwrap();
+            PENDING.store(false, Ordering::SeqCst);
+
+            info!("switching RTIO clock");
+            init();
+            init_core(true);
+        }
+    }
+
+    pub fn startup(io: &Io) {
+        config::subscribe("rtio_clock", |_| {
+            if kernel::is_running() {
+                info!("RTIO clock will be switched once the kernel finishes");
+            }
+            PENDING.store(true, Ordering::SeqCst)
+        });
+        io.spawn("rtio_clock_switch", 4096, thread);
+    }
+}
+
 pub fn startup(io: &Io) {
     // The RTIO CRG may depend on the DRTIO transceiver clock.
     // Initialize DRTIO first to bring up transceiver clocking.
@@ -265,32 +333,8 @@ pub fn startup(io: &Io) {
     {
         #[cfg(has_rtio_clock_switch)]
         {
-            #[derive(Debug)]
-            enum RtioClock {
-                Internal = 0,
-                External = 1
-            };
-
-            let clk = config::read("rtio_clock", |result| {
-                match result {
-                    Ok(b"i") => {
-                        info!("using internal RTIO clock");
-                        RtioClock::Internal
-                    },
-                    Ok(b"e") => {
-                        info!("using external RTIO clock");
-                        RtioClock::External
-                    },
-                    _ => {
-                        info!("using internal RTIO clock (by default)");
-                        RtioClock::Internal
-                    },
-                }
-            });
-
-            if !crg::init(clk as u8) {
-                error!("RTIO clock failed");
-            }
+            clock_switch::init();
+            clock_switch::startup(io);
         }
         #[cfg(not(has_rtio_clock_switch))]
         {
@@ -301,7 +345,7 @@ pub fn startup(io: &Io) {
     }
 
     init_core(true);
-    io.spawn(4096, async_error_thread);
+    io.spawn("rtio_async_error", 4096, async_error_thread);
 }
 
 pub fn init_core(phy: bool) {
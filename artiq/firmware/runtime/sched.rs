@@ -4,16 +4,17 @@ use core::mem;
 use core::result;
 use core::cell::{Cell, RefCell};
 use alloc::Vec;
-use fringe::OwnedStack;
+use fringe::{OwnedStack, Stack};
 use fringe::generator::{Generator, Yielder, State as GeneratorState};
 use smoltcp::time::Duration;
 use smoltcp::Error as NetworkError;
 use smoltcp::wire::IpEndpoint;
-use smoltcp::socket::{SocketHandle, SocketRef};
+use smoltcp::socket::{Socket, SocketHandle, SocketRef, TcpState};
 
 use io::{Read, Write};
 use board_misoc::clock;
 use urc::Urc;
+use {net_acl, net_stats};
 
 #[derive(Fail, Debug)]
 pub enum Error {
@@ -36,7 +37,9 @@ type SocketSet = ::smoltcp::socket::SocketSet<'static, 'static, 'static>;
 #[derive(Debug)]
 struct WaitRequest {
     event:   Option<*mut FnMut() -> bool>,
-    timeout: Option<u64>
+    timeout: Option<u64>,
+    // The local and remote endpoints of the socket the event depends on, if any.
+    socket:  Option<(IpEndpoint, IpEndpoint)>
 }
 
 unsafe impl Send for WaitRequest {}
@@ -48,6 +51,16 @@ enum WaitResult {
     Interrupted
 }
 
+// Unused stack memory holds this pattern, so that stack usage can be measured.
+const STACK_PAINT: usize = 0x5354_4b21;
+
+// The lowest words of every stack hold this pattern, and are checked whenever
+// the thread is suspended. Without an MMU, there is no guard page, and a thread
+// that runs past its stack would otherwise silently overwrite the heap.
+const STACK_CANARY: usize = 0xdead_c0de;
+const STACK_CANARY_WORDS: usize = 4;
+const STACK_CANARY_SIZE: usize = STACK_CANARY_WORDS * mem::size_of::<usize>();
+
 #[derive(Debug)]
 struct Thread {
     generator:   Generator<WaitResult, WaitRequest, OwnedStack>,
@@ -55,24 +68,77 @@ struct Thread {
     interrupted: bool
 }
 
+// The parts of a thread that do not change while it is running.
+#[derive(Debug)]
+struct ThreadInfo {
+    name:        &'static str,
+    stack_limit: usize,
+    stack_base:  usize
+}
+
+impl ThreadInfo {
+    fn canary(&self) -> *mut [usize; STACK_CANARY_WORDS] {
+        self.stack_limit as *mut [usize; STACK_CANARY_WORDS]
+    }
+
+    fn check_canary(&self) {
+        let canary = unsafe { *self.canary() };
+        if canary.iter().any(|&word| word != STACK_CANARY) {
+            panic!("stack overflow in thread {} (stack size {} bytes)",
+                   self.name, self.stack_size())
+        }
+    }
+
+    // The usable part of the stack, excluding the canary.
+    fn stack_size(&self) -> usize {
+        self.stack_base - self.stack_limit - STACK_CANARY_SIZE
+    }
+
+    // The most stack the thread has ever used, found by looking for
+    // the lowest word that no longer holds STACK_PAINT.
+    fn stack_used(&self) -> usize {
+        let mut addr = self.stack_limit + STACK_CANARY_SIZE;
+        while addr < self.stack_base &&
+                unsafe { *(addr as *const usize) } == STACK_PAINT {
+            addr += mem::size_of::<usize>()
+        }
+        self.stack_base - addr
+    }
+}
+
 impl Thread {
-    unsafe fn new<F>(io: &Io, stack_size: usize, f: F) -> ThreadHandle
+    unsafe fn new<F>(io: &Io, name: &'static str, stack_size: usize, f: F) -> ThreadHandle
             where F: 'static + FnOnce(Io) + Send {
+        let threads = io.threads.clone();
         let spawned = io.spawned.clone();
         let sockets = io.sockets.clone();
 
         let stack = OwnedStack::new(stack_size);
-        ThreadHandle::new(Thread {
+        let info = ThreadInfo {
+            name:        name,
+            stack_limit: stack.limit() as usize,
+            stack_base:  stack.base() as usize
+        };
+        let mut addr = info.stack_limit;
+        while addr < info.stack_base {
+            *(addr as *mut usize) = STACK_PAINT;
+            addr += mem::size_of::<usize>()
+        }
+        *info.canary() = [STACK_CANARY; STACK_CANARY_WORDS];
+
+        ThreadHandle::new(info, Thread {
             generator: Generator::unsafe_new(stack, |yielder, _| {
                 f(Io {
                     yielder: Some(yielder),
+                    threads: threads,
                     spawned: spawned,
                     sockets: sockets
                 })
             }),
             waiting_for: WaitRequest {
                 event:   None,
-                timeout: None
+                timeout: None,
+                socket:  None
             },
             interrupted: false
         })
@@ -92,30 +158,86 @@ impl Thread {
 }
 
 #[derive(Clone)]
-pub struct ThreadHandle(Urc<RefCell<Thread>>);
+pub struct ThreadHandle(Urc<(ThreadInfo, RefCell<Thread>)>);
 
 impl ThreadHandle {
-    fn new(thread: Thread) -> ThreadHandle {
-        ThreadHandle(Urc::new(RefCell::new(thread)))
+    fn new(info: ThreadInfo, thread: Thread) -> ThreadHandle {
+        ThreadHandle(Urc::new((info, RefCell::new(thread))))
+    }
+
+    fn info(&self) -> &ThreadInfo {
+        &(self.0).0
+    }
+
+    fn thread(&self) -> &RefCell<Thread> {
+        &(self.0).1
     }
 
     pub fn terminated(&self) -> bool {
-        match self.0.try_borrow() {
+        match self.thread().try_borrow() {
             Ok(thread) => thread.terminated(),
             Err(_) => false // the running thread hasn't terminated
         }
     }
 
     pub fn interrupt(&self) {
-        match self.0.try_borrow_mut() {
+        match self.thread().try_borrow_mut() {
             Ok(mut thread) => thread.interrupt(),
             Err(_) => panic!("cannot interrupt the running thread")
         }
     }
+
+    fn status(&self, now: u64) -> ThreadStatus {
+        let info = self.info();
+        let state = match self.thread().try_borrow() {
+            Err(_) => ThreadState::Running,
+            Ok(thread) => {
+                let WaitRequest { event, timeout, socket } = thread.waiting_for;
+                match (socket, event, timeout) {
+                    (Some((local, remote)), _, _) => ThreadState::Socket { local, remote },
+                    (None, Some(_), _) => ThreadState::Event,
+                    (None, None, Some(instant)) =>
+                        ThreadState::Timeout { remaining_ms: instant.saturating_sub(now) },
+                    (None, None, None) => ThreadState::Ready
+                }
+            }
+        };
+        ThreadStatus {
+            name:       info.name,
+            stack_size: info.stack_size(),
+            stack_used: info.stack_used(),
+            state:      state
+        }
+    }
+}
+
+/// What a thread is waiting for.
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadState {
+    /// The thread is the one asking.
+    Running,
+    /// The thread will be resumed at the next opportunity.
+    Ready,
+    /// The thread is waiting for a condition that does not involve a socket.
+    Event,
+    /// The thread is sleeping.
+    Timeout { remaining_ms: u64 },
+    /// The thread is waiting for a TCP socket to become readable, writable,
+    /// connected or closed.
+    Socket { local: IpEndpoint, remote: IpEndpoint }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadStatus {
+    pub name:       &'static str,
+    pub stack_size: usize,
+    /// The high-water mark of stack usage.
+    pub stack_used: usize,
+    pub state:      ThreadState
 }
 
 pub struct Scheduler {
-    threads: Vec<ThreadHandle>,
+    threads: Urc<RefCell<Vec<ThreadHandle>>>,
     spawned: Urc<RefCell<Vec<ThreadHandle>>>,
     sockets: Urc<RefCell<SocketSet>>,
     run_idx: usize,
@@ -124,7 +246,7 @@ pub struct Scheduler {
 impl Scheduler {
     pub fn new() -> Scheduler {
         Scheduler {
-            threads: Vec::new(),
+            threads: Urc::new(RefCell::new(Vec::new())),
             spawned: Urc::new(RefCell::new(Vec::new())),
             sockets: Urc::new(RefCell::new(SocketSet::new(Vec::new()))),
             run_idx: 0,
@@ -134,6 +256,7 @@ impl Scheduler {
     pub fn io(&self) -> Io<'static> {
         Io {
             yielder: None,
+            threads: self.threads.clone(),
             spawned: self.spawned.clone(),
             sockets: self.sockets.clone()
         }
@@ -142,17 +265,24 @@ impl Scheduler {
     pub fn run(&mut self) {
         self.sockets.borrow_mut().prune();
 
-        self.threads.append(&mut *self.spawned.borrow_mut());
-        if self.threads.len() == 0 { return }
+        let thread_count = {
+            let mut threads = self.threads.borrow_mut();
+            threads.append(&mut *self.spawned.borrow_mut());
+            threads.len()
+        };
+        if thread_count == 0 { return }
 
         let now = clock::get_ms();
         let start_idx = self.run_idx;
         loop {
-            self.run_idx = (self.run_idx + 1) % self.threads.len();
+            self.run_idx = (self.run_idx + 1) % thread_count;
 
+            // Don't keep the thread list borrowed while the thread runs, so that
+            // it can inspect it.
+            let handle = self.threads.borrow()[self.run_idx].clone();
             let result = {
                 let &mut Thread { ref mut generator, ref mut interrupted, ref waiting_for } =
-                    &mut *self.threads[self.run_idx].0.borrow_mut();
+                    &mut *handle.thread().borrow_mut();
                 if *interrupted {
                     *interrupted = false;
                     generator.resume(WaitResult::Interrupted)
@@ -170,15 +300,18 @@ impl Scheduler {
                 }
             };
 
+            // Catch stack overflows before the corrupted memory is used.
+            handle.info().check_canary();
+
             match result {
                 None => {
                     // The thread has terminated.
-                    self.threads.remove(self.run_idx);
+                    self.threads.borrow_mut().remove(self.run_idx);
                     self.run_idx = 0
                 },
                 Some(wait_request) => {
                     // The thread has suspended itself.
-                    let mut thread = self.threads[self.run_idx].0.borrow_mut();
+                    let mut thread = handle.thread().borrow_mut();
                     thread.waiting_for = wait_request
                 }
             }
@@ -195,14 +328,15 @@ impl Scheduler {
 #[derive(Clone)]
 pub struct Io<'a> {
     yielder: Option<&'a Yielder<WaitResult, WaitRequest, OwnedStack>>,
+    threads: Urc<RefCell<Vec<ThreadHandle>>>,
     spawned: Urc<RefCell<Vec<ThreadHandle>>>,
     sockets: Urc<RefCell<SocketSet>>,
 }
 
 impl<'a> Io<'a> {
-    pub fn spawn<F>(&self, stack_size: usize, f: F) -> ThreadHandle
+    pub fn spawn<F>(&self, name: &'static str, stack_size: usize, f: F) -> ThreadHandle
             where F: 'static + FnOnce(Io) + Send {
-        let handle = unsafe { Thread::new(self, stack_size, f) };
+        let handle = unsafe { Thread::new(self, name, stack_size, f) };
         self.spawned.borrow_mut().push(handle.clone());
         handle
     }
@@ -214,7 +348,8 @@ impl<'a> Io<'a> {
     pub fn sleep(&self, duration_ms: u64) -> Result<(), Error> {
         let request = WaitRequest {
             timeout: Some(clock::get_ms() + duration_ms),
-            event:   None
+            event:   None,
+            socket:  None
         };
 
         match self.yielder().suspend(request) {
@@ -235,7 +370,8 @@ impl<'a> Io<'a> {
     pub fn relinquish(&self) -> Result<(), Error> {
         self.suspend(WaitRequest {
             timeout: None,
-            event:   None
+            event:   None,
+            socket:  None
         })
     }
 
@@ -243,7 +379,24 @@ impl<'a> Io<'a> {
         let f = unsafe { mem::transmute::<&mut FnMut() -> bool, *mut FnMut() -> bool>(&mut f) };
         self.suspend(WaitRequest {
             timeout: None,
-            event:   Some(f)
+            event:   Some(f),
+            socket:  None
+        })
+    }
+
+    // Like `until`, but records the socket being waited for.
+    fn until_socket<F: FnMut() -> bool>(&self, handle: SocketHandle, mut f: F)
+                                        -> Result<(), Error> {
+        let endpoints = {
+            let mut sockets = self.sockets.borrow_mut();
+            let socket = sockets.get::<TcpSocketLower>(handle);
+            (socket.local_endpoint(), socket.remote_endpoint())
+        };
+        let f = unsafe { mem::transmute::<&mut FnMut() -> bool, *mut FnMut() -> bool>(&mut f) };
+        self.suspend(WaitRequest {
+            timeout: None,
+            event:   Some(f),
+            socket:  Some(endpoints)
         })
     }
 
@@ -263,12 +416,33 @@ impl<'a> Io<'a> {
     pub fn join(&self, handle: ThreadHandle) -> Result<(), Error> {
         self.until(move || handle.terminated())
     }
+
+    /// The local and remote endpoints and the state of every TCP socket.
+    pub fn tcp_sockets(&self) -> Vec<(IpEndpoint, IpEndpoint, TcpState)> {
+        self.sockets.borrow().iter().filter_map(|socket| {
+            match *socket {
+                Socket::Tcp(ref socket) =>
+                    Some((socket.local_endpoint(), socket.remote_endpoint(), socket.state())),
+                _ => None
+            }
+        }).collect()
+    }
+
+    /// The name, stack usage and state of every thread.
+    pub fn threads(&self) -> Vec<ThreadStatus> {
+        let now = clock::get_ms();
+        let threads = self.threads.borrow();
+        let spawned = self.spawned.borrow();
+        threads.iter().chain(spawned.iter())
+               .map(|handle| handle.status(now))
+               .collect()
+    }
 }
 
 macro_rules! until {
     ($socket:expr, $ty:ty, |$var:ident| $cond:expr) => ({
         let (sockets, handle) = ($socket.io.sockets.clone(), $socket.handle);
-        $socket.io.until(move || {
+        $socket.io.until_socket(handle, move || {
             let mut sockets = sockets.borrow_mut();
             let $var = sockets.get::<$ty>(handle);
             $cond
@@ -319,8 +493,13 @@ impl<'a> TcpListener<'a> {
         self.with_lower(|s| s.is_open())
     }
 
+    /// Whether there is a connection to accept. This doesn't check whether the
+    /// remote end is in `allowed_hosts`; `accept` and `try_accept` do.
     pub fn can_accept(&self) -> bool {
-        self.with_lower(|s| s.is_active())
+        // At least one half of the connection has to be open. This handles the case
+        // where a remote socket immediately sends a FIN--that still counts as
+        // accepting even though nothing may be sent.
+        self.with_lower(|s| s.may_send() || s.may_recv())
     }
 
     pub fn local_endpoint(&self) -> IpEndpoint {
@@ -337,27 +516,62 @@ impl<'a> TcpListener<'a> {
             .map_err(|err| err.into())
     }
 
+    /// Waits for a connection from a host in `allowed_hosts`, rejecting any others.
     pub fn accept(&self) -> Result<TcpStream<'a>, Error> {
-        // We're waiting until at least one half of the connection becomes open.
-        // This handles the case where a remote socket immediately sends a FIN--
-        // that still counts as accepting even though nothing may be sent.
-        let (sockets, handle) = (self.io.sockets.clone(), self.handle.get());
-        self.io.until(move || {
-            let mut sockets = sockets.borrow_mut();
-            let socket = sockets.get::<TcpSocketLower>(handle);
-            socket.may_send() || socket.may_recv()
-        })?;
+        loop {
+            let (sockets, handle) = (self.io.sockets.clone(), self.handle.get());
+            self.io.until_socket(handle, move || {
+                let mut sockets = sockets.borrow_mut();
+                let socket = sockets.get::<TcpSocketLower>(handle);
+                socket.may_send() || socket.may_recv()
+            })?;
+
+            if let Some(stream) = self.try_accept() {
+                return Ok(stream)
+            }
+        }
+    }
+
+    /// Like `accept`, but returns `None` instead of waiting if there is no
+    /// connection to accept, or if it was rejected.
+    pub fn try_accept(&self) -> Option<TcpStream<'a>> {
+        if !self.can_accept() || self.reject_disallowed() {
+            return None
+        }
 
         let accepted = self.handle.get();
+        self.listen_again();
+        Some(TcpStream {
+            io:     self.io,
+            handle: accepted
+        })
+    }
+
+    // Replaces the socket, which has been handed over to a connection, with
+    // a fresh one listening on the same endpoint.
+    fn listen_again(&self) {
         self.handle.set(Self::new_lower(self.io, self.buffer_size.get()));
         match self.listen(self.endpoint.get()) {
             Ok(()) => (),
             _ => unreachable!()
         }
-        Ok(TcpStream {
-            io:     self.io,
-            handle: accepted
-        })
+    }
+
+    // If the remote end of the incoming connection is not in `allowed_hosts`,
+    // resets the connection and returns true.
+    fn reject_disallowed(&self) -> bool {
+        let remote = self.with_lower(|s| s.remote_endpoint());
+        if net_acl::is_allowed(remote.addr) {
+            return false
+        }
+
+        warn!("rejected connection from {} to port {}: host is not in `allowed_hosts`",
+              remote, self.endpoint.get().port);
+        net_acl::count_rejected();
+        self.with_lower(|mut s| s.abort());
+        self.io.sockets.borrow_mut().release(self.handle.get());
+        self.listen_again();
+        true
     }
 
     pub fn close(&self) {
@@ -452,10 +666,13 @@ impl<'a> TcpStream<'a> {
     }
 }
 
-impl<'a> Read for TcpStream<'a> {
-    type ReadError = Error;
+impl<'a> TcpStream<'a> {
+    fn count(&self, rx_bytes: usize, tx_bytes: usize) {
+        let port = self.with_lower(|s| s.local_endpoint().port);
+        net_stats::count_tcp(port, rx_bytes, tx_bytes)
+    }
 
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::ReadError> {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         // Only borrow the underlying socket for the span of the next statement.
         let result = self.with_lower(|mut s| s.recv_slice(buf));
         match result {
@@ -476,13 +693,8 @@ impl<'a> Read for TcpStream<'a> {
             Err(_) => unreachable!()
         }
     }
-}
-
-impl<'a> Write for TcpStream<'a> {
-    type WriteError = Error;
-    type FlushError = Error;
 
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::WriteError> {
+    fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
         // Only borrow the underlying socket for the span of the next statement.
         let result = self.with_lower(|mut s| s.send_slice(buf));
         match result {
@@ -503,6 +715,27 @@ impl<'a> Write for TcpStream<'a> {
             Err(_) => unreachable!()
         }
     }
+}
+
+impl<'a> Read for TcpStream<'a> {
+    type ReadError = Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::ReadError> {
+        let length = self.recv(buf)?;
+        self.count(length, 0);
+        Ok(length)
+    }
+}
+
+impl<'a> Write for TcpStream<'a> {
+    type WriteError = Error;
+    type FlushError = Error;
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::WriteError> {
+        let length = self.send(buf)?;
+        self.count(0, length);
+        Ok(length)
+    }
 
     fn flush(&mut self) -> Result<(), Self::FlushError> {
         until!(self, TcpSocketLower, |s|  s.send_queue() == 0 || !s.may_send())?;
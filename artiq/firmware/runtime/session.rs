@@ -4,7 +4,7 @@ use byteorder::{ByteOrder, NetworkEndian};
 
 use io::{Read, Write, Error as IoError};
 use board_misoc::{ident, cache, config};
-use {mailbox, rpc_queue, kernel};
+use {mailbox, rpc_queue, kernel, crash_log, profiler};
 use urc::Urc;
 use sched::{ThreadHandle, Io, TcpListener, TcpStream, Error as SchedError};
 #[cfg(has_rtio_core)]
@@ -75,7 +75,7 @@ impl Congress {
             now: 0,
             cache: Cache::new(),
             dma_manager: DmaManager::new(),
-            finished_cleanly: Cell::new(true)
+            finished_cleanly: Cell::new(!crash_log::crashed_last_boot())
         }
     }
 }
@@ -211,6 +211,9 @@ unsafe fn kern_load(io: &Io, session: &mut Session, library: &[u8])
 
     kernel::start();
 
+    if let Some(request) = profiler::kernel_request() {
+        kern_send(io, &request)?;
+    }
     kern_send(io, &kern::LoadRequest(&library))?;
     kern_recv(io, |reply| {
         match reply {
@@ -573,7 +576,7 @@ fn flash_kernel_worker(io: &Io,
     }
 }
 
-fn respawn<F>(io: &Io, handle: &mut Option<ThreadHandle>, f: F)
+fn respawn<F>(io: &Io, name: &'static str, handle: &mut Option<ThreadHandle>, f: F)
         where F: 'static + FnOnce(Io) + Send {
     match handle.take() {
         None => (),
@@ -585,7 +588,7 @@ fn respawn<F>(io: &Io, handle: &mut Option<ThreadHandle>, f: F)
         }
     }
 
-    *handle = Some(io.spawn(16384, f))
+    *handle = Some(io.spawn(name, 16384, f))
 }
 
 pub fn thread(io: Io) {
@@ -598,7 +601,7 @@ pub fn thread(io: Io) {
     let mut kernel_thread = None;
     {
         let congress = congress.clone();
-        respawn(&io, &mut kernel_thread, move |io| {
+        respawn(&io, "startup_kernel", &mut kernel_thread, move |io| {
             let mut congress = congress.borrow_mut();
             info!("running startup kernel");
             match flash_kernel_worker(&io, &mut congress, "startup_kernel") {
@@ -615,8 +618,7 @@ pub fn thread(io: Io) {
     }
 
     loop {
-        if listener.can_accept() {
-            let mut stream = listener.accept().expect("session: cannot accept");
+        if let Some(mut stream) = listener.try_accept() {
             stream.set_timeout(Some(1000));
             stream.set_keep_alive(Some(500));
 
@@ -632,7 +634,7 @@ pub fn thread(io: Io) {
 
             let congress = congress.clone();
             let stream = stream.into_handle();
-            respawn(&io, &mut kernel_thread, move |io| {
+            respawn(&io, "session_worker", &mut kernel_thread, move |io| {
                 let mut congress = congress.borrow_mut();
                 let mut stream = TcpStream::from_handle(&io, stream);
                 match host_kernel_worker(&io, &mut stream, &mut *congress) {
@@ -654,7 +656,7 @@ pub fn thread(io: Io) {
             info!("no connection, starting idle kernel");
 
             let congress = congress.clone();
-            respawn(&io, &mut kernel_thread, move |io| {
+            respawn(&io, "idle_kernel", &mut kernel_thread, move |io| {
                 let mut congress = congress.borrow_mut();
                 match flash_kernel_worker(&io, &mut *congress, "idle_kernel") {
                     Ok(()) =>
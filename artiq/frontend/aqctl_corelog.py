@@ -4,12 +4,11 @@ import argparse
 import asyncio
 import struct
 import logging
-import re
 
 from artiq.tools import *
 from artiq.protocols.pc_rpc import Server
 from artiq.protocols.logging import log_with_name
-from artiq.coredevice.comm_mgmt import Request, Reply
+from artiq.coredevice.comm_mgmt import Request, Reply, LogLevel
 
 
 def get_argparser():
@@ -26,32 +25,50 @@ class PingTarget:
         return True
 
 
+_levels = {
+    LogLevel.ERROR: logging.ERROR,
+    LogLevel.WARN: logging.WARN,
+    LogLevel.INFO: logging.INFO,
+    LogLevel.DEBUG: logging.DEBUG,
+    LogLevel.TRACE: logging.TRACE,
+}
+
+
 async def get_logs(host):
     reader, writer = await asyncio.open_connection(host, 1380)
     writer.write(b"ARTIQ management\n")
     writer.write(struct.pack("B", Request.PullLog.value))
+    writer.write(struct.pack(">q", 0))
     await writer.drain()
 
-    while True:
+    async def read_string():
         length, = struct.unpack(">l", await reader.readexactly(4))
-        log = await reader.readexactly(length)
-
-        for line in log.decode("utf-8").splitlines():
-            m = re.match(r"^\[.+?\] (TRACE|DEBUG| INFO| WARN|ERROR)\((.+?)\): (.+)$", line)
-            levelname = m.group(1)
-            if levelname == 'TRACE':
-                level = logging.TRACE
-            elif levelname == 'DEBUG':
-                level = logging.DEBUG
-            elif levelname == ' INFO':
-                level = logging.INFO
-            elif levelname == ' WARN':
-                level = logging.WARN
-            elif levelname == 'ERROR':
-                level = logging.ERROR
-            name = 'firmware.' + m.group(2).replace('::', '.')
-            text = m.group(3)
-            log_with_name(name, level, text)
+        return (await reader.readexactly(length)).decode("utf-8")
+
+    next_seq = None
+    while True:
+        ty, = struct.unpack("B", await reader.readexactly(1))
+        if Reply(ty) != Reply.LogRecord:
+            raise IOError("Incorrect reply from device: {} (expected {})"
+                          .format(Reply(ty), Reply.LogRecord))
+        seq, timestamp, level = struct.unpack(">qqB", await reader.readexactly(17))
+        target = await read_string()
+        message = await read_string()
+
+        if next_seq is None:
+            # Records evicted before we connected are not worth a warning.
+            pass
+        elif seq > next_seq:
+            log_with_name("firmware", logging.WARN,
+                          "{} log messages were lost".format(seq - next_seq))
+        elif seq < next_seq:
+            log_with_name("firmware", logging.WARN,
+                          "core device log restarted; did it reboot?")
+        next_seq = seq + 1
+
+        name = 'firmware.' + target.replace('::', '.')
+        for line in message.splitlines():
+            log_with_name(name, _levels[LogLevel(level)], line)
 
 
 def main():
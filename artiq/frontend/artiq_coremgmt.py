@@ -2,11 +2,13 @@
 
 import argparse
 import struct
+import sys
 
 from artiq.tools import verbosity_args, init_logger
 from artiq.master.databases import DeviceDB
 from artiq.coredevice.comm_kernel import CommKernel
-from artiq.coredevice.comm_mgmt import CommMgmt
+from artiq.coredevice.comm_mgmt import CommMgmt, MgmtError
+from artiq.coredevice import discovery
 from artiq.coredevice.profiler import CallgrindWriter
 
 
@@ -20,6 +22,10 @@ def get_argparser():
     parser.add_argument("-D", "--device", default=None,
                         help="use specified core device address instead of "
                              "reading device database")
+    parser.add_argument("-s", "--secret-file", metavar="FILE",
+                        type=argparse.FileType("rb"), default=None,
+                        help="authenticate with the shared secret in FILE "
+                             "(the value of the `mgmt_secret` config key)")
 
     tools = parser.add_subparsers(dest="tool")
     tools.required = True
@@ -33,6 +39,9 @@ def get_argparser():
     p_clear = subparsers.add_parser("clear",
                                     help="clear log buffer")
 
+    p_crash = subparsers.add_parser("crash",
+                                    help="show the report from the last panic")
+
     p_set_level = subparsers.add_parser("set_level",
                                         help="set minimum level for messages to be logged")
     p_set_level.add_argument("level", metavar="LEVEL", type=str,
@@ -44,6 +53,14 @@ def get_argparser():
     p_set_uart_level.add_argument("level", metavar="LEVEL", type=str,
                                   help="log level (one of: OFF ERROR WARN INFO DEBUG TRACE)")
 
+    p_set_target_filter = subparsers.add_parser("set_target_filter",
+                                                help="override the log level of individual "
+                                                     "firmware modules")
+    p_set_target_filter.add_argument("filter", metavar="FILTER", type=str,
+                                     help="comma-separated TARGET=LEVEL pairs, e.g. "
+                                          "runtime::rtio_mgt=debug,runtime::session=warn; "
+                                          "an empty string removes all overrides")
+
     # configuration
     t_config = tools.add_parser("config",
                                 help="read and change core device configuration")
@@ -85,11 +102,36 @@ def get_argparser():
                                   help="load the specified firmware in RAM")
 
     t_hotswap.add_argument("image", metavar="IMAGE", type=argparse.FileType("rb"),
-                           help="runtime image to be executed")
+                           help="runtime image to be executed (.bin or .fbi)")
+    t_hotswap.add_argument("-k", "--key", metavar="KEY", type=argparse.FileType("rb"),
+                           default=None,
+                           help="file with the 32-byte Ed25519 private key to sign "
+                                "the image with; required if the core device has "
+                                "`hotswap_pubkey` set")
+
+    # firmware in flash
+    t_firmware = tools.add_parser("firmware",
+                                  help="update the firmware stored in flash")
+
+    subparsers = t_firmware.add_subparsers(dest="action")
+    subparsers.required = True
+
+    p_flash = subparsers.add_parser("flash",
+                                    help="write firmware to the inactive flash slot; "
+                                         "it is started on the next reboot")
+    p_flash.add_argument("image", metavar="IMAGE", type=argparse.FileType("rb"),
+                         help="runtime image to be written (.bin or .fbi)")
+    p_flash.add_argument("-k", "--key", metavar="KEY", type=argparse.FileType("rb"),
+                         default=None,
+                         help="file with the 32-byte Ed25519 private key to sign "
+                              "the image with")
+
+    p_status = subparsers.add_parser("status",
+                                     help="show which flash slots are in use")
 
     # profiling
     t_profile = tools.add_parser("profile",
-                                 help="account for communications CPU time")
+                                 help="account for CPU time")
 
     subparsers = t_profile.add_subparsers(dest="action")
     subparsers.required = True
@@ -101,7 +143,11 @@ def get_argparser():
     p_start.add_argument("--hits-size", metavar="ENTRIES", type=int, default=8192,
                          help="hit buffer size")
     p_start.add_argument("--edges-size", metavar="ENTRIES", type=int, default=8192,
-                         help="edge buffer size")
+                         help="edge buffer size; 0 disables call graph recording")
+    p_start.add_argument("--kernel", dest="kernel_cpu", default=False,
+                         action="store_true",
+                         help="sample the kernel CPU instead of the "
+                              "communications CPU")
 
     p_stop = subparsers.add_parser("stop",
                                    help="stop profiling")
@@ -111,7 +157,8 @@ def get_argparser():
     p_save.add_argument("output", metavar="OUTPUT", type=argparse.FileType("w"),
                         help="file to save profile to, in Callgrind format")
     p_save.add_argument("firmware", metavar="FIRMWARE", type=str,
-                        help="path to firmware ELF file")
+                        help="path to firmware ELF file, or to kernel ELF "
+                             "file if the kernel CPU was profiled")
     p_save.add_argument("--no-compression",
                         dest="compression", default=True, action="store_false",
                         help="disable profile compression")
@@ -119,6 +166,51 @@ def get_argparser():
                         dest="demangle", default=True, action="store_false",
                         help="disable symbol demangling")
 
+    p_export = subparsers.add_parser("export",
+                                     help="save profile in pprof format, for "
+                                          "use with pprof or flamegraph tools")
+    p_export.add_argument("output", metavar="OUTPUT", type=argparse.FileType("wb"),
+                          help="file to save profile to")
+
+    # network statistics
+    t_netstats = tools.add_parser("netstats",
+                                  help="show network statistics")
+
+    # packet capture
+    t_capture = tools.add_parser("capture",
+                                 help="capture network traffic of the core device")
+
+    subparsers = t_capture.add_subparsers(dest="action")
+    subparsers.required = True
+
+    p_start = subparsers.add_parser("start",
+                                    help="start capturing into a ring buffer")
+    p_start.add_argument("--buffer-size", metavar="BYTES", type=int,
+                         default=256*1024,
+                         help="capture buffer size (default: %(default)s)")
+    p_start.add_argument("--snap-len", metavar="BYTES", type=int, default=1514,
+                         help="bytes to keep of each frame (default: %(default)s)")
+
+    p_stop = subparsers.add_parser("stop",
+                                   help="stop capturing")
+
+    p_save = subparsers.add_parser("save",
+                                   help="save the captured frames")
+    p_save.add_argument("output", metavar="OUTPUT", type=argparse.FileType("wb"),
+                        help="file to save the capture to, in pcap format")
+
+    # discovery
+    t_discover = tools.add_parser("discover",
+                                  help="list the core devices on the local "
+                                       "network segment")
+    t_discover.add_argument("-t", "--timeout", metavar="SECONDS", type=float,
+                            default=1.0,
+                            help="how long to wait for replies "
+                                 "(default: %(default)s)")
+    t_discover.add_argument("-a", "--address", default="255.255.255.255",
+                            help="broadcast address of the network segment "
+                                 "(default: %(default)s)")
+
     # misc debug
     t_debug = tools.add_parser("debug",
                                help="specialized debug functions")
@@ -129,6 +221,13 @@ def get_argparser():
     p_allocator = subparsers.add_parser("allocator",
                                         help="show heap layout")
 
+    p_heap = subparsers.add_parser("heap",
+                                   help="show heap statistics")
+
+    p_threads = subparsers.add_parser("threads",
+                                      help="show runtime threads, their stack "
+                                           "usage and what they wait for")
+
     return parser
 
 
@@ -136,65 +235,155 @@ def main():
     args = get_argparser().parse_args()
     init_logger(args)
 
+    if args.tool == "discover":
+        for sender, device in discovery.discover(args.timeout, args.address):
+            print("{}: mac {}, ip {}".format(sender, device.get("mac"),
+                                             ", ".join(device["ip"])))
+            print("  software ident {}".format(device.get("software_ident")))
+            print("  gateware ident {}".format(device.get("gateware_ident")))
+        return
+
     if args.device is None:
         core_addr = DeviceDB(args.device_db).get("core")["arguments"]["host"]
     else:
         core_addr = args.device
-    mgmt = CommMgmt(core_addr)
-
-    if args.tool == "log":
-        if args.action == "set_level":
-            mgmt.set_log_level(args.level)
-        if args.action == "set_uart_level":
-            mgmt.set_uart_log_level(args.level)
-        if args.action == "clear":
-            mgmt.clear_log()
-        if args.action == None:
-            print(mgmt.get_log(), end="")
-
-    if args.tool == "config":
-        if args.action == "read":
-            value = mgmt.config_read(args.key)
-            if not value:
-                print("Key {} does not exist".format(args.key))
-            else:
-                print(value)
-        if args.action == "write":
-            for key, value in args.string:
-                mgmt.config_write(key, value.encode("utf-8"))
-            for key, filename in args.file:
-                with open(filename, "rb") as fi:
-                    mgmt.config_write(key, fi.read())
-        if args.action == "remove":
-            for key in args.key:
-                mgmt.config_remove(key)
-        if args.action == "erase":
-            mgmt.config_erase()
-
-    if args.tool == "reboot":
-        mgmt.reboot()
-
-    if args.tool == "hotswap":
-        mgmt.hotswap(args.image.read())
-
-    if args.tool == "profile":
-        if args.action == "start":
-            mgmt.start_profiler(args.interval, args.hits_size, args.edges_size)
-        elif args.action == "stop":
-            mgmt.stop_profiler()
-        elif args.action == "save":
-            hits, edges = mgmt.get_profile()
-            writer = CallgrindWriter(args.output, args.firmware, "or1k-linux",
-                                     args.compression, args.demangle)
-            writer.header()
-            for addr, count in hits.items():
-                writer.hit(addr, count)
-            for (caller, callee), count in edges.items():
-                writer.edge(caller, callee, count)
-
-    if args.tool == "debug":
-        if args.action == "allocator":
-            mgmt.debug_allocator()
+    secret = None
+    if args.secret_file is not None:
+        with args.secret_file:
+            secret = args.secret_file.read()
+    mgmt = CommMgmt(core_addr, secret=secret)
+
+    try:
+        if args.tool == "log":
+            if args.action == "set_level":
+                mgmt.set_log_level(args.level)
+            if args.action == "set_uart_level":
+                mgmt.set_uart_log_level(args.level)
+            if args.action == "set_target_filter":
+                mgmt.set_log_target_filter(args.filter)
+            if args.action == "clear":
+                mgmt.clear_log()
+            if args.action == "crash":
+                print(mgmt.get_last_crash(), end="")
+            if args.action == None:
+                print(mgmt.get_log(), end="")
+
+        if args.tool == "config":
+            if args.action == "read":
+                value = mgmt.config_read(args.key)
+                if not value:
+                    print("Key {} does not exist".format(args.key))
+                else:
+                    print(value)
+            if args.action == "write":
+                for key, value in args.string:
+                    mgmt.config_write(key, value.encode("utf-8"))
+                for key, filename in args.file:
+                    with open(filename, "rb") as fi:
+                        mgmt.config_write(key, fi.read())
+            if args.action == "remove":
+                for key in args.key:
+                    mgmt.config_remove(key)
+            if args.action == "erase":
+                mgmt.config_erase()
+
+        if args.tool == "reboot":
+            mgmt.reboot()
+
+        if args.tool == "hotswap":
+            signing_key = args.key.read() if args.key is not None else None
+            mgmt.hotswap(args.image.read(), signing_key)
+
+        if args.tool == "firmware":
+            if args.action == "flash":
+                signing_key = args.key.read() if args.key is not None else None
+                mgmt.flash_firmware(args.image.read(), signing_key)
+            if args.action == "status":
+                running, active, pending = mgmt.get_firmware_slots()
+                print("running: {}".format(running))
+                print("active:  {}".format(active))
+                print("pending: {}".format(pending or "none"))
+
+        if args.tool == "profile":
+            if args.action == "start":
+                mgmt.start_profiler(args.interval, args.hits_size, args.edges_size,
+                                    args.kernel_cpu)
+            elif args.action == "stop":
+                mgmt.stop_profiler()
+            elif args.action == "save":
+                hits, edges, _ = mgmt.get_profile()
+                writer = CallgrindWriter(args.output, args.firmware, "or1k-linux",
+                                         args.compression, args.demangle)
+                writer.header()
+                for addr, count in hits.items():
+                    writer.hit(addr, count)
+                for (caller, callee), count in edges.items():
+                    writer.edge(caller, callee, count)
+            elif args.action == "export":
+                with args.output:
+                    args.output.write(mgmt.get_profile_pprof())
+
+        if args.tool == "capture":
+            if args.action == "start":
+                mgmt.start_capture(args.buffer_size, args.snap_len)
+            elif args.action == "stop":
+                mgmt.stop_capture()
+            elif args.action == "save":
+                with args.output:
+                    args.output.write(mgmt.get_capture())
+
+        if args.tool == "netstats":
+            stats = mgmt.get_network_stats()
+            services = {1380: "mgmt", 1381: "session",
+                        1382: "analyzer", 1383: "moninj"}
+            print("frames:")
+            for name, value in sorted(stats.frames.items()):
+                print("  {}: {}".format(name, value))
+            print("MAC errors:")
+            for name, value in sorted(stats.mac_errors.items()):
+                print("  {}: {}".format(name, value))
+            print("rejected connections: {}".format(stats.rejected_connections))
+            print("network stack errors:")
+            for kind, count in sorted(stats.poll_errors.items()):
+                if count:
+                    print("  {}: {}".format(kind, count))
+            print("TCP traffic (received/sent bytes):")
+            for port, (rx, tx) in sorted(stats.services.items()):
+                print("  {} ({}): {}/{}".format(port, services.get(port, "?"),
+                                                rx, tx))
+            print("TCP sockets:")
+            for local, remote, state in stats.sockets:
+                print("  {} <-> {}: {}".format(local, remote, state))
+            print("ARP neighbors:")
+            for ip, mac, age in stats.neighbors:
+                print("  {} at {} (seen {:.1f} s ago)".format(ip, mac, age/1000))
+
+        if args.tool == "debug":
+            if args.action == "allocator":
+                mgmt.debug_allocator()
+            if args.action == "heap":
+                stats = mgmt.get_heap_stats()
+                total = stats.busy + stats.idle + stats.meta
+                print("busy: {} bytes".format(stats.busy))
+                print("idle: {} bytes".format(stats.idle))
+                print("meta: {} bytes".format(stats.meta))
+                print("total: {} bytes".format(total))
+                print("largest free block: {} bytes".format(stats.largest_idle))
+                print("blocks: {}".format(stats.blocks))
+                print("peak busy: {} bytes".format(stats.peak_busy))
+            if args.action == "threads":
+                for thread in mgmt.get_threads():
+                    if thread.state == "timeout":
+                        state = "timeout in {} ms".format(thread.detail)
+                    elif thread.state == "socket":
+                        state = "socket {} <-> {}".format(*thread.detail)
+                    else:
+                        state = thread.state
+                    print("{:20} stack {:5}/{:5} bytes  {}".format(
+                        thread.name, thread.stack_used, thread.stack_size, state))
+    except MgmtError as e:
+        print("error: {}".format(e.message), file=sys.stderr)
+        sys.exit(1)
 
 
 if __name__ == "__main__":
@@ -257,6 +257,8 @@ def main():
     args = get_argparser().parse_args()
     init_logger(args)
 
+    # The firmware region ends where the next item begins, or at the end of
+    # the flash; FIRMWARE_FLASH_END in the gateware targets must match.
     config = {
         "kasli": {
             "programmer":   partial(ProgrammerXC7, board="kasli", proxy="bscan_spi_xc7a100t.bit"),
@@ -104,6 +104,8 @@ class _StandaloneBase(MiniSoC, AMPSoC):
                          **kwargs)
         AMPSoC.__init__(self)
         add_identifier(self)
+        # End of the 16 MiB flash; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x1000000
 
         self.submodules.leds = gpio.GPIOOut(Cat(
             self.platform.request("user_led", 0)))
@@ -728,6 +730,8 @@ class _MasterBase(MiniSoC, AMPSoC):
                          **kwargs)
         AMPSoC.__init__(self)
         add_identifier(self)
+        # End of the 16 MiB flash; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x1000000
 
         platform = self.platform
 
@@ -859,6 +863,8 @@ class _SatelliteBase(BaseSoC):
                  l2_size=128*1024,
                  **kwargs)
         add_identifier(self)
+        # End of the 16 MiB flash; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x1000000
 
         platform = self.platform
 
@@ -129,6 +129,8 @@ class _StandaloneBase(MiniSoC, AMPSoC):
                          **kwargs)
         AMPSoC.__init__(self)
         add_identifier(self)
+        # End of the 16 MiB flash; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x1000000
 
         if isinstance(self.platform.toolchain, XilinxVivadoToolchain):
             self.platform.toolchain.bitstream_commands.extend([
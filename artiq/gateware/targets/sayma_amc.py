@@ -138,6 +138,8 @@ class Standalone(MiniSoC, AMPSoC, RTMCommon):
         AMPSoC.__init__(self)
         RTMCommon.__init__(self)
         add_identifier(self, suffix=".without-sawg" if not with_sawg else "")
+        # The RTM gateware follows; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x200000
         self.config["HMC830_REF"] = "100"
 
         platform = self.platform
@@ -249,6 +251,8 @@ class MasterDAC(MiniSoC, AMPSoC, RTMCommon):
         AMPSoC.__init__(self)
         RTMCommon.__init__(self)
         add_identifier(self, suffix=".without-sawg" if not with_sawg else "")
+        # The RTM gateware follows; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x200000
         self.config["HMC830_REF"] = "100"
 
         platform = self.platform
@@ -401,6 +405,8 @@ class Master(MiniSoC, AMPSoC):
                          **kwargs)
         AMPSoC.__init__(self)
         add_identifier(self)
+        # The RTM gateware follows; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x200000
 
         platform = self.platform
         rtio_clk_freq = 150e6
@@ -532,6 +538,8 @@ class Satellite(BaseSoC, RTMCommon):
                  **kwargs)
         RTMCommon.__init__(self)
         add_identifier(self, suffix=".without-sawg" if not with_sawg else "")
+        # The RTM gateware follows; see the flash map in artiq_flash
+        self.config["FIRMWARE_FLASH_END"] = 0x200000
         self.config["HMC830_REF"] = "150"
 
         platform = self.platform
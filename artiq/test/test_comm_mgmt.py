@@ -0,0 +1,256 @@
+import hashlib
+import hmac
+import os
+import socket
+import struct
+import threading
+import unittest
+
+from artiq.coredevice.comm_mgmt import (CommMgmt, MgmtError, ErrorCode,
+                                        Request, Reply, NetworkStats,
+                                        HeapStats, Profile, ThreadInfo)
+
+
+SECRET = b"0123456789abcdef"
+
+
+class MockDevice:
+    """Answers requests of the management protocol the way the core device
+    does when `mgmt_secret` is set, encoding replies in the same order as the
+    runtime. Requests other than the read-only ones require authentication."""
+    READ_ONLY = {Request.GetChallenge, Request.Authenticate,
+                 Request.GetNetworkStats, Request.GetHeapStats,
+                 Request.GetThreads}
+
+    def __init__(self, sock, secret):
+        self.sock = sock
+        self.secret = secret
+        self.challenge = None
+        self.authenticated = False
+
+        self.network_stats = None
+        self.heap_stats = None
+        self.threads = []
+        self.profiler = None
+        self.profile = None
+        self.pprof = b""
+
+    def _read(self, length):
+        data = b""
+        while len(data) < length:
+            chunk = self.sock.recv(length - len(data))
+            if not chunk:
+                raise EOFError
+            data += chunk
+        return data
+
+    def _read_int32(self):
+        (value, ) = struct.unpack(">l", self._read(4))
+        return value
+
+    def _write_header(self, ty):
+        self.sock.sendall(struct.pack("B", ty.value))
+
+    def _write_int32(self, value):
+        self.sock.sendall(struct.pack(">L", value))
+
+    def _write_int64(self, value):
+        self.sock.sendall(struct.pack(">Q", value))
+
+    def _write_string(self, value):
+        value = value.encode("utf-8")
+        self._write_int32(len(value))
+        self.sock.sendall(value)
+
+    def _write_error(self, code, message):
+        message = message.encode("utf-8")
+        self.sock.sendall(struct.pack(">Bll", Reply.Error.value, code.value,
+                                      len(message)) + message)
+
+    def _authenticate(self):
+        response = self._read(self._read_int32())
+        if (self.challenge is not None and
+                hmac.compare_digest(response, hmac.new(
+                    self.secret, self.challenge,
+                    hashlib.sha256).digest())):
+            self._write_header(Reply.Success)
+            self.authenticated = True
+        else:
+            self._write_error(ErrorCode.AuthenticationFailed,
+                              "authentication failed")
+            raise EOFError
+        self.challenge = None
+
+    def _write_network_stats(self):
+        stats = self.network_stats
+        self._write_header(Reply.NetworkStats)
+        for key in ["rx_preamble_errors", "rx_crc_errors", "rx_dropped"]:
+            self._write_int32(stats.mac_errors[key])
+        for key in ["rx_frames", "rx_bytes", "tx_frames", "tx_bytes"]:
+            self._write_int64(stats.frames[key])
+        self._write_int32(stats.rejected_connections)
+        self._write_int32(len(stats.poll_errors))
+        for kind, count in stats.poll_errors.items():
+            self._write_string(kind)
+            self._write_int32(count)
+        self._write_int32(len(stats.sockets))
+        for endpoints_and_state in stats.sockets:
+            for value in endpoints_and_state:
+                self._write_string(value)
+        self._write_int32(len(stats.services))
+        for port, (rx_bytes, tx_bytes) in stats.services.items():
+            self.sock.sendall(struct.pack(">H", port))
+            self._write_int64(rx_bytes)
+            self._write_int64(tx_bytes)
+        self._write_int32(len(stats.neighbors))
+        for ip, mac, age in stats.neighbors:
+            self._write_string(ip)
+            self._write_string(mac)
+            self._write_int64(age)
+
+    def _write_threads(self):
+        self._write_header(Reply.Threads)
+        self._write_int32(len(self.threads))
+        for thread in self.threads:
+            self._write_string(thread.name)
+            self._write_int32(thread.stack_size)
+            self._write_int32(thread.stack_used)
+            self._write_string(thread.state)
+            if thread.state == "timeout":
+                self._write_int64(thread.detail)
+            elif thread.state == "socket":
+                self._write_string(thread.detail[0])
+                self._write_string(thread.detail[1])
+
+    def _write_profile(self):
+        profile = self.profile
+        self.sock.sendall(struct.pack("BB", Reply.Profile.value,
+                                      profile.kernel_cpu))
+        self._write_int32(len(profile.hits))
+        for addr, count in profile.hits.items():
+            self._write_int32(addr)
+            self._write_int32(count)
+        self._write_int32(len(profile.edges))
+        for (caller, callee), count in profile.edges.items():
+            self._write_int32(caller)
+            self._write_int32(callee)
+            self._write_int32(count)
+
+    def serve(self):
+        try:
+            while True:
+                ty = Request(self._read(1)[0])
+                if not self.authenticated and ty not in self.READ_ONLY:
+                    self._write_error(ErrorCode.Unauthorized,
+                                      "this request requires authentication")
+                elif ty == Request.GetChallenge:
+                    self.challenge = os.urandom(32)
+                    self._write_header(Reply.Challenge)
+                    self._write_int32(len(self.challenge))
+                    self.sock.sendall(self.challenge)
+                elif ty == Request.Authenticate:
+                    self._authenticate()
+                elif ty == Request.GetNetworkStats:
+                    self._write_network_stats()
+                elif ty == Request.GetHeapStats:
+                    self._write_header(Reply.HeapStats)
+                    for value in self.heap_stats:
+                        self._write_int32(value)
+                elif ty == Request.GetThreads:
+                    self._write_threads()
+                elif ty == Request.StartProfiler:
+                    interval, hits_size, edges_size = \
+                        struct.unpack(">lll", self._read(12))
+                    kernel_cpu = bool(self._read(1)[0])
+                    self.profiler = (interval, hits_size, edges_size, kernel_cpu)
+                    self._write_header(Reply.Success)
+                elif ty == Request.GetProfile:
+                    self._write_profile()
+                elif ty == Request.GetProfilePprof:
+                    self._write_header(Reply.ProfilePprof)
+                    self._write_int32(len(self.pprof))
+                    self.sock.sendall(self.pprof)
+                else:
+                    raise NotImplementedError(ty)
+        except EOFError:
+            pass
+        finally:
+            self.sock.close()
+
+
+class MockDeviceCase(unittest.TestCase):
+    def setUp(self):
+        client_sock, device_sock = socket.socketpair()
+        self.device = MockDevice(device_sock, SECRET)
+        self.thread = threading.Thread(target=self.device.serve)
+        self.thread.start()
+        self.mgmt = CommMgmt("localhost")
+        self.mgmt.socket = client_sock
+
+    def tearDown(self):
+        self.mgmt.close()
+        self.thread.join()
+
+
+class AuthenticationCase(MockDeviceCase):
+    def test_authenticate(self):
+        self.mgmt.authenticate(SECRET)
+
+    def test_wrong_secret(self):
+        with self.assertRaises(MgmtError) as cm:
+            self.mgmt.authenticate(b"not the right secret")
+        self.assertEqual(cm.exception.code, ErrorCode.AuthenticationFailed)
+
+    def test_unauthenticated_request(self):
+        with self.assertRaises(MgmtError) as cm:
+            self.mgmt.clear_log()
+        self.assertEqual(cm.exception.code, ErrorCode.Unauthorized)
+
+
+class StatsCase(MockDeviceCase):
+    def test_get_network_stats(self):
+        self.device.network_stats = NetworkStats(
+            mac_errors={"rx_preamble_errors": 1, "rx_crc_errors": 2,
+                        "rx_dropped": 3},
+            frames={"rx_frames": 10, "rx_bytes": 1000,
+                    "tx_frames": 20, "tx_bytes": 2000},
+            rejected_connections=4,
+            poll_errors={"checksum": 5},
+            sockets=[("0.0.0.0:1380", "192.168.1.1:50000", "ESTABLISHED")],
+            services={1380: (100, 200)},
+            neighbors=[("192.168.1.1", "02-00-00-00-00-01", 1500)])
+        self.assertEqual(self.mgmt.get_network_stats(), self.device.network_stats)
+
+    def test_get_heap_stats(self):
+        # Sizes above 2 GiB must not come back negative.
+        self.device.heap_stats = HeapStats(0x1000, 0x80000000, 0x60,
+                                           0x7ff00000, 12, 0x2000)
+        self.assertEqual(self.mgmt.get_heap_stats(), self.device.heap_stats)
+
+    def test_get_threads(self):
+        self.device.threads = [
+            ThreadInfo("mgmt_worker", 4096, 1200, "running", None),
+            ThreadInfo("analyzer", 4096, 800, "timeout", 250),
+            ThreadInfo("moninj", 4096, 900, "socket", ("0.0.0.0:1383", "*:*")),
+        ]
+        self.assertEqual(self.mgmt.get_threads(), self.device.threads)
+
+
+class ProfilerCase(MockDeviceCase):
+    def setUp(self):
+        super().setUp()
+        self.mgmt.authenticate(SECRET)
+
+    def test_start_profiler(self):
+        self.mgmt.start_profiler(1000, 512, 256, kernel_cpu=True)
+        self.assertEqual(self.device.profiler, (1000, 512, 256, True))
+
+    def test_get_profile(self):
+        self.device.profile = Profile(hits={0x1234: 10},
+                                      edges={(0x100, 0x1234): 7},
+                                      kernel_cpu=True)
+        self.assertEqual(self.mgmt.get_profile(), self.device.profile)
+
+    def test_get_profile_pprof(self):
+        self.device.pprof = b"\x0a\x02\x08\x01"
+        self.assertEqual(self.mgmt.get_profile_pprof(), self.device.pprof)
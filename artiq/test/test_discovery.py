@@ -0,0 +1,46 @@
+import socket
+import threading
+import unittest
+
+from artiq.coredevice import discovery
+
+
+REPLY = (b"ARTIQ core device\n"
+         b"software_ident: 5.0;kasli\n"
+         b"gateware_ident: 5.0;kasli\n"
+         b"mac: 02-00-00-00-00-21\n"
+         b"ip: 192.168.1.70/24\n"
+         b"ip: fe80::ff:fe00:21/64\n")
+
+
+class DiscoveryCase(unittest.TestCase):
+    def test_parse_reply(self):
+        device = discovery.parse_reply(REPLY)
+        self.assertEqual(device["software_ident"], "5.0;kasli")
+        self.assertEqual(device["mac"], "02-00-00-00-00-21")
+        self.assertEqual(device["ip"],
+                         ["192.168.1.70/24", "fe80::ff:fe00:21/64"])
+        self.assertIsNone(discovery.parse_reply(b"something else\n"))
+
+    def test_discover(self):
+        responder = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        responder.bind(("127.0.0.1", 0))
+        port = responder.getsockname()[1]
+
+        def respond():
+            request, client = responder.recvfrom(1024)
+            if request == discovery.REQUEST:
+                responder.sendto(REPLY, client)
+        thread = threading.Thread(target=respond)
+        thread.start()
+        try:
+            devices = discovery.discover(timeout=0.5, address="127.0.0.1",
+                                         port=port)
+        finally:
+            thread.join()
+            responder.close()
+
+        self.assertEqual(len(devices), 1)
+        sender, device = devices[0]
+        self.assertEqual(sender, "127.0.0.1")
+        self.assertEqual(device["gateware_ident"], "5.0;kasli")
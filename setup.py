@@ -69,7 +69,10 @@ Topic :: Scientific/Engineering :: Physics
 Topic :: System :: Hardware
 """.splitlines(),
     install_requires=requirements,
-    extras_require={},
+    extras_require={
+        # Signing of firmware images for hotswap and flashing.
+        "signing": ["pynacl"],
+    },
     dependency_links=[
         "git+https://github.com/m-labs/pyqtgraph.git@develop#egg=pyqtgraph",
         "git+https://github.com/m-labs/llvmlite.git@artiq#egg=llvmlite_artiq"